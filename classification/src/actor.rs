@@ -1,56 +1,164 @@
+use std::{path::Path, sync::Arc};
+
 use ractor::{Actor, ActorProcessingErr, ActorRef, RpcReplyPort, async_trait};
+use serde::Deserialize;
 use tokio::fs::read_to_string;
 use tracing::{debug, instrument};
 
-use crate::{Error, MLProperties, populate_prompt, runner::PipelineRunner, sanitise_output};
+use crate::{
+    Confidence, Error, Extraction, MLProperties, MODEL_ID, Vocabulary, content_hash,
+    populate_prompt,
+    runner::{Batching, ModelSettings, PipelineRunner},
+    sanitise_output, shared_prefix_len,
+};
 
 pub struct ExtractionActor;
 
-pub struct ExtractionArgs;
+/// Which prompts are used to extract properties from an item
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptMode {
+    /// A single generation producing every class at once
+    #[default]
+    Combined,
+    /// Separate features and genres generations, merged afterward
+    Split,
+    /// A single generation that must choose from the supplied `Vocabulary`
+    Closed,
+}
+
+impl PromptMode {
+    fn files(self) -> &'static [&'static str] {
+        match self {
+            PromptMode::Combined => &["./prompts/combined.txt"],
+            PromptMode::Split => &["./prompts/features.txt", "./prompts/genres.txt"],
+            PromptMode::Closed => &["./prompts/closed.txt"],
+        }
+    }
+
+    async fn load(self) -> Result<Vec<PromptTemplate>, std::io::Error> {
+        load_prompts(self.files()).await
+    }
+
+    /// The version stored alongside properties extracted with the current
+    /// prompt files, i.e. `features@1a2b3c4d5e6f7a8b+genres@8b7a6f5e4d3c2b1a`
+    pub async fn version(self) -> Result<String, std::io::Error> {
+        Ok(prompt_version(&self.load().await?))
+    }
+}
+
+pub struct ExtractionArgs {
+    pub prompt_mode: PromptMode,
+    pub batching: Batching,
+}
+
+/// A prompt template, its item independent prefix is only known once the
+/// vocabulary is filled in
+struct PromptTemplate {
+    name: String,
+    text: String,
+}
+
+impl PromptTemplate {
+    async fn load(path: &Path) -> Result<Self, std::io::Error> {
+        // Tabs are stripped up front, otherwise `populate_prompt` would shift
+        // the prefix.
+        let text = read_to_string(path).await?.replace('\t', "");
+        Ok(Self {
+            name: path.display().to_string(),
+            text,
+        })
+    }
+
+    /// The file name and a hash of the content, i.e.
+    /// `combined@1a2b3c4d5e6f7a8b`
+    fn version(&self) -> String {
+        let stem = Path::new(&self.name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+        format!("{stem}@{:016x}", content_hash(&self.text))
+    }
+}
+
+async fn load_prompts(files: &[impl AsRef<Path>]) -> Result<Vec<PromptTemplate>, std::io::Error> {
+    let mut prompts = vec![];
+    for file in files {
+        prompts.push(PromptTemplate::load(file.as_ref()).await?);
+    }
+    Ok(prompts)
+}
+
+fn prompt_version(prompts: &[PromptTemplate]) -> String {
+    prompts
+        .iter()
+        .map(PromptTemplate::version)
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
 pub struct ExtractionState {
-    pipeline_runner: PipelineRunner,
-    features_prompt: String,
-    genres_prompt: String,
+    pipeline: Arc<Pipeline>,
 }
 
-impl ExtractionState {
+/// Runs each prompt over an item and merges the results. The actor shares one
+/// with the task spawned for each item, so requests reach the runner
+/// concurrently and can be batched.
+pub struct Pipeline {
+    runner: PipelineRunner,
+    prompts: Vec<PromptTemplate>,
+    prompt_version: String,
+}
+
+impl Pipeline {
+    /// Loads the prompt templates in `files`, which are run in order
+    pub async fn new(
+        runner: PipelineRunner,
+        files: &[impl AsRef<Path>],
+    ) -> Result<Self, std::io::Error> {
+        let prompts = load_prompts(files).await?;
+        let prompt_version = prompt_version(&prompts);
+        Ok(Self {
+            runner,
+            prompts,
+            prompt_version,
+        })
+    }
+
     #[instrument(skip_all)]
-    async fn run_pipeline(
+    pub async fn run_pipeline(
         &self,
         title: String,
         description: String,
-    ) -> Result<MLProperties, Error> {
+        vocabulary: Option<Vocabulary>,
+    ) -> Result<Extraction, Error> {
         debug!(title, "running pipeline");
-        let features: MLProperties = {
-            let features_prompt = populate_prompt(&self.features_prompt, &title, &description);
-            let pipeline_response = sanitise_output(
-                self.pipeline_runner
-                    .run(features_prompt)
-                    .await
-                    .map_err(|e| Error::Pipeline { source: e })?,
-            );
-            debug!(pipeline_response, "model returned for features prompt");
-            serde_json::from_str(&pipeline_response)?
-        };
-
-        let mut genres: MLProperties = {
-            let genres_prompt = populate_prompt(&self.genres_prompt, &title, &description);
-            let pipeline_response = sanitise_output(
-                self.pipeline_runner
-                    .run(genres_prompt)
-                    .await
-                    .map_err(|e| Error::Pipeline { source: e })?,
-            );
-            debug!(pipeline_response, "model returned for genres prompt");
-            serde_json::from_str(&pipeline_response)?
-        };
-
-        genres.features.extend(features.features);
-        genres.types.extend(features.types);
-        genres.genres.extend(features.genres);
-        genres.themes.extend(features.themes);
-        debug!(ml_properties = ?genres, "pipeline results");
-        Ok(genres)
+        let vocabulary = vocabulary.unwrap_or_default();
+        let mut properties = MLProperties::default();
+        let mut confidence = Confidence::default();
+        for prompt in &self.prompts {
+            // The vocabulary sits ahead of the item, so it's part of the prefix
+            let template = vocabulary.populate(&prompt.text);
+            let prefix_len = shared_prefix_len(&template);
+            let populated = populate_prompt(&template, &title, &description);
+            let generation = self
+                .runner
+                .run(populated, prefix_len)
+                .await
+                .map_err(|e| Error::Pipeline { source: e })?;
+            let pipeline_response = sanitise_output(generation.text.clone());
+            debug!(pipeline_response, prompt = %prompt.name, "model returned");
+            let output = serde_json::from_str(&pipeline_response)?;
+            confidence.score(&generation, &output);
+            properties.merge(output);
+        }
+        debug!(ml_properties = ?properties, ?confidence, "pipeline results");
+        Ok(Extraction {
+            properties,
+            model_id: MODEL_ID.to_owned(),
+            prompt_version: self.prompt_version.clone(),
+            confidence,
+        })
     }
 }
 
@@ -58,7 +166,9 @@ pub enum ExtractionMsg {
     Process {
         title: String,
         description: String,
-        rpc_reply_port: RpcReplyPort<Result<MLProperties, Error>>,
+        /// Values to choose from, only used by `PromptMode::Closed` prompts
+        vocabulary: Option<Vocabulary>,
+        rpc_reply_port: RpcReplyPort<Result<Extraction, Error>>,
     },
 }
 #[async_trait]
@@ -70,16 +180,12 @@ impl Actor for ExtractionActor {
     async fn pre_start(
         &self,
         _: ActorRef<Self::Msg>,
-        _: Self::Arguments,
+        args: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
-        let pipeline_runner = PipelineRunner::setup().await?;
-
-        let features_prompt = read_to_string("./prompts/features.txt").await?;
-        let genres_prompt = read_to_string("./prompts/genres.txt").await?;
+        let runner = PipelineRunner::setup(args.batching, ModelSettings::default()).await?;
+        let pipeline = Pipeline::new(runner, args.prompt_mode.files()).await?;
         Ok(Self::State {
-            pipeline_runner,
-            features_prompt,
-            genres_prompt,
+            pipeline: Arc::new(pipeline),
         })
     }
 
@@ -93,9 +199,14 @@ impl Actor for ExtractionActor {
             ExtractionMsg::Process {
                 title,
                 description,
+                vocabulary,
                 rpc_reply_port,
             } => {
-                let _ = rpc_reply_port.send(state.run_pipeline(title, description).await);
+                let pipeline = state.pipeline.clone();
+                tokio::spawn(async move {
+                    let _ = rpc_reply_port
+                        .send(pipeline.run_pipeline(title, description, vocabulary).await);
+                });
             }
         }
 
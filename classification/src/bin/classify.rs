@@ -0,0 +1,201 @@
+//! Runs the extraction pipeline without the server or crawler, printing the
+//! extracted `MLProperties` as JSON. Handy for iterating on prompts and model
+//! settings.
+//!
+//! A single item:
+//! `cargo run -p classification --bin classify -- --title "Better Farming"
+//! --description "Adds new crops"`
+//!
+//! A JSONL file of `{"id", "title", "description"}` objects, printing a line
+//! per item:
+//! `cargo run -p classification --bin classify -- --input items.jsonl`
+//!
+//! An item from the local database, which needs the `db` feature and the
+//! server to be stopped as it holds the database lock:
+//! `cargo run -p classification --features db --bin classify -- --item
+//! 2345678901`
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use classification::{
+    Extraction, MLProperties, Vocabulary,
+    actor::Pipeline,
+    runner::{Batching, ModelSettings, PipelineRunner},
+};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Whatever, whatever};
+use tokio::fs;
+
+#[derive(Parser)]
+struct Args {
+    /// Title of the item to classify
+    #[arg(long, requires = "description", conflicts_with_all = ["input", "item"])]
+    title: Option<String>,
+    /// Description of the item to classify
+    #[arg(long, requires = "title")]
+    description: Option<String>,
+    /// JSONL file of items to classify
+    #[arg(long, conflicts_with = "item")]
+    input: Option<PathBuf>,
+    /// Workshop item ID to read from the local database
+    #[arg(long)]
+    item: Option<String>,
+    /// The local database, used with `--item`
+    #[arg(long, default_value = "./workshopdb")]
+    database: PathBuf,
+    /// Prompt file(s) to run, their results are merged
+    #[arg(long, default_value = "./prompts/combined.txt")]
+    prompt: Vec<PathBuf>,
+    /// JSON `MLProperties` to offer closed vocabulary prompts
+    #[arg(long)]
+    vocabulary: Option<PathBuf>,
+    /// Also print how confident the model was in each value
+    #[arg(long)]
+    confidence: bool,
+    #[command(flatten)]
+    model: ModelSettings,
+}
+
+#[derive(Deserialize)]
+struct Item {
+    #[serde(default)]
+    id: String,
+    title: String,
+    description: String,
+}
+
+#[derive(Serialize)]
+struct Output<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    properties: &'a MLProperties,
+    /// Keyed by `class/value`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confidence: Option<BTreeMap<String, f32>>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Whatever> {
+    let args = Args::parse();
+    let items = match (&args.title, &args.description, &args.input, &args.item) {
+        (Some(title), Some(description), ..) => vec![Item {
+            id: String::new(),
+            title: title.clone(),
+            description: description.clone(),
+        }],
+        (_, _, Some(path), _) => read_jsonl(path).await?,
+        (_, _, _, Some(id)) => vec![load_item(&args.database, id).await?],
+        _ => whatever!("nothing to classify; pass --title and --description, --input or --item"),
+    };
+    let vocabulary = match &args.vocabulary {
+        Some(path) => Some(Vocabulary(
+            serde_json::from_str(
+                &fs::read_to_string(path)
+                    .await
+                    .with_whatever_context(|_| format!("reading {}", path.display()))?,
+            )
+            .whatever_context("parsing the vocabulary")?,
+        )),
+        None => None,
+    };
+
+    let runner = PipelineRunner::setup(Batching::default(), args.model)
+        .await
+        .whatever_context("setting up the model")?;
+    let pipeline = Pipeline::new(runner, &args.prompt)
+        .await
+        .whatever_context("reading prompts")?;
+
+    let single = items.len() == 1;
+    for item in &items {
+        let extraction = match pipeline
+            .run_pipeline(
+                item.title.clone(),
+                item.description.clone(),
+                vocabulary.clone(),
+            )
+            .await
+        {
+            Ok(extraction) => extraction,
+            // Carry on with the rest of a file, one bad output shouldn't
+            // waste the whole run
+            Err(error) if !single => {
+                eprintln!("classifying {:?}: {error}", item.id);
+                continue;
+            }
+            Err(error) => return Err(error).whatever_context("classifying"),
+        };
+        let output = Output {
+            id: (!item.id.is_empty()).then_some(item.id.as_str()),
+            properties: &extraction.properties,
+            confidence: args.confidence.then(|| confidence(&extraction)),
+        };
+        let json = if single {
+            serde_json::to_string_pretty(&output)
+        } else {
+            serde_json::to_string(&output)
+        };
+        println!("{}", json.whatever_context("serialising output")?);
+    }
+    Ok(())
+}
+
+fn confidence(extraction: &Extraction) -> BTreeMap<String, f32> {
+    extraction
+        .properties
+        .values()
+        .filter_map(|(class, value)| {
+            let confidence = extraction.confidence.get(class, value)?;
+            Some((format!("{class}/{value}"), confidence))
+        })
+        .collect()
+}
+
+async fn read_jsonl(path: &Path) -> Result<Vec<Item>, Whatever> {
+    fs::read_to_string(path)
+        .await
+        .with_whatever_context(|_| format!("reading {}", path.display()))?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line)
+                .with_whatever_context(|_| format!("parsing a line of {}", path.display()))
+        })
+        .collect()
+}
+
+#[cfg(feature = "db")]
+async fn load_item(database: &Path, id: &str) -> Result<Item, Whatever> {
+    use surrealdb::{Surreal, engine::local::RocksDb};
+
+    let db = Surreal::new::<RocksDb>(database)
+        .await
+        .whatever_context("opening the database")?;
+    db.use_ns("workshop")
+        .use_db("workshop")
+        .await
+        .whatever_context("using ns/db")?;
+    let item: Option<Item> = db
+        .query(
+            "SELECT record::id(id) AS id, title, description FROM type::thing('workshop_items', \
+             $id)",
+        )
+        .bind(("id", id.to_owned()))
+        .await
+        .whatever_context("querying the item")?
+        .take(0)
+        .whatever_context("taking the item")?;
+    match item {
+        Some(item) => Ok(item),
+        None => whatever!("no item {id} in {}", database.display()),
+    }
+}
+
+#[cfg(not(feature = "db"))]
+async fn load_item(_: &Path, _: &str) -> Result<Item, Whatever> {
+    whatever!("reading items from the database needs the `db` feature")
+}
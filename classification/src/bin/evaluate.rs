@@ -0,0 +1,184 @@
+//! Scores extraction output against a labelled dataset and prints per class
+//! precision/recall for each prompt/model variant.
+//!
+//! Recorded output can be scored offline:
+//! `cargo run -p classification --bin evaluate -- --dataset
+//! classification/eval/dataset.jsonl --recorded
+//! classification/eval/recorded.jsonl`
+//!
+//! Or the model can be run with a set of prompts, optionally recording its
+//! output for later comparisons:
+//! `cargo run -p classification --bin evaluate -- --dataset
+//! classification/eval/dataset.jsonl --prompt prompts/combined.txt --variant
+//! combined --record classification/eval/recorded.jsonl`
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::Parser;
+use classification::{
+    WhateverAsync,
+    eval::{LabelledItem, RecordedOutput, Report, evaluate},
+    populate_prompt,
+    runner::{Batching, ModelSettings, PipelineRunner},
+    shared_prefix_len,
+};
+use serde::{Serialize, de::DeserializeOwned};
+use snafu::{ResultExt, Whatever, whatever};
+use tokio::{fs, io::AsyncWriteExt, task::JoinSet};
+
+#[derive(Parser)]
+struct Args {
+    /// JSONL file of labelled items
+    #[arg(long)]
+    dataset: PathBuf,
+    /// JSONL file(s) of recorded model output to score
+    #[arg(long)]
+    recorded: Vec<PathBuf>,
+    /// Prompt file(s) to run through the model, each item gets one output per
+    /// prompt
+    #[arg(long)]
+    prompt: Vec<PathBuf>,
+    /// Name for the output produced by `--prompt`
+    #[arg(long, default_value = "live")]
+    variant: String,
+    /// Appends the output produced by `--prompt` to this file
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// How many prompts are run through the model together
+    #[arg(long, default_value_t = 1)]
+    batch_size: usize,
+    /// Milliseconds to wait for a batch to fill
+    #[arg(long, default_value_t = 50)]
+    batch_wait: u64,
+    #[command(flatten)]
+    model: ModelSettings,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Whatever> {
+    let args = Args::parse();
+    if args.recorded.is_empty() && args.prompt.is_empty() {
+        whatever!("nothing to evaluate; pass --recorded and/or --prompt");
+    }
+
+    let dataset: Vec<LabelledItem> = read_jsonl(&args.dataset).await?;
+    let mut recorded = vec![];
+    for path in &args.recorded {
+        recorded.extend(read_jsonl::<RecordedOutput>(path).await?);
+    }
+
+    if !args.prompt.is_empty() {
+        let batching = Batching {
+            size: args.batch_size,
+            max_wait: Duration::from_millis(args.batch_wait),
+        };
+        let live = run_model(&dataset, &args.prompt, &args.variant, batching, args.model).await?;
+        if let Some(path) = &args.record {
+            append_jsonl(path, &live).await?;
+        }
+        recorded.extend(live);
+    }
+
+    print!("{}", Report(&evaluate(&dataset, &recorded)));
+    Ok(())
+}
+
+async fn run_model(
+    dataset: &[LabelledItem],
+    prompts: &[PathBuf],
+    variant: &str,
+    batching: Batching,
+    settings: ModelSettings,
+) -> Result<Vec<RecordedOutput>, Whatever> {
+    let mut templates = vec![];
+    for path in prompts {
+        let template = fs::read_to_string(path)
+            .await
+            .with_whatever_context(|_| format!("reading prompt {}", path.display()))?
+            .replace('\t', "");
+        templates.push(template);
+    }
+    let templates = Arc::new(templates);
+    let runner = Arc::new(
+        PipelineRunner::setup(batching, settings)
+            .await
+            .whatever_context("setting up the model")?,
+    );
+
+    // Items run concurrently so the runner can batch them
+    let mut tasks = JoinSet::new();
+    for (index, item) in dataset.iter().enumerate() {
+        let (runner, templates) = (runner.clone(), templates.clone());
+        let (id, title, description) = (
+            item.id.clone(),
+            item.title.clone(),
+            item.description.clone(),
+        );
+        tasks.spawn(async move {
+            let mut outputs = vec![];
+            for template in templates.iter() {
+                let prompt = populate_prompt(template, &title, &description);
+                outputs.push(
+                    runner
+                        .run(prompt, shared_prefix_len(template))
+                        .await
+                        .with_whatever_context(|_| format!("running the model for {id}"))?
+                        .text,
+                );
+            }
+            Ok::<_, WhateverAsync>((index, id, outputs))
+        });
+    }
+
+    let mut recorded = vec![];
+    while let Some(result) = tasks.join_next().await {
+        let (index, id, outputs) = result
+            .whatever_context("joining model task")?
+            .whatever_context("running the model")?;
+        recorded.push((
+            index,
+            RecordedOutput {
+                variant: variant.to_owned(),
+                id,
+                outputs,
+            },
+        ));
+    }
+    // Keep the dataset's order, so recordings diff cleanly
+    recorded.sort_unstable_by_key(|(index, _)| *index);
+    Ok(recorded.into_iter().map(|(_, output)| output).collect())
+}
+
+async fn read_jsonl<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, Whatever> {
+    fs::read_to_string(path)
+        .await
+        .with_whatever_context(|_| format!("reading {}", path.display()))?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line)
+                .with_whatever_context(|_| format!("parsing a line of {}", path.display()))
+        })
+        .collect()
+}
+
+async fn append_jsonl<T: Serialize>(path: &Path, values: &[T]) -> Result<(), Whatever> {
+    let mut text = String::new();
+    for value in values {
+        text += &serde_json::to_string(value).whatever_context("serialising output")?;
+        text.push('\n');
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_whatever_context(|_| format!("opening {}", path.display()))?
+        .write_all(text.as_bytes())
+        .await
+        .with_whatever_context(|_| format!("writing {}", path.display()))
+}
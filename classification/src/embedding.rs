@@ -0,0 +1,158 @@
+use std::path::PathBuf;
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config};
+use hf_hub::{
+    Repo, RepoType,
+    api::tokio::{Api, ApiRepo},
+};
+use snafu::ResultExt;
+use tokenizers::{Tokenizer, TruncationParams};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::{JoinHandle, spawn_blocking},
+};
+use tracing::{Instrument, info_span, warn};
+
+use crate::{
+    Error, ParseConfigSnafu, ReadConfigSnafu, TokenizerLoadSnafu, VarBuilderLoadSnafu,
+    WhateverAsync,
+};
+
+pub const EMBEDDING_MODEL_ID: &str = "sentence-transformers/all-MiniLM-L6-v2";
+/// Length of the vectors produced by `EMBEDDING_MODEL_ID`
+pub const EMBEDDING_DIMENSIONS: usize = 384;
+/// Tokens past this are ignored, matches the length the model was trained on
+const MAX_TOKENS: usize = 256;
+
+/// Text to embed and where to send the result
+type Job = (String, oneshot::Sender<Result<Vec<f32>, WhateverAsync>>);
+
+/// Turns text into a normalised sentence embedding, suitable for cosine
+/// similarity.
+pub struct EmbeddingRunner {
+    embedding_task: JoinHandle<()>,
+    embedding_tx: mpsc::Sender<Job>,
+}
+
+impl EmbeddingRunner {
+    pub async fn setup() -> Result<Self, Error> {
+        let span = info_span!("EmbeddingRunner::setup");
+        let _g = span.enter();
+        let api = Api::new().map_err(|e| Error::ApiInit {
+            message: e.to_string(),
+        })?;
+        let repo = api.repo(Repo::with_revision(
+            EMBEDDING_MODEL_ID.to_string(),
+            RepoType::Model,
+            "main".to_string(),
+        ));
+        let weights = get(&repo, "model.safetensors")
+            .instrument(info_span!(parent: &span, "get weights"))
+            .await?;
+        let tokenizer = get(&repo, "tokenizer.json")
+            .instrument(info_span!(parent: &span, "get tokenizer"))
+            .await?;
+        let config = get(&repo, "config.json")
+            .instrument(info_span!(parent: &span, "get config"))
+            .await?;
+
+        let device = Device::Cpu;
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[weights], DType::F32, &device)
+                .context(VarBuilderLoadSnafu)?
+        };
+        let mut tokenizer = Tokenizer::from_file(tokenizer).context(TokenizerLoadSnafu)?;
+        tokenizer
+            .with_padding(None)
+            .with_truncation(Some(TruncationParams {
+                max_length: MAX_TOKENS,
+                ..Default::default()
+            }))
+            .map_err(|e| Error::TokenizerLoad { source: e })?;
+        let file_bytes = tokio::fs::read(config)
+            .instrument(info_span!(parent: &span, "read config"))
+            .await
+            .context(ReadConfigSnafu)?;
+        let config: Config = serde_json::from_slice(&file_bytes).context(ParseConfigSnafu)?;
+
+        let (embedding_tx, mut embedding_rx) = mpsc::channel::<Job>(1);
+        let embedding_task: JoinHandle<()> = spawn_blocking(move || {
+            let model =
+                info_span!("Model setup").in_scope(|| BertModel::load(vb, &config).unwrap());
+            while let Some((text, reply)) = embedding_rx.blocking_recv() {
+                let _ = info_span!("run embedding")
+                    .in_scope(|| reply.send(embed(&model, &tokenizer, &device, &text)));
+            }
+
+            warn!("Channel has dropped; exiting");
+        });
+
+        Ok(Self {
+            embedding_task,
+            embedding_tx,
+        })
+    }
+
+    /// Embeds `text`, the result has `EMBEDDING_DIMENSIONS` values and unit
+    /// length.
+    pub async fn embed(&self, text: String) -> Result<Vec<f32>, WhateverAsync> {
+        let (tx, rx) = oneshot::channel();
+        self.embedding_tx
+            .send((text, tx))
+            .await
+            .whatever_context("sending to embedding task")?;
+        rx.await.whatever_context("receiving response")?
+    }
+}
+
+impl Drop for EmbeddingRunner {
+    fn drop(&mut self) {
+        self.embedding_task.abort();
+    }
+}
+
+async fn get(repo: &ApiRepo, filename: &'static str) -> Result<PathBuf, Error> {
+    repo.get(filename).await.map_err(|e| Error::RepoGet {
+        filename,
+        message: e.to_string(),
+    })
+}
+
+/// Mean pools the token embeddings and normalises the result.
+fn embed(
+    model: &BertModel,
+    tokenizer: &Tokenizer,
+    device: &Device,
+    text: &str,
+) -> Result<Vec<f32>, WhateverAsync> {
+    let encoding = tokenizer
+        .encode(text, true)
+        .map_err(|e| e.to_string())
+        .whatever_context("encode text")?;
+    let token_ids = Tensor::new(encoding.get_ids(), device)
+        .whatever_context("create input tensor")?
+        .unsqueeze(0)
+        .whatever_context("unsqueeze batch dim")?;
+    let token_type_ids = token_ids
+        .zeros_like()
+        .whatever_context("create token type tensor")?;
+    let embeddings = model
+        .forward(&token_ids, &token_type_ids, None)
+        .whatever_context("model forward")?;
+    let pooled = embeddings
+        .mean(1)
+        .whatever_context("mean pool tokens")?
+        .squeeze(0)
+        .whatever_context("squeeze batch dim")?;
+    let norm = pooled
+        .sqr()
+        .and_then(|squared| squared.sum_all())
+        .and_then(|sum| sum.sqrt())
+        .whatever_context("compute norm")?;
+    pooled
+        .broadcast_div(&norm)
+        .and_then(|normalised| normalised.to_vec1::<f32>())
+        .whatever_context("normalise embedding")
+}
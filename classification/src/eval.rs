@@ -0,0 +1,217 @@
+//! Scoring of extraction output against a labelled dataset, so that prompt and
+//! model changes can be compared without eyeballing `dbg!` output.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{MLProperties, sanitise_output};
+
+/// A workshop item along with the properties we expect to be extracted
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LabelledItem {
+    pub id: String,
+    pub title: String,
+    pub description: String,
+    pub expected: MLProperties,
+}
+
+/// The raw model output for an item, one entry per prompt that was run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedOutput {
+    /// Names the prompt/model combination that produced the output
+    pub variant: String,
+    /// The `LabelledItem::id` this output is for
+    pub id: String,
+    pub outputs: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub false_negatives: usize,
+}
+
+impl Counts {
+    fn add(&mut self, expected: &[String], predicted: &[String]) {
+        let normalise = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| value.trim().to_lowercase())
+                .collect::<BTreeSet<_>>()
+        };
+        let expected = normalise(expected);
+        let predicted = normalise(predicted);
+        let matched = expected.intersection(&predicted).count();
+        self.true_positives += matched;
+        self.false_positives += predicted.len() - matched;
+        self.false_negatives += expected.len() - matched;
+    }
+
+    /// The fraction of predicted values that were expected, 0 when nothing was
+    /// predicted
+    pub fn precision(&self) -> f64 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_positives,
+        )
+    }
+
+    /// The fraction of expected values that were predicted, 0 when nothing was
+    /// expected
+    pub fn recall(&self) -> f64 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_negatives,
+        )
+    }
+}
+
+#[expect(clippy::cast_precision_loss, reason = "counts are tiny")]
+fn ratio(numerator: usize, denominator: usize) -> f64 {
+    if denominator == 0 {
+        0.0
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+/// Scores for a single variant across the whole dataset
+#[derive(Debug, Default, Clone)]
+pub struct Scores {
+    pub classes: BTreeMap<&'static str, Counts>,
+    /// Total model outputs seen
+    pub outputs: usize,
+    /// Outputs that weren't valid JSON after `sanitise_output`
+    pub parse_failures: usize,
+    /// Dataset items with no recorded output for this variant
+    pub missing: usize,
+}
+
+impl Scores {
+    /// Scores the outputs for one item, unparsable outputs contribute nothing
+    /// so their expected values count as misses.
+    pub fn add(&mut self, expected: &MLProperties, outputs: &[String]) {
+        let mut predicted = MLProperties::default();
+        for output in outputs {
+            self.outputs += 1;
+            match serde_json::from_str::<MLProperties>(&sanitise_output(output.clone())) {
+                Ok(properties) => predicted.merge(properties),
+                Err(_) => self.parse_failures += 1,
+            }
+        }
+        for (class, expected, predicted) in [
+            ("genres", &expected.genres, &predicted.genres),
+            ("themes", &expected.themes, &predicted.themes),
+            ("types", &expected.types, &predicted.types),
+            ("features", &expected.features, &predicted.features),
+        ] {
+            self.classes
+                .entry(class)
+                .or_default()
+                .add(expected, predicted);
+        }
+    }
+
+    pub fn parse_failure_rate(&self) -> f64 {
+        ratio(self.parse_failures, self.outputs)
+    }
+}
+
+/// Scores every variant present in `recorded` against `dataset`
+pub fn evaluate(dataset: &[LabelledItem], recorded: &[RecordedOutput]) -> BTreeMap<String, Scores> {
+    let variants = recorded
+        .iter()
+        .map(|output| output.variant.as_str())
+        .collect::<BTreeSet<_>>();
+    variants
+        .into_iter()
+        .map(|variant| {
+            let mut scores = Scores::default();
+            for item in dataset {
+                match recorded
+                    .iter()
+                    .find(|output| output.variant == variant && output.id == item.id)
+                {
+                    Some(output) => scores.add(&item.expected, &output.outputs),
+                    None => scores.missing += 1,
+                }
+            }
+            (variant.to_owned(), scores)
+        })
+        .collect()
+}
+
+/// Renders scores as a table, one block per variant
+pub struct Report<'a>(pub &'a BTreeMap<String, Scores>);
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<24} {:<10} {:>9} {:>9} {:>5} {:>5} {:>5}",
+            "variant", "class", "precision", "recall", "tp", "fp", "fn"
+        )?;
+        for (variant, scores) in self.0 {
+            for (class, counts) in &scores.classes {
+                writeln!(
+                    f,
+                    "{variant:<24} {class:<10} {:>9.3} {:>9.3} {:>5} {:>5} {:>5}",
+                    counts.precision(),
+                    counts.recall(),
+                    counts.true_positives,
+                    counts.false_positives,
+                    counts.false_negatives
+                )?;
+            }
+            writeln!(
+                f,
+                "{variant:<24} parse failures {}/{} ({:.1}%), missing items {}",
+                scores.parse_failures,
+                scores.outputs,
+                scores.parse_failure_rate() * 100.0,
+                scores.missing
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::eval::{LabelledItem, RecordedOutput, Report, evaluate};
+
+    fn read_jsonl<T: serde::de::DeserializeOwned>(text: &str) -> Vec<T> {
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_recorded_outputs() {
+        let dataset: Vec<LabelledItem> = read_jsonl(include_str!("../eval/dataset.jsonl"));
+        let recorded: Vec<RecordedOutput> = read_jsonl(include_str!("../eval/recorded.jsonl"));
+        let results = evaluate(&dataset, &recorded);
+        println!("{}", Report(&results));
+
+        let split = &results["split"];
+        assert_eq!(split.outputs, 10);
+        assert_eq!(split.parse_failures, 0);
+        assert_eq!(split.missing, 0);
+
+        let combined = &results["combined"];
+        assert_eq!(combined.outputs, 5);
+        assert_eq!(combined.parse_failures, 1);
+        let types = combined.classes["types"];
+        assert_eq!(types.true_positives, 4);
+        assert_eq!(types.false_negatives, 1);
+        assert_eq!(types.false_positives, 0);
+        assert!((types.precision() - 1.0).abs() < f64::EPSILON);
+        assert!((types.recall() - 0.8).abs() < f64::EPSILON);
+    }
+}
@@ -1,25 +1,29 @@
 #[cfg(feature = "mkl")]
 extern crate intel_mkl_src;
 pub mod actor;
+pub mod embedding;
+pub mod eval;
 mod hub;
-mod runner;
+mod mistral;
+pub mod runner;
 
 use std::backtrace::Backtrace;
 
 use snafu::{ResultExt, Snafu, whatever};
 
-const MODEL_ID: &str = "mistralai/Mistral-7B-Instruct-v0.2";
+pub const MODEL_ID: &str = "mistralai/Mistral-7B-Instruct-v0.2";
 
-use candle_core::{DType, Device, Tensor};
+use std::collections::HashMap;
+
+use candle_core::{D, DType, Device, Tensor};
 use candle_examples::token_output_stream::TokenOutputStream;
-use candle_transformers::{
-    generation::{LogitsProcessor, Sampling},
-    models::mistral::Model,
-};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 use tracing::{debug, instrument};
 
+use crate::mistral::Model;
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("failed to initialise HF Hub API: {message}"))]
@@ -117,6 +121,15 @@ pub struct TextGeneration {
     logits_processor: LogitsProcessor,
     repeat_penalty: f32,
     repeat_last_n: usize,
+    prefix_cache: Option<PrefixCache>,
+}
+
+/// A snapshot of the model after it has consumed the static start of a prompt,
+/// cloning a `Model` is cheap as the KV cache tensors are reference counted.
+struct PrefixCache {
+    text: String,
+    tokens: Vec<u32>,
+    model: Model,
 }
 
 impl TextGeneration {
@@ -154,19 +167,79 @@ impl TextGeneration {
             repeat_last_n,
             device: device.clone(),
             tokenizer: TokenOutputStream::new(tokenizer),
+            prefix_cache: None,
+        }
+    }
+
+    /// Primes the KV cache with `prefix`, reusing the previous run's cache when
+    /// the prefix is unchanged. Returns how many of `tokens` are already in the
+    /// cache.
+    fn restore_prefix(&mut self, prefix: &str, tokens: &[u32]) -> Result<usize, WhateverAsync> {
+        if prefix.is_empty() {
+            self.model.clear_kv_cache();
+            return Ok(0);
+        }
+        if self
+            .prefix_cache
+            .as_ref()
+            .is_none_or(|cache| cache.text != prefix)
+        {
+            let prefix_tokens = self
+                .tokenizer
+                .tokenizer()
+                .encode(prefix, true)
+                .map_err(|e| e.to_string())
+                .whatever_context("encode prompt prefix")?
+                .get_ids()
+                .to_vec();
+            self.model.clear_kv_cache();
+            let input = Tensor::new(prefix_tokens.as_slice(), &self.device)
+                .whatever_context("create prefix tensor")?
+                .unsqueeze(0)
+                .whatever_context("unsqueeze prefix batch dim")?;
+            self.model
+                .forward(&input, 0)
+                .whatever_context("prefix forward")?;
+            debug!(tokens = prefix_tokens.len(), "cached prompt prefix");
+            self.prefix_cache = Some(PrefixCache {
+                text: prefix.to_owned(),
+                tokens: prefix_tokens,
+                model: self.model.clone(),
+            });
+        }
+
+        match &self.prefix_cache {
+            Some(cache)
+                if tokens.len() > cache.tokens.len() && tokens.starts_with(&cache.tokens) =>
+            {
+                self.model = cache.model.clone();
+                Ok(cache.tokens.len())
+            }
+            _ => {
+                debug!("prompt tokens diverged from the cached prefix; running in full");
+                self.model.clear_kv_cache();
+                Ok(0)
+            }
         }
     }
 
+    /// Generates a completion for `prompt`, the first `prefix_len` bytes are
+    /// treated as shared between calls and their KV cache is reused.
     #[instrument(skip_all)]
-    fn run(&mut self, prompt: &str, sample_len: usize) -> Result<String, WhateverAsync> {
+    fn run(
+        &mut self,
+        prompt: &str,
+        prefix_len: usize,
+        sample_len: usize,
+    ) -> Result<Generation, WhateverAsync> {
         let encoding = self
             .tokenizer
             .tokenizer()
             .encode(prompt, true)
             .map_err(|e| e.to_string())
             .whatever_context("encode prompt")?;
-        self.model.clear_kv_cache();
         let mut tokens = encoding.get_ids().to_vec();
+        let cached = self.restore_prefix(&prompt[..prefix_len], &tokens)?;
         let mut input_tokens = String::new();
         for &t in tokens.iter() {
             if let Some(t) = self.tokenizer.next_token(t).unwrap() {
@@ -174,14 +247,12 @@ impl TextGeneration {
             }
         }
 
-        let eos_token = match self.tokenizer.get_token("</s>") {
-            Some(token) => token,
-            None => whatever!("cannot find the </s> token"),
-        };
+        let eos_token = self.eos_token()?;
         self.tokenizer.clear();
         let mut output = String::new();
+        let mut token_logprobs = vec![];
         for index in 0..sample_len {
-            let context_size = if index > 0 { 1 } else { tokens.len() };
+            let context_size = if index > 0 { 1 } else { tokens.len() - cached };
             let start_pos = tokens.len().saturating_sub(context_size);
             let ctxt = &tokens[start_pos..];
             let input = Tensor::new(ctxt, &self.device)
@@ -199,22 +270,7 @@ impl TextGeneration {
                 .whatever_context("squeeze seq dim")?
                 .to_dtype(DType::F32)
                 .whatever_context("cast logits to f32")?;
-            let logits = if self.repeat_penalty == 1. {
-                logits
-            } else {
-                let start_at = tokens.len().saturating_sub(self.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    self.repeat_penalty,
-                    &tokens[start_at..],
-                )
-                .whatever_context("apply_repeat_penalty")?
-            };
-
-            let next_token = self
-                .logits_processor
-                .sample(&logits)
-                .whatever_context("sample next token")?;
+            let (next_token, logprob) = self.sample(&tokens, &logits)?;
             tokens.push(next_token);
             if next_token == eos_token {
                 break;
@@ -226,6 +282,7 @@ impl TextGeneration {
             {
                 output.push_str(&t);
             }
+            token_logprobs.push((output.len(), logprob));
         }
         if let Some(rest) = self
             .tokenizer
@@ -236,16 +293,331 @@ impl TextGeneration {
         }
 
         debug!(output, "finished running ML");
-        Ok(output)
+        Ok(Generation {
+            text: output,
+            token_logprobs,
+        })
+    }
+
+    fn eos_token(&self) -> Result<u32, WhateverAsync> {
+        match self.tokenizer.get_token("</s>") {
+            Some(token) => Ok(token),
+            None => whatever!("cannot find the </s> token"),
+        }
+    }
+
+    /// Samples the next token from `logits` (f32, vocab sized), returning it
+    /// and its log probability.
+    fn sample(&mut self, tokens: &[u32], logits: &Tensor) -> Result<(u32, f32), WhateverAsync> {
+        let logits = if self.repeat_penalty == 1. {
+            logits.clone()
+        } else {
+            let start_at = tokens.len().saturating_sub(self.repeat_last_n);
+            candle_transformers::utils::apply_repeat_penalty(
+                logits,
+                self.repeat_penalty,
+                &tokens[start_at..],
+            )
+            .whatever_context("apply_repeat_penalty")?
+        };
+        let next_token = self
+            .logits_processor
+            .sample(&logits)
+            .whatever_context("sample next token")?;
+        let logprob = candle_nn::ops::log_softmax(&logits, D::Minus1)
+            .and_then(|logprobs| logprobs.get(next_token as usize))
+            .and_then(|logprob| logprob.to_scalar::<f32>())
+            .whatever_context("token log probability")?;
+        Ok((next_token, logprob))
+    }
+
+    /// Generates completions for several `(prompt, prefix_len)` pairs, see
+    /// `run`. Prompts sharing a prefix go through the model together, results
+    /// are in the same order as `prompts`.
+    pub(crate) fn run_batch(
+        &mut self,
+        prompts: &[(String, usize)],
+        sample_len: usize,
+    ) -> Vec<Result<Generation, WhateverAsync>> {
+        let mut groups: Vec<(&str, Vec<usize>)> = vec![];
+        for (index, (prompt, prefix_len)) in prompts.iter().enumerate() {
+            let prefix = &prompt[..*prefix_len];
+            match groups.iter_mut().find(|(text, _)| *text == prefix) {
+                Some((_, members)) => members.push(index),
+                None => groups.push((prefix, vec![index])),
+            }
+        }
+
+        let mut results: Vec<_> = prompts.iter().map(|_| None).collect();
+        for (prefix, members) in groups {
+            let batch: Vec<_> = members.iter().map(|&i| prompts[i].0.as_str()).collect();
+            let generations = if batch.len() > 1 {
+                self.run_padded(prefix, &batch, sample_len)
+                    .inspect_err(|error| debug!(?error, "batch failed"))
+                    .ok()
+                    .flatten()
+            } else {
+                None
+            };
+            match generations {
+                Some(generations) => {
+                    for (&index, generation) in members.iter().zip(generations) {
+                        results[index] = Some(Ok(generation));
+                    }
+                }
+                // Run them one at a time, so each gets its own error
+                None => {
+                    for &index in &members {
+                        let (prompt, prefix_len) = &prompts[index];
+                        results[index] = Some(self.run(prompt, *prefix_len, sample_len));
+                    }
+                }
+            }
+        }
+        results.into_iter().flatten().collect()
+    }
+
+    /// Runs prompts sharing `prefix` as one batch. Each row is laid out as
+    /// `[cached prefix][padding][prompt]` so they all end on the same column,
+    /// padding is masked out and positions skip over it. Returns `None` when
+    /// the prompts can't share the cached prefix.
+    #[instrument(skip_all, fields(batch = prompts.len()))]
+    #[allow(clippy::cast_possible_truncation)]
+    fn run_padded(
+        &mut self,
+        prefix: &str,
+        prompts: &[&str],
+        sample_len: usize,
+    ) -> Result<Option<Vec<Generation>>, WhateverAsync> {
+        let mut sequences = Vec::with_capacity(prompts.len());
+        for prompt in prompts {
+            let encoding = self
+                .tokenizer
+                .tokenizer()
+                .encode(*prompt, true)
+                .map_err(|e| e.to_string())
+                .whatever_context("encode prompt")?;
+            sequences.push(Sequence::new(
+                encoding.get_ids().to_vec(),
+                self.tokenizer.tokenizer().clone(),
+            ));
+        }
+        let cached = self.restore_prefix(prefix, &sequences[0].tokens)?;
+        let prefix_tokens = sequences[0].tokens[..cached].to_vec();
+        if sequences
+            .iter()
+            .any(|seq| seq.tokens.len() <= cached || !seq.tokens.starts_with(&prefix_tokens))
+        {
+            return Ok(None);
+        }
+        let eos_token = self.eos_token()?;
+        let batch = sequences.len();
+        self.model
+            .expand_batch(batch)
+            .whatever_context("expand prefix cache")?;
+
+        let longest = sequences
+            .iter()
+            .map(|seq| seq.tokens.len() - cached)
+            .max()
+            .unwrap_or_default();
+        let width = cached + longest;
+        let mut input = Vec::with_capacity(batch * longest);
+        let mut positions = Vec::with_capacity(batch * longest);
+        let mut mask = Vec::with_capacity(batch * longest * width);
+        // Per row, which keys in the KV cache are real tokens
+        let mut visible = Vec::with_capacity(batch);
+        for seq in &sequences {
+            let padding = longest - (seq.tokens.len() - cached);
+            input.extend(std::iter::repeat_n(eos_token, padding));
+            input.extend_from_slice(&seq.tokens[cached..]);
+            positions.extend(
+                (0..longest).map(|column| (cached + column.saturating_sub(padding)) as u32),
+            );
+            let keys: Vec<bool> = (0..width)
+                .map(|key| key < cached || key - cached >= padding)
+                .collect();
+            for query in 0..longest {
+                // Padding attends to itself, a row that's entirely masked
+                // softmaxes to NaN
+                mask.extend((0..width).map(|key| {
+                    let causal = key < cached || key - cached <= query;
+                    if causal && (keys[key] || key == cached + query) {
+                        0.
+                    } else {
+                        f32::NEG_INFINITY
+                    }
+                }));
+            }
+            visible.push(keys);
+        }
+        let input = Tensor::from_vec(input, (batch, longest), &self.device)
+            .whatever_context("create input tensor")?;
+        let positions = Tensor::from_vec(positions, (batch, longest), &self.device)
+            .whatever_context("create positions tensor")?;
+        let mask = Tensor::from_vec(mask, (batch, 1, longest, width), &self.device)
+            .whatever_context("create mask tensor")?;
+        let mut logits = self
+            .model
+            .forward_masked(&input, &positions, Some(&mask))
+            .whatever_context("model forward")?;
+
+        for step in 0..sample_len {
+            let batch_logits = logits
+                .squeeze(1)
+                .whatever_context("squeeze seq dim")?
+                .to_dtype(DType::F32)
+                .whatever_context("cast logits to f32")?;
+            for (row, seq) in sequences.iter_mut().enumerate() {
+                if seq.finished {
+                    continue;
+                }
+                let row_logits = batch_logits.get(row).whatever_context("row logits")?;
+                let (next_token, logprob) = self.sample(&seq.tokens, &row_logits)?;
+                seq.push(next_token, logprob, eos_token)?;
+            }
+            if step + 1 == sample_len || sequences.iter().all(|seq| seq.finished) {
+                break;
+            }
+
+            // Finished rows keep being fed their last token, their output is
+            // ignored
+            let input: Vec<u32> = sequences
+                .iter()
+                .map(|seq| seq.tokens.last().copied().unwrap_or(eos_token))
+                .collect();
+            let positions: Vec<u32> = sequences
+                .iter()
+                .map(|seq| (seq.tokens.len() - 1) as u32)
+                .collect();
+            let mut mask = Vec::with_capacity(batch * (visible[0].len() + 1));
+            for keys in &mut visible {
+                keys.push(true);
+                mask.extend(
+                    keys.iter()
+                        .map(|&real| if real { 0. } else { f32::NEG_INFINITY }),
+                );
+            }
+            let width = visible[0].len();
+            let input = Tensor::from_vec(input, (batch, 1), &self.device)
+                .whatever_context("create input tensor")?;
+            let positions = Tensor::from_vec(positions, (batch, 1), &self.device)
+                .whatever_context("create positions tensor")?;
+            let mask = Tensor::from_vec(mask, (batch, 1, 1, width), &self.device)
+                .whatever_context("create mask tensor")?;
+            logits = self
+                .model
+                .forward_masked(&input, &positions, Some(&mask))
+                .whatever_context("model forward")?;
+        }
+
+        let generations = sequences
+            .into_iter()
+            .map(Sequence::finish)
+            .collect::<Result<Vec<_>, _>>()?;
+        debug!(outputs = ?generations, "finished running ML batch");
+        Ok(Some(generations))
+    }
+}
+
+/// A prompt being generated as part of a batch
+struct Sequence {
+    tokens: Vec<u32>,
+    stream: TokenOutputStream,
+    generation: Generation,
+    finished: bool,
+}
+
+impl Sequence {
+    fn new(tokens: Vec<u32>, tokenizer: Tokenizer) -> Self {
+        Self {
+            tokens,
+            stream: TokenOutputStream::new(tokenizer),
+            generation: Generation::default(),
+            finished: false,
+        }
+    }
+
+    fn push(&mut self, token: u32, logprob: f32, eos_token: u32) -> Result<(), WhateverAsync> {
+        self.tokens.push(token);
+        if token == eos_token {
+            self.finished = true;
+            return Ok(());
+        }
+        if let Some(t) = self
+            .stream
+            .next_token(token)
+            .whatever_context("stream next token")?
+        {
+            self.generation.text.push_str(&t);
+        }
+        self.generation
+            .token_logprobs
+            .push((self.generation.text.len(), logprob));
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Generation, WhateverAsync> {
+        if let Some(rest) = self
+            .stream
+            .decode_rest()
+            .whatever_context("decode remaining tokens")?
+        {
+            self.generation.text.push_str(&rest);
+        }
+        Ok(self.generation)
+    }
+}
+
+/// Text produced by the model along with how likely it found each token
+#[derive(Debug, Clone, Default)]
+pub struct Generation {
+    pub text: String,
+    /// The byte offset in `text` each token ends at and its log probability;
+    /// tokens that end part way through a character are placed before it.
+    pub token_logprobs: Vec<(usize, f32)>,
+}
+
+impl Generation {
+    /// The geometric mean probability of the tokens that produced `value`
+    /// within the JSON array under `key`, or `None` if it can't be found.
+    pub fn value_confidence(&self, key: &str, value: &str) -> Option<f32> {
+        let key = serde_json::to_string(key).ok()?;
+        let value = serde_json::to_string(value).ok()?;
+        let after_key = self.text.find(&key)? + key.len();
+        let array_start = after_key + self.text[after_key..].find('[')?;
+        // Anything but the colon means the key doesn't hold an array
+        if self.text[after_key..array_start].trim() != ":" {
+            return None;
+        }
+        let array_end = array_start + enclosed_len(&self.text[array_start..], '[', ']')?;
+        // Skip the quotes, so the tokens either side aren't counted
+        let start = array_start + self.text[array_start..array_end].find(&value)? + 1;
+        let end = start + value.len() - 2;
+
+        let mut token_start = 0;
+        let mut total = 0.0;
+        let mut count = 0u16;
+        for &(token_end, logprob) in &self.token_logprobs {
+            if token_end > start && token_start < end {
+                total += logprob;
+                count += 1;
+            }
+            token_start = token_end;
+        }
+        (count > 0).then(|| (total / f32::from(count)).exp())
     }
 }
 
 /// The text replacement cases were things I ran into during development, the
 /// '{' search is an effort to protect against prompt repeating by the model.
+/// The output ends at the brace closing the first object, so nested objects and
+/// anything the model adds after it don't break parsing.
 pub fn sanitise_output(string: String) -> String {
     let nothing = "";
-    let string =
-        &string[string.find('{').unwrap_or_default()..string.find('}').unwrap_or(string.len())];
+    let start = string.find('{').unwrap_or_default();
+    let end = enclosed_len(&string[start..], '{', '}').map_or(string.len(), |len| start + len);
+    let string = &string[start..end];
     string
         .replace("<br>", nothing)
         .replace("###", nothing)
@@ -255,6 +627,37 @@ pub fn sanitise_output(string: String) -> String {
         .replace("Output:", nothing)
 }
 
+/// The length of the object or array at the start of `string` including its
+/// `close` character, those inside JSON strings don't count.
+fn enclosed_len(string: &str, open: char, close: char) -> Option<usize> {
+    let mut depth = 0_usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in string.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            _ if c == open => depth += 1,
+            _ if c == close => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
 /// This took a lot longer to develop than expected, because, having a tab
 /// character at the end would sometimes cause the model to just repeat the end
 /// of its prompt.
@@ -265,7 +668,21 @@ pub fn populate_prompt(prompt: &str, title: &str, description: &str) -> String {
         .replace("\t", "")
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The length of the item independent start of a prompt template, cut back to
+/// the last line break so that it tokenises the same way alone as it does
+/// inside a populated prompt.
+pub fn shared_prefix_len(prompt: &str) -> usize {
+    let placeholder = ["[TITLE]", "[DESCRIPTION]"]
+        .iter()
+        .filter_map(|placeholder| prompt.find(placeholder))
+        .min()
+        .unwrap_or(0);
+    prompt[..placeholder]
+        .rfind('\n')
+        .map_or(0, |index| index + 1)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct MLProperties {
     #[serde(default)]
     pub genres: Vec<String>,
@@ -277,6 +694,189 @@ pub struct MLProperties {
     pub features: Vec<String>,
 }
 
+impl MLProperties {
+    /// Appends the values from `other`, used when a pipeline runs several
+    /// prompts for one item.
+    pub fn merge(&mut self, other: MLProperties) {
+        self.genres.extend(other.genres);
+        self.themes.extend(other.themes);
+        self.types.extend(other.types);
+        self.features.extend(other.features);
+    }
+
+    /// Each value alongside the name of its class
+    pub fn values(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("genres", &self.genres),
+            ("themes", &self.themes),
+            ("types", &self.types),
+            ("features", &self.features),
+        ]
+        .into_iter()
+        .flat_map(|(name, values)| values.iter().map(move |value| (name, value.as_str())))
+    }
+}
+
+/// How sure the model was of each value it extracted, from 0 to 1
+#[derive(Debug, Default, Clone)]
+pub struct Confidence(HashMap<(String, String), f32>);
+
+impl Confidence {
+    /// Scores every value in `properties` against the generation they were
+    /// parsed from, a value seen more than once keeps its highest score.
+    pub fn score(&mut self, generation: &Generation, properties: &MLProperties) {
+        for (class, value) in properties.values() {
+            if let Some(confidence) = generation.value_confidence(class, value) {
+                self.0
+                    .entry((class.to_owned(), confidence_key(value)))
+                    .and_modify(|existing| *existing = existing.max(confidence))
+                    .or_insert(confidence);
+            }
+        }
+    }
+
+    /// Looks up a value by class name (`"genres"`, `"themes"`, `"types"` or
+    /// `"features"`), matching as loosely as `Vocabulary::canonicalise` so
+    /// canonical values find the score of what the model wrote.
+    pub fn get(&self, class: &str, value: &str) -> Option<f32> {
+        self.0
+            .get(&(class.to_owned(), confidence_key(value)))
+            .copied()
+    }
+}
+
+fn confidence_key(value: &str) -> String {
+    normalise(strip_other(value.trim()).unwrap_or(value))
+}
+
+/// The result of running the pipeline over an item, along with what produced
+/// it.
+#[derive(Debug)]
+pub struct Extraction {
+    pub properties: MLProperties,
+    /// The hub ID of the model that was run
+    pub model_id: String,
+    /// Identifies the prompts that were run, changes along with their content
+    pub prompt_version: String,
+    pub confidence: Confidence,
+}
+
+/// FNV-1a, used instead of `DefaultHasher` as the result is persisted and so
+/// must be stable between builds.
+pub fn content_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Marks a value the model chose outside a closed vocabulary
+const OTHER_PREFIX: &str = "other:";
+
+/// The accepted values per class offered to the model when classifying
+/// against a closed vocabulary.
+#[derive(Debug, Default, Clone)]
+pub struct Vocabulary(pub MLProperties);
+
+/// Model output split into values that matched the vocabulary (in their
+/// canonical spelling) and values the model suggested outside of it.
+#[derive(Debug, Default)]
+pub struct Canonicalised {
+    pub known: MLProperties,
+    pub other: MLProperties,
+}
+
+impl Vocabulary {
+    /// Substitutes the `[GENRES]`, `[THEMES]`, `[TYPES]` and `[FEATURES]`
+    /// placeholders with the allowed values.
+    pub fn populate(&self, prompt: &str) -> String {
+        let list = |values: &[String]| serde_json::to_string(values).unwrap_or_default();
+        prompt
+            .replace("[GENRES]", &list(&self.0.genres))
+            .replace("[THEMES]", &list(&self.0.themes))
+            .replace("[TYPES]", &list(&self.0.types))
+            .replace("[FEATURES]", &list(&self.0.features))
+    }
+
+    /// Maps model output onto the vocabulary's spelling of each value, anything
+    /// that doesn't match, or was explicitly marked as "other", is kept aside.
+    pub fn canonicalise(&self, properties: MLProperties) -> Canonicalised {
+        let mut result = Canonicalised::default();
+        canonicalise_values(
+            &self.0.genres,
+            properties.genres,
+            &mut result.known.genres,
+            &mut result.other.genres,
+        );
+        canonicalise_values(
+            &self.0.themes,
+            properties.themes,
+            &mut result.known.themes,
+            &mut result.other.themes,
+        );
+        canonicalise_values(
+            &self.0.types,
+            properties.types,
+            &mut result.known.types,
+            &mut result.other.types,
+        );
+        canonicalise_values(
+            &self.0.features,
+            properties.features,
+            &mut result.known.features,
+            &mut result.other.features,
+        );
+        result
+    }
+}
+
+/// Case and a trailing plural 's' are the differences seen most in practice.
+/// Only one is dropped, and not from short words like "gas".
+fn normalise(value: &str) -> String {
+    let value = value.trim().to_lowercase();
+    match value.strip_suffix('s') {
+        Some(singular) if singular.chars().count() >= 3 => singular.to_owned(),
+        _ => value,
+    }
+}
+
+/// The suggestion from an "other: <value>" answer
+fn strip_other(value: &str) -> Option<&str> {
+    value
+        .get(..OTHER_PREFIX.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(OTHER_PREFIX))
+        .map(|_| value[OTHER_PREFIX.len()..].trim())
+}
+
+fn canonicalise_values(
+    allowed: &[String],
+    values: Vec<String>,
+    known: &mut Vec<String>,
+    other: &mut Vec<String>,
+) {
+    for value in values {
+        let value = value.trim();
+        if let Some(suggestion) = strip_other(value) {
+            if !suggestion.is_empty() && !other.iter().any(|v| v == suggestion) {
+                other.push(suggestion.to_owned());
+            }
+            continue;
+        }
+
+        let normalised = normalise(value);
+        match allowed
+            .iter()
+            .find(|allowed| normalise(allowed) == normalised)
+        {
+            Some(canonical) if !known.contains(canonical) => known.push(canonical.clone()),
+            Some(_) => {}
+            None if !value.is_empty() && !other.iter().any(|v| v == value) => {
+                other.push(value.to_owned());
+            }
+            None => {}
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::fs::read_to_string;
@@ -290,12 +890,116 @@ mod test {
     use tokenizers::Tokenizer;
 
     use crate::{
-        Error, MLProperties, MODEL_ID, ModelInitSnafu, ParseConfigSnafu, ReadConfigSnafu,
-        TextGeneration, TokenizerLoadSnafu, VarBuilderLoadSnafu, populate_prompt, sanitise_output,
+        Confidence, Error, Generation, MLProperties, MODEL_ID, ModelInitSnafu, ParseConfigSnafu,
+        ReadConfigSnafu, TextGeneration, TokenizerLoadSnafu, VarBuilderLoadSnafu, Vocabulary,
+        content_hash, normalise, populate_prompt, sanitise_output, shared_prefix_len,
     };
     const DMS_T: &str = "The Dead Man's Switch";
     const DMS_D: &str = r#"<a href="https://steamcommunity.com/id/FUJIKENGAWA/myworkshopfiles/?appid=294100&amp;sort=score&amp;browsefilter=myfiles&amp;view=imagewall" target="_blank"><img src="https://i.imgur.com/kvppfWO.png"></a><br><br>Millennia earlier, the threat of archotech war machines, pirates, and awry bio-organic weapons arose. Nara an Interstellar Industries Complex initiated the project of semi-automated war-machines, which aimed at creating a legion of war-machines with low technology and maintenance requirements to aid humans in all galaxies in the war against all those threats.<br><br>It was a quite successful project, and as countless fleets of unknown generations of ships marched toward the borders of human civilization, the production technology of these weapons also spread in the edge world... until today.<br><br><h1>Introduction</h1>This is a large-scale mod that was created around the cyberpunk theme of the 1990s. It has a lot of content, including heavy metallic weapons, industrial-tactical robots, and bio-mech bionic, so I don’t intend to teach you how to play it. You experience the story you want to play with the new choices! I believe you will find the joy you want.<br><br><h1>Content</h1><img src="https://i.imgur.com/Z61a1rv.png"><br>A new Scenario<br>A standalone technology tree<br>An Ideology style pack<br>A well-prepared interstellar colonization company<br>A royalty Title system based on military organization<br>A cargo load of heavy weapons and more than 20 types of military-industrial-style killing machines.<br>Mechanoids that have customizable weapons.<br>Cheap but costly bionic<br>lots of new clothes<br>CE compatibility<br><br>Try it for yourself, I guarantee it&#x27;s worth a try.<br><br><h1>FAQ</h1>Q:how to equip mech weapon? (For mech)<br>A:Select Mech and right-click the weapon, if the weapon is supported it will be available to equip.<br><br>Q:how to equip mech weapon? (For Colonists)<br>A:They need an exo-skelton suit before equip it.<br><br>Q:What weapon did a mech supported<br>A: you can find the supported weapon inside the info card.<br><br>Q: how to get ____ compoment<br>A: defeat boss group,or trading<br><br>Q: can i disable some of mech&#x27;s worktype?<br>A: <a href="https://steamcommunity.com/sharedfiles/filedetails/?id=3268299107" target="_blank">https://steamcommunity.com/sharedfiles/filedetails/?id=3268299107</a><br><h1>Warning - A new game is highly recommended</h1><br><h1>Known Issues</h1>work mech will get error loading with Rebound.<br><br><h1>Author’s words</h1>This is my fourth year modding in the Rimworld community, also my first year of studying for a master&#x27;s degree after college. I apologize to everyone who has been waiting for this mod for a long time (it’s been a while). Making such a big project is well-challenged.<br><br>DMS is a response that condenses my experience and understanding of countless works to the world and those works and creators who have profoundly affected my life.<br>Completing this project, which included my understanding of Rimworld art and those coolish Science Fiction styles, is also the realization of my dream of creating cool mecha works since I was a child.<br>Therefore, it is quite a torment in terms of technology, time, development enthusiasm, and progress management.<br>From the beginning of the project to the release, it spanned two game versions. Countless setbacks and subversions made me doubt my motivation to continue doing it more than once and questioned whether my work and abilities met expectations...<br><br>But this has all passed, and I have reached a reconciliation between my heavy academic workload and my pursuit of excellence. It was unanimously decided to release the mod before the end of 2023. which is today.<br>Although there may still be some minor problems, anyway still hope you enjoy it. _AOBA 2023/12/25<br><br>If you find any problems or have any ideas while playing, you can give feedback to the Discord group<br><a href="https://discord.gg/Pvj5Xj3yBm" target="_blank"><img src="https://i.ibb.co/CHY91mx/discord-Icon.png"></a><br><a href="https://www.paypal.com/paypalme/AobaKuma" target="_blank"><img src="https://i.ibb.co/cLqX4rv/Paypal-Icon.png"></a><br><a href="https://ko-fi.com/aobakuma" target="_blank"><img src="https://i.ibb.co/KhN0Tgp/Ko-Fi-Icon.png"></a><br><br><br>	"#;
 
+    #[test]
+    fn test_shared_prefix() {
+        let template = "Analyse this:\nTITLE: [TITLE]\nDESCRIPTION: [DESCRIPTION]";
+        let prefix_len = shared_prefix_len(template);
+        assert_eq!(&template[..prefix_len], "Analyse this:\n");
+
+        let populated = populate_prompt(template, "A", "B");
+        assert!(populated.starts_with(&template[..prefix_len]));
+        assert_eq!(shared_prefix_len("TITLE: [TITLE]"), 0);
+    }
+
+    #[test]
+    fn test_sanitise_output() {
+        assert_eq!(
+            sanitise_output(r#"Output: {"genres": ["sci-fi"]} {"genres": []}"#.into()),
+            r#"{"genres": ["sci-fi"]}"#
+        );
+        assert_eq!(
+            sanitise_output(r#"```json {"a": {"b": "}\""}}```"#.into()),
+            r#"{"a": {"b": "}\""}}"#
+        );
+        // Unterminated output is kept whole so the parse error shows it
+        assert_eq!(sanitise_output(r#"{"genres": ["#.into()), r#"{"genres": ["#);
+    }
+
+    #[test]
+    fn test_content_hash() {
+        // Known FNV-1a values, these must never change as they're persisted
+        assert_eq!(content_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(content_hash("a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_value_confidence() {
+        // One token per piece: `{"genres": ["`, `sci`, `-fi`, `"], "types": ["`,
+        // `other: mechs`, `"]}`
+        let pieces = [
+            ("{\"genres\": [\"", -0.1),
+            ("sci", -0.2),
+            ("-fi", -0.4),
+            ("\"], \"types\": [\"", -0.1),
+            ("other: mechs", -2.0),
+            ("\"]}", -0.1),
+        ];
+        let mut generation = Generation::default();
+        for (piece, logprob) in pieces {
+            generation.text.push_str(piece);
+            generation
+                .token_logprobs
+                .push((generation.text.len(), logprob));
+        }
+
+        let confidence = generation.value_confidence("genres", "sci-fi").unwrap();
+        assert!((confidence - (-0.3f32).exp()).abs() < 1e-6);
+        assert!(generation.value_confidence("themes", "sci-fi").is_none());
+        // Only the key's own array is searched
+        assert!(generation.value_confidence("genres", "mechs").is_none());
+        assert!(
+            generation
+                .value_confidence("genres", "other: mechs")
+                .is_none()
+        );
+
+        let properties: MLProperties = serde_json::from_str(&generation.text).unwrap();
+        let mut scores = Confidence::default();
+        scores.score(&generation, &properties);
+        // Found by the spelling used after canonicalisation
+        assert!((scores.get("genres", "Sci-Fi").unwrap() - confidence).abs() < 1e-6);
+        assert!((scores.get("types", "mechs").unwrap() - (-2.0f32).exp()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_canonicalise() {
+        let vocabulary = Vocabulary(MLProperties {
+            genres: vec!["sci-fi".into()],
+            themes: vec![],
+            types: vec!["expansion".into()],
+            features: vec!["weapons".into(), "mechanoids".into()],
+        });
+        let result = vocabulary.canonicalise(MLProperties {
+            genres: vec!["Sci-Fi".into()],
+            themes: vec!["other: cyberpunk".into(), "OTHER:".into()],
+            types: vec!["expansions".into(), "expansion".into()],
+            features: vec!["weapon".into(), "spacesuits".into()],
+        });
+        assert_eq!(result.known.genres, vec!["sci-fi"]);
+        assert_eq!(result.known.types, vec!["expansion"]);
+        assert_eq!(result.known.features, vec!["weapons"]);
+        assert_eq!(result.other.themes, vec!["cyberpunk"]);
+        assert_eq!(result.other.features, vec!["spacesuits"]);
+        assert!(result.other.genres.is_empty());
+
+        let prompt = vocabulary.populate("Genres: [GENRES]\nThemes: [THEMES]");
+        assert_eq!(prompt, "Genres: [\"sci-fi\"]\nThemes: []");
+    }
+
+    #[test]
+    fn test_normalise() {
+        assert_eq!(normalise(" Weapons "), "weapon");
+        assert_eq!(normalise("Bosses"), "bosse");
+        assert_eq!(normalise("gas"), "gas");
+    }
+
     #[test]
     fn test_dms() {
         let themes_prompt = read_to_string("../prompts/features.txt").unwrap();
@@ -372,6 +1076,9 @@ mod test {
             model, tokenizer, 299792458, None, None, None, 1.1, 64, &device,
         );
 
-        Ok(pipeline.run(prompt, 10000).map(sanitise_output).unwrap())
+        Ok(pipeline
+            .run(prompt, 0, 10000)
+            .map(|generation| sanitise_output(generation.text))
+            .unwrap())
     }
 }
@@ -0,0 +1,333 @@
+//! Mistral, adapted from `candle_transformers::models::mistral` to take an
+//! explicit attention mask and per sequence positions so padded prompts can be
+//! run as a batch. The sliding window is ignored, the instruct model we run
+//! doesn't use one.
+
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, Embedding, Linear, RmsNorm, VarBuilder, linear_no_bias, rms_norm};
+use candle_transformers::{models::mistral::Config, utils::repeat_kv};
+
+fn head_dim(cfg: &Config) -> usize {
+    cfg.head_dim
+        .unwrap_or(cfg.hidden_size / cfg.num_attention_heads)
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    #[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+    fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
+        let rope_theta = cfg.rope_theta as f32;
+        let dim = head_dim(cfg);
+        let max_seq_len = cfg.max_position_embeddings;
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / rope_theta.powf(i as f32 / dim as f32))
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: freqs.sin()?.to_dtype(dtype)?,
+            cos: freqs.cos()?.to_dtype(dtype)?,
+        })
+    }
+
+    /// `positions` is `(batch, seq_len)`, each sequence is rotated by its own
+    /// positions.
+    fn apply(&self, q: &Tensor, k: &Tensor, positions: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (b_sz, seq_len) = positions.dims2()?;
+        let flat = positions.flatten_all()?;
+        let half = self.cos.dim(1)?;
+        let cos = self
+            .cos
+            .index_select(&flat, 0)?
+            .reshape((b_sz, seq_len, half))?;
+        let sin = self
+            .sin
+            .index_select(&flat, 0)?
+            .reshape((b_sz, seq_len, half))?;
+        let q_embed = candle_nn::rotary_emb::rope(q, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope(k, &cos, &sin)?;
+        Ok((q_embed, k_embed))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: &VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let intermediate_sz = cfg.intermediate_size;
+        Ok(Self {
+            gate_proj: linear_no_bias(hidden_sz, intermediate_sz, vb.pp("gate_proj"))?,
+            up_proj: linear_no_bias(hidden_sz, intermediate_sz, vb.pp("up_proj"))?,
+            down_proj: linear_no_bias(intermediate_sz, hidden_sz, vb.pp("down_proj"))?,
+            act_fn: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: &VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let head_dim = head_dim(cfg);
+        Ok(Self {
+            q_proj: linear_no_bias(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?,
+            k_proj: linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?,
+            v_proj: linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?,
+            o_proj: linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?,
+            num_heads,
+            num_kv_heads,
+            num_kv_groups: num_heads / num_kv_heads,
+            head_dim,
+            rotary_emb,
+            kv_cache: None,
+        })
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        positions: &Tensor,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let query_states = self
+            .q_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let key_states = self
+            .k_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let value_states = self
+            .v_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (query_states, key_states) =
+            self.rotary_emb
+                .apply(&query_states, &key_states, positions)?;
+
+        let (key_states, value_states) = match &self.kv_cache {
+            None => (key_states, value_states),
+            Some((prev_k, prev_v)) => (
+                Tensor::cat(&[prev_k, &key_states], 2)?,
+                Tensor::cat(&[prev_v, &value_states], 2)?,
+            ),
+        };
+        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+
+        let key_states = repeat_kv(key_states, self.num_kv_groups)?.contiguous()?;
+        let value_states = repeat_kv(value_states, self.num_kv_groups)?.contiguous()?;
+
+        let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+        let attn_weights = (query_states.matmul(&key_states.t()?)? * scale)?;
+        let attn_weights = match attention_mask {
+            None => attn_weights,
+            Some(mask) => attn_weights.broadcast_add(mask)?,
+        };
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        attn_weights
+            .matmul(&value_states)?
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.num_heads * self.head_dim))?
+            .apply(&self.o_proj)
+    }
+
+    fn expand_batch(&mut self, size: usize) -> Result<()> {
+        if let Some((k, v)) = &self.kv_cache {
+            self.kv_cache = Some((k.repeat((size, 1, 1, 1))?, v.repeat((size, 1, 1, 1))?));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: &VarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: Attention::new(rotary_emb, cfg, &vb.pp("self_attn"))?,
+            mlp: Mlp::new(cfg, &vb.pp("mlp"))?,
+            input_layernorm: rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?,
+            post_attention_layernorm: rms_norm(
+                cfg.hidden_size,
+                cfg.rms_norm_eps,
+                vb.pp("post_attention_layernorm"),
+            )?,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        positions: &Tensor,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(&xs, attention_mask, positions)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+        residual + xs
+    }
+}
+
+/// Cloning is cheap, the weights and KV cache tensors are reference counted.
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: &VarBuilder) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(vb.dtype(), cfg, vb_m.device())?);
+        let vb_l = vb_m.pp("layers");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|layer_idx| DecoderLayer::new(rotary_emb.clone(), cfg, &vb_l.pp(layer_idx)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm: rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?,
+            lm_head: linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?,
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    /// Runs unpadded sequences that continue from `seqlen_offset` tokens in
+    /// the KV cache, returning the logits for the last token of each.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (b_sz, seq_len) = input_ids.dims2()?;
+        let positions = Tensor::arange(
+            seqlen_offset as u32,
+            (seqlen_offset + seq_len) as u32,
+            &self.device,
+        )?
+        .unsqueeze(0)?
+        .repeat((b_sz, 1))?;
+        let mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask: Vec<_> = (0..seq_len)
+                .flat_map(|i| {
+                    (0..seqlen_offset + seq_len).map(move |j| {
+                        if j > seqlen_offset + i {
+                            f32::NEG_INFINITY
+                        } else {
+                            0.
+                        }
+                    })
+                })
+                .collect();
+            Some(Tensor::from_vec(
+                mask,
+                (1, 1, seq_len, seqlen_offset + seq_len),
+                &self.device,
+            )?)
+        };
+        self.forward_masked(input_ids, &positions, mask.as_ref())
+    }
+
+    /// Runs `input_ids` (`(batch, seq_len)`) at the given `positions` (`u32`,
+    /// same shape), with an additive f32 `mask` broadcastable to `(batch, 1,
+    /// seq_len, cached + seq_len)`. Returns the logits for the last token of
+    /// each sequence.
+    pub fn forward_masked(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let (_, seq_len) = input_ids.dims2()?;
+        let mask = mask.map(|mask| mask.to_dtype(self.dtype)).transpose()?;
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in &mut self.layers {
+            xs = layer.forward(&xs, mask.as_ref(), positions)?;
+        }
+        xs.narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    /// Repeats a single sequence's KV cache `size` times, so several prompts
+    /// can continue from the same prefix.
+    pub fn expand_batch(&mut self, size: usize) -> Result<()> {
+        for layer in &mut self.layers {
+            layer.self_attn.expand_batch(size)?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.self_attn.kv_cache = None;
+        }
+    }
+}
@@ -1,26 +1,97 @@
+use std::time::Duration;
+
 use candle_core::{DType, Device};
 use candle_nn::VarBuilder;
-use candle_transformers::models::mistral::Model;
+use candle_transformers::models::mistral::Config;
 use hf_hub::{Repo, RepoType, api::tokio::Api};
 use snafu::ResultExt;
 use tokenizers::Tokenizer;
 use tokio::{
     sync::{mpsc, oneshot},
     task::{JoinHandle, spawn_blocking},
+    time::{Instant, timeout_at},
 };
 use tracing::{Instrument, info_span, warn};
 
 use crate::{
-    Error, MODEL_ID, ParseConfigSnafu, ReadConfigSnafu, TextGeneration, TokenizerLoadSnafu,
-    VarBuilderLoadSnafu, WhateverAsync, hub::hub_load_safetensors,
+    Error, Generation, MODEL_ID, ParseConfigSnafu, ReadConfigSnafu, TextGeneration,
+    TokenizerLoadSnafu, VarBuilderLoadSnafu, WhateverAsync, hub::hub_load_safetensors,
+    mistral::Model,
 };
 
+/// A prompt, the byte length of its shared prefix and where to send the result
+type Job = (
+    String,
+    usize,
+    oneshot::Sender<Result<Generation, WhateverAsync>>,
+);
+
+/// How queued prompts are grouped into a single forward pass. Batching trades
+/// latency for throughput, which matters most when backfilling on a CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct Batching {
+    /// The most prompts run together, 1 runs them one at a time
+    pub size: usize,
+    /// How long the first prompt waits for the rest of a batch
+    pub max_wait: Duration,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            size: 1,
+            max_wait: Duration::ZERO,
+        }
+    }
+}
+
+/// How the model samples its output, exposed as flags by the binaries
+#[derive(Debug, Clone, Copy, clap::Args)]
+pub struct ModelSettings {
+    /// Seed for sampling, only matters with a temperature
+    #[arg(long, default_value_t = 299_792_458)]
+    pub seed: u64,
+    /// Sampling temperature, greedy when unset or 0
+    #[arg(long)]
+    pub temperature: Option<f64>,
+    /// Nucleus sampling probability cutoff
+    #[arg(long)]
+    pub top_p: Option<f64>,
+    /// Only sample from the k most likely tokens
+    #[arg(long)]
+    pub top_k: Option<usize>,
+    /// Penalty applied to recently generated tokens, 1 disables it
+    #[arg(long, default_value_t = 1.1)]
+    pub repeat_penalty: f32,
+    /// How many recent tokens the repeat penalty looks at
+    #[arg(long, default_value_t = 64)]
+    pub repeat_last_n: usize,
+    /// The most tokens generated per prompt
+    #[arg(long, default_value_t = 100_000)]
+    pub sample_len: usize,
+}
+
+impl Default for ModelSettings {
+    fn default() -> Self {
+        Self {
+            seed: 299_792_458,
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            sample_len: 100_000,
+        }
+    }
+}
+
 pub struct PipelineRunner {
+    batch_task: JoinHandle<()>,
     pipeline_task: JoinHandle<()>,
-    pipeline_tx: mpsc::Sender<(String, oneshot::Sender<Result<String, WhateverAsync>>)>,
+    pipeline_tx: mpsc::Sender<Job>,
 }
 impl PipelineRunner {
-    pub async fn setup() -> Result<Self, Error> {
+    pub async fn setup(batching: Batching, settings: ModelSettings) -> Result<Self, Error> {
         let span = info_span!("PipelineRunner::setup");
         let _g = span.enter();
         let api = Api::new().map_err(|e| Error::ApiInit {
@@ -64,35 +135,73 @@ impl PipelineRunner {
             .instrument(info_span!(parent: &span, "read config"))
             .await
             .context(ReadConfigSnafu)?;
-        let config = info_span!(parent: &span, "parse config")
+        let config: Config = info_span!(parent: &span, "parse config")
             .in_scope(|| serde_json::from_slice(&file_bytes))
             .context(ParseConfigSnafu)?;
-        let (pipeline_tx, mut pipeline_rx) =
-            mpsc::channel::<(String, oneshot::Sender<Result<String, WhateverAsync>>)>(1);
+        let batch_size = batching.size.max(1);
+        let (pipeline_tx, mut pipeline_rx) = mpsc::channel::<Job>(batch_size);
+        let (batch_tx, mut batch_rx) = mpsc::channel::<Vec<Job>>(1);
+        let batch_task = tokio::spawn(async move {
+            while let Some(job) = pipeline_rx.recv().await {
+                let mut batch = vec![job];
+                let deadline = Instant::now() + batching.max_wait;
+                while batch.len() < batch_size {
+                    match timeout_at(deadline, pipeline_rx.recv()).await {
+                        Ok(Some(job)) => batch.push(job),
+                        Ok(None) | Err(_) => break,
+                    }
+                }
+                if batch_tx.send(batch).await.is_err() {
+                    break;
+                }
+            }
+        });
         let pipeline_task: JoinHandle<()> = spawn_blocking(move || {
-            let model = info_span!("Model setup").in_scope(|| Model::new(&config, vb).unwrap());
+            let model = info_span!("Model setup").in_scope(|| Model::new(&config, &vb).unwrap());
             let mut pipeline = TextGeneration::new(
-                model, tokenizer, 299792458, None, None, None, 1.1, 64, &device,
+                model,
+                tokenizer,
+                settings.seed,
+                settings.temperature,
+                settings.top_p,
+                settings.top_k,
+                settings.repeat_penalty,
+                settings.repeat_last_n,
+                &device,
             );
 
-            while let Some((task, reply)) = pipeline_rx.blocking_recv() {
-                let _ = info_span!("run model")
-                    .in_scope(|| reply.send(pipeline.run(task.as_str(), 100_000)));
+            while let Some(batch) = batch_rx.blocking_recv() {
+                let (prompts, replies): (Vec<_>, Vec<_>) = batch
+                    .into_iter()
+                    .map(|(task, prefix_len, reply)| ((task, prefix_len), reply))
+                    .unzip();
+                let results = info_span!("run model", batch = prompts.len())
+                    .in_scope(|| pipeline.run_batch(&prompts, settings.sample_len));
+                for (reply, result) in replies.into_iter().zip(results) {
+                    let _ = reply.send(result);
+                }
             }
 
             warn!("Channel has dropped; exiting");
         });
 
         Ok(Self {
+            batch_task,
             pipeline_task,
             pipeline_tx,
         })
     }
 
-    pub async fn run(&self, prompt: String) -> Result<String, WhateverAsync> {
+    /// Runs `prompt` through the model, `prefix_len` bytes from its start are
+    /// expected to repeat between calls and are served from the KV cache.
+    pub async fn run(
+        &self,
+        prompt: String,
+        prefix_len: usize,
+    ) -> Result<Generation, WhateverAsync> {
         let (tx, rx) = oneshot::channel();
         self.pipeline_tx
-            .send((prompt, tx))
+            .send((prompt, prefix_len, tx))
             .await
             .whatever_context("sending to tasks runner")?;
         rx.await.whatever_context("receiving response")?
@@ -101,6 +210,7 @@ impl PipelineRunner {
 
 impl Drop for PipelineRunner {
     fn drop(&mut self) {
+        self.batch_task.abort();
         self.pipeline_task.abort();
     }
 }
@@ -1,7 +1,13 @@
-use classification::actor::{ExtractionActor, ExtractionArgs};
+use std::time::Duration;
+
+use classification::{
+    MODEL_ID,
+    actor::{ExtractionActor, ExtractionArgs, PromptMode},
+    runner::Batching,
+};
 use ractor::Actor;
 use reqwest::Client;
-use snafu::{ResultExt, Whatever};
+use snafu::{OptionExt, ResultExt, Whatever, whatever};
 use surrealdb::{Surreal, engine::local::Db};
 use tracing::{Instrument, info_span, instrument};
 
@@ -11,9 +17,11 @@ use crate::{
         item_update_actor::{ItemUpdateActor, ItemUpdateArgs},
         properties_actor::{PropertiesActor, PropertiesArgs},
     },
+    domain::properties::Provenance,
     processing::{
-        bb_actor::{BBActor, BBArgs},
-        language_actor::{LanguageActor, LanguageArgs},
+        bb_actor::{BBActor, BBArgs, BBMsg},
+        embedding_actor::{EmbeddingActor, EmbeddingArgs, EmbeddingMsg},
+        language_actor::{DetectedLanguage, LanguageActor, LanguageArgs},
         ml_queue_actor::{MLQueueActor, MLQueueArgs},
     },
     steam::steam_download_actor::{SteamDownloadActor, SteamDownloadArgs},
@@ -27,23 +35,59 @@ use crate::{
 pub async fn spawn(config: &Config, db: &Surreal<Db>) -> Result<(), Whatever> {
     let reqwest_client = Client::new();
 
+    let languages = match config.languages.as_slice() {
+        [] => DetectedLanguage::DEFAULT
+            .iter()
+            .filter_map(|language| language.language())
+            .collect(),
+        [all] if all.eq_ignore_ascii_case("all") => DetectedLanguage::ALL
+            .iter()
+            .filter_map(|language| language.language())
+            .collect(),
+        names => names
+            .iter()
+            .map(|name| {
+                DetectedLanguage::from_name(name)
+                    .and_then(DetectedLanguage::language)
+                    .with_whatever_context(|| format!("Unsupported language '{name}'"))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    if languages.len() == 1 {
+        whatever!("At least two languages are needed to tell them apart");
+    }
     let (language_actor, _) = Actor::spawn(
         Some("/language".to_string()),
         LanguageActor {},
-        LanguageArgs {},
+        LanguageArgs { languages },
     )
     .instrument(info_span!("spawn::language"))
     .await
     .whatever_context("Spawning language actor")?;
-    let (bb_actor, _) = Actor::spawn(Some("/bb".to_string()), BBActor {}, BBArgs {})
-        .instrument(info_span!("spawn::language"))
-        .await
-        .whatever_context("Spawning bb actor")?;
+    let (bb_actor, _) = Actor::spawn(
+        Some("/bb".to_string()),
+        BBActor {},
+        BBArgs {
+            database: db.clone(),
+        },
+    )
+    .instrument(info_span!("spawn::language"))
+    .await
+    .whatever_context("Spawning bb actor")?;
+    bb_actor
+        .send_message(BBMsg::Backfill(None))
+        .whatever_context("Starting description backfill")?;
 
     let (extraction_actor, _) = Actor::spawn(
         Some("/ml_extractor".to_string()),
         ExtractionActor,
-        ExtractionArgs {},
+        ExtractionArgs {
+            prompt_mode: config.extraction.prompt_mode,
+            batching: Batching {
+                size: config.extraction.batch_size,
+                max_wait: Duration::from_millis(config.extraction.batch_wait_ms),
+            },
+        },
     )
     .instrument(info_span!("spawn::extraction"))
     .await
@@ -54,12 +98,19 @@ pub async fn spawn(config: &Config, db: &Surreal<Db>) -> Result<(), Whatever> {
         PropertiesActor,
         PropertiesArgs {
             database: db.clone(),
+            thresholds: config.moderation.clone(),
         },
     )
     .instrument(info_span!("spawn::properties"))
     .await
     .whatever_context("Spawning properties actor")?;
 
+    let prompt_version = config
+        .extraction
+        .prompt_mode
+        .version()
+        .await
+        .whatever_context("Reading prompts")?;
     let (ml_queue_actor, _) = Actor::spawn(
         Some("/ml_queue".to_string()),
         MLQueueActor,
@@ -67,11 +118,39 @@ pub async fn spawn(config: &Config, db: &Surreal<Db>) -> Result<(), Whatever> {
             database: db.clone(),
             extractor: extraction_actor,
             property_actor,
+            vocabulary_limit: (config.extraction.prompt_mode == PromptMode::Closed)
+                .then_some(config.extraction.vocabulary_limit),
+            status: config.extraction.status,
+            accept_confidence: config.extraction.accept_confidence,
+            concurrency: config.extraction.batch_size,
+            current: Provenance {
+                model: MODEL_ID.to_owned(),
+                prompt_version,
+                confidence: None,
+            },
         },
     )
     .instrument(info_span!("spawn::ml_queue"))
     .await
     .whatever_context("Spawning ML queue actor")?;
+    let embedding_actor = if config.embeddings {
+        let (embedding_actor, _) = Actor::spawn(
+            Some("/embedding".to_string()),
+            EmbeddingActor,
+            EmbeddingArgs {
+                database: db.clone(),
+            },
+        )
+        .instrument(info_span!("spawn::embedding"))
+        .await
+        .whatever_context("Spawning embedding actor")?;
+        embedding_actor
+            .send_message(EmbeddingMsg::Backfill(None))
+            .whatever_context("Starting embedding backfill")?;
+        Some(embedding_actor)
+    } else {
+        None
+    };
     let (item_update_actor, _) = Actor::spawn(
         Some("/item_updater".to_string()),
         ItemUpdateActor {},
@@ -80,6 +159,7 @@ pub async fn spawn(config: &Config, db: &Surreal<Db>) -> Result<(), Whatever> {
             bb_actor,
             database: db.clone(),
             ml_queue: config.ml_extraction.then_some(ml_queue_actor),
+            embedding_queue: embedding_actor,
         },
     )
     .instrument(info_span!("spawn::item_update"))
@@ -93,6 +173,7 @@ pub async fn spawn(config: &Config, db: &Surreal<Db>) -> Result<(), Whatever> {
             client: reqwest_client.clone(),
             base_url: config.base_url.clone(),
             biscuit: config.biscuit.clone(),
+            identity: config.identity.clone(),
         },
     )
     .instrument(info_span!("spawn::auth"))
@@ -1,18 +1,71 @@
-use std::{collections::HashMap, str::FromStr, sync::Arc};
+use std::{path::PathBuf, str::FromStr, sync::Arc};
 
-use biscuit_auth::PrivateKey;
-use serde::{Deserialize, Deserializer};
+use biscuit_auth::{KeyPair, PrivateKey, PublicKey, RootKeyProvider, error};
+use chrono::{DateTime, Utc};
+use classification::actor::PromptMode;
+use serde::{Deserialize, Deserializer, de::Error};
 use veil::Redact;
 
+use crate::{db::model::Status, domain::properties::Thresholds};
+
+const STEAM_DISCOVERY: &str = "https://steamcommunity.com/openid/";
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub steam: Steam,
     pub database: Database,
     pub updater: bool,
     pub ml_extraction: bool,
+    #[serde(default)]
+    pub extraction: Extraction,
+    /// Embed items for similarity and semantic search
+    #[serde(default)]
+    pub embeddings: bool,
+    /// Languages to detect in descriptions by name, i.e. `["English",
+    /// "German"]`, or `["all"]` for every supported language. English,
+    /// Russian, Chinese, Japanese and Korean when empty
+    #[serde(default)]
+    pub languages: Vec<String>,
     pub force_update: bool,
     pub base_url: Arc<String>,
     pub biscuit: Arc<BiscuitConfig>,
+    /// Where users log in, Steam unless configured otherwise
+    #[serde(default)]
+    pub identity: IdentityConfig,
+    /// When votes accept or reject properties without a moderator
+    #[serde(default)]
+    pub moderation: Thresholds,
+}
+
+/// The identity provider users log in through, i.e.
+/// `identity = { provider = "dev", user = "76561197960287930" }`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum IdentityConfig {
+    /// Steam's OpenID 2.0 endpoint, or a local stand-in for it found at
+    /// `discovery_url`
+    Steam {
+        #[serde(default = "steam_discovery")]
+        discovery_url: String,
+    },
+    /// Logs in as any user, only for tests and offline development
+    Dev {
+        /// Who logging in logs in as, unless `dev.user` is given
+        #[serde(default)]
+        user: String,
+    },
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        Self::Steam {
+            discovery_url: steam_discovery(),
+        }
+    }
+}
+
+fn steam_discovery() -> String {
+    STEAM_DISCOVERY.to_owned()
 }
 #[derive(Deserialize, Redact)]
 pub struct Steam {
@@ -20,6 +73,39 @@ pub struct Steam {
     pub api_token: Arc<String>,
     pub appid: u32,
 }
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Extraction {
+    /// Which prompt(s) to run, see `PromptMode`
+    pub prompt_mode: PromptMode,
+    /// How many of the most used accepted values per class are offered to the
+    /// model in `PromptMode::Closed`
+    pub vocabulary_limit: usize,
+    /// The status given to extracted properties; -1 rejected, 0 pending (for
+    /// review) or 1 accepted
+    pub status: Status,
+    /// Extracted properties the model is at least this confident in (0 to 1)
+    /// are accepted regardless of `status`
+    pub accept_confidence: Option<f32>,
+    /// How many items are run through the model together, larger batches
+    /// make better use of a CPU when backfilling
+    pub batch_size: usize,
+    /// Milliseconds an item waits for the rest of its batch
+    pub batch_wait_ms: u64,
+}
+
+impl Default for Extraction {
+    fn default() -> Self {
+        Self {
+            prompt_mode: PromptMode::default(),
+            vocabulary_limit: 100,
+            status: Status::Pending,
+            accept_confidence: None,
+            batch_size: 1,
+            batch_wait_ms: 50,
+        }
+    }
+}
 #[derive(Deserialize, Redact)]
 pub struct Database {
     pub user: String,
@@ -27,30 +113,138 @@ pub struct Database {
     pub password: String,
 }
 
+/// Keys for signing and verifying biscuits. New biscuits are signed with the
+/// current key and carry its ID, biscuits signed with a previous key keep
+/// working until that key expires, so rotating doesn't log everyone out.
 #[derive(Redact)]
 #[redact(all)]
 pub struct BiscuitConfig {
     pub private_key: PrivateKey,
-    // pub lifetime: Duration,
+    /// Embedded in new biscuits as the root key ID
+    pub key_id: u32,
+    pub previous_keys: Vec<PreviousKey>,
+}
+
+/// A retired signing key, only used for verification
+#[derive(Debug, Clone)]
+pub struct PreviousKey {
+    pub id: u32,
+    pub public_key: PublicKey,
+    /// When biscuits signed with this key stop being accepted, for sessions
+    /// this should be at least the session lifetime after rotating
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl BiscuitConfig {
+    pub fn keypair(&self) -> KeyPair {
+        KeyPair::from(&self.private_key)
+    }
+
+    /// Picks the public key for a biscuit's root key ID. Biscuits minted
+    /// before rotation was supported have no ID, they're treated as key 0.
+    fn public_key(&self, key_id: Option<u32>) -> Result<PublicKey, error::Format> {
+        let key_id = key_id.unwrap_or_default();
+        if key_id == self.key_id {
+            return Ok(self.private_key.public());
+        }
+        self.previous_keys
+            .iter()
+            .find(|key| key.id == key_id)
+            .filter(|key| key.expires.is_none_or(|expires| expires > Utc::now()))
+            .map(|key| key.public_key)
+            .ok_or(error::Format::UnknownPublicKey)
+    }
+}
+
+impl RootKeyProvider for &BiscuitConfig {
+    fn choose(&self, key_id: Option<u32>) -> Result<PublicKey, error::Format> {
+        self.public_key(key_id)
+    }
+}
+
+/// A key given inline or read from a file, i.e. a mounted secret
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeySource {
+    File { file: PathBuf },
+    Inline(String),
+}
+
+impl KeySource {
+    fn read<E: serde::de::Error>(&self, name: &str) -> Result<String, E> {
+        match self {
+            KeySource::Inline(key) => Ok(key.trim().to_owned()),
+            KeySource::File { file } => std::fs::read_to_string(file)
+                .map(|key| key.trim().to_owned())
+                .map_err(|e| E::custom(format!("reading {name} from {}: {e}", file.display()))),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawBiscuitConfig {
+    private_key: KeySource,
+    #[serde(default)]
+    key_id: u32,
+    #[serde(default)]
+    previous_keys: Vec<RawPreviousKey>,
+}
+
+#[derive(Deserialize)]
+struct RawPreviousKey {
+    id: u32,
+    public_key: KeySource,
+    expires: Option<DateTime<Utc>>,
 }
 
 impl<'de> serde::Deserialize<'de> for BiscuitConfig {
     fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
-        let mut map: HashMap<String, String> = HashMap::deserialize(d)?;
+        let raw = RawBiscuitConfig::deserialize(d)?;
+        let private_key = PrivateKey::from_str(&raw.private_key.read("biscuit.private_key")?)
+            .map_err(|e| {
+                D::Error::custom(format!(
+                    "biscuit.private_key is malformed, expected i.e. `ed25519-private/<hex>`: {e}"
+                ))
+            })?;
+        let previous_keys = raw
+            .previous_keys
+            .into_iter()
+            .map(|key| {
+                if key.id == raw.key_id {
+                    return Err(D::Error::custom(format!(
+                        "biscuit.previous_keys reuses the current key ID {}",
+                        key.id
+                    )));
+                }
+                let public_key = PublicKey::from_str(&key.public_key.read("a previous key")?)
+                    .map_err(|e| {
+                        D::Error::custom(format!(
+                            "biscuit.previous_keys key {} is malformed, expected i.e. \
+                             `ed25519/<hex>`: {e}",
+                            key.id
+                        ))
+                    })?;
+                Ok(PreviousKey {
+                    id: key.id,
+                    public_key,
+                    expires: key.expires,
+                })
+            })
+            .collect::<Result<_, _>>()?;
         Ok(Self {
-            private_key: map
-                .remove("private_key")
-                .as_deref()
-                .map(FromStr::from_str)
-                .unwrap()
-                .unwrap(),
+            private_key,
+            key_id: raw.key_id,
+            previous_keys,
         })
     }
 }
 
 #[cfg(test)]
 mod test {
-    use biscuit_auth::KeyPair;
+    use biscuit_auth::{Biscuit, KeyPair, macros::biscuit};
+    use chrono::{TimeDelta, Utc};
+
+    use super::{BiscuitConfig, PreviousKey};
 
     #[test]
     fn test_keygen() {
@@ -58,4 +252,57 @@ mod test {
         println!("{}", pair.public().print());
         println!("{}", pair.private().to_prefixed_string());
     }
+
+    #[test]
+    fn test_key_rotation() {
+        let old = KeyPair::new();
+        let expired = KeyPair::new();
+        let config = BiscuitConfig {
+            private_key: KeyPair::new().private(),
+            key_id: 2,
+            previous_keys: vec![
+                PreviousKey {
+                    id: 1,
+                    public_key: old.public(),
+                    expires: Some(Utc::now() + TimeDelta::days(1)),
+                },
+                PreviousKey {
+                    id: 0,
+                    public_key: expired.public(),
+                    expires: Some(Utc::now() - TimeDelta::days(1)),
+                },
+            ],
+        };
+        let mint = |keypair: &KeyPair, id: Option<u32>| {
+            let builder = biscuit!("user(\"1\");");
+            match id {
+                Some(id) => builder.root_key_id(id),
+                None => builder,
+            }
+            .build(keypair)
+            .unwrap()
+            .to_base64()
+            .unwrap()
+        };
+        let verify = |token: String| Biscuit::from_base64(token, &config).is_ok();
+
+        assert!(verify(mint(&config.keypair(), Some(2))));
+        assert!(verify(mint(&old, Some(1))));
+        // Unversioned biscuits are key 0, which has expired
+        assert!(!verify(mint(&expired, None)));
+        // Signed with a key other than the ID claims
+        assert!(!verify(mint(&old, Some(2))));
+    }
+
+    #[test]
+    fn test_malformed_key() {
+        let error = serde_json::from_str::<BiscuitConfig>(r#"{"private_key": "not a key"}"#)
+            .err()
+            .map(|e| e.to_string())
+            .unwrap_or_default();
+        assert!(
+            error.contains("biscuit.private_key is malformed"),
+            "{error}"
+        );
+    }
 }
@@ -1,15 +1,20 @@
+use tracing::debug;
+
 use crate::{
-    db::model::{Source, Status},
-    domain::properties::{NewProperty, PropertiesError, PropertiesPort, VoteData},
+    db::model::{Property, Source, Status},
+    domain::properties::{
+        NewProperty, PropertiesError, PropertiesPort, Provenance, Tally, Thresholds, VoteData,
+    },
 };
 
 pub struct PropertiesService<R: PropertiesPort> {
     repo: R,
+    thresholds: Thresholds,
 }
 
 impl<R: PropertiesPort> PropertiesService<R> {
-    pub fn new(repo: R) -> Self {
-        Self { repo }
+    pub fn new(repo: R, thresholds: Thresholds) -> Self {
+        Self { repo, thresholds }
     }
 
     pub async fn new_property(
@@ -17,6 +22,7 @@ impl<R: PropertiesPort> PropertiesService<R> {
         mut new_property: NewProperty,
         source: Source<String>,
         status: Status,
+        provenance: Option<Provenance>,
     ) -> Result<(), PropertiesError> {
         new_property.value = new_property.value.to_ascii_lowercase();
 
@@ -43,18 +49,48 @@ impl<R: PropertiesPort> PropertiesService<R> {
         }
 
         self.repo
-            .create_or_link_property(new_property, source, status)
+            .create_or_link_property(new_property, source, status, provenance)
             .await
     }
 
+    /// Casts a vote weighted by the voter's reputation, then lets the votes
+    /// decide the property's status if they crossed a threshold.
     pub async fn vote(&self, vote: VoteData, userid: String) -> Result<(), PropertiesError> {
         if vote.score != 1 && vote.score != -1 {
             return Err(PropertiesError::InvalidVoteScore);
         }
-        self.repo.vote(vote, userid).await
+        let weight = self.repo.reputation(userid.clone()).await?.weight();
+        let (item, property) = target(&vote);
+        let tally = self.repo.vote(vote, userid, weight).await?;
+        self.moderate(item, property, tally).await
     }
 
     pub async fn remove_vote(&self, vote: VoteData, userid: String) -> Result<(), PropertiesError> {
-        self.repo.remove_vote(vote, userid).await
+        let (item, property) = target(&vote);
+        let tally = self.repo.remove_vote(vote, userid).await?;
+        self.moderate(item, property, tally).await
     }
+
+    async fn moderate(
+        &self,
+        item: String,
+        property: Property,
+        tally: Option<Tally>,
+    ) -> Result<(), PropertiesError> {
+        let Some(status) = tally.and_then(|tally| self.thresholds.decide(&tally)) else {
+            return Ok(());
+        };
+        debug!(%item, %property, ?status, "votes decided property");
+        self.repo.decide_by_votes(item, property, status).await
+    }
+}
+
+fn target(vote: &VoteData) -> (String, Property) {
+    (
+        vote.item.clone(),
+        Property {
+            class: vote.class.clone(),
+            value: vote.value.clone(),
+        },
+    )
 }
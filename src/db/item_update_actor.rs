@@ -12,12 +12,16 @@ use surrealdb::{
 use tracing::{debug, error};
 
 use crate::{
-    db::model::{Dependencies, WorkshopItem},
+    db::model::{
+        CompanionKind, Dependencies, MentionedCompanion, Source, WorkshopItem, into_string,
+    },
     processing::{
         bb_actor::BBMsg,
+        embedding_actor::EmbeddingMsg,
         join_process_actor::{JoinProcessActor, JoinProcessArgs, JoinProcessMsg},
         language_actor::{DetectedLanguage, LanguageMsg},
         ml_queue_actor::MLQueueMsg,
+        translations::{ItemTitle, link_dependants, link_translation},
     },
     steam::model::{Child, IPublishedResponse, IPublishedStruct, SteamRoot},
 };
@@ -29,19 +33,29 @@ pub struct ItemUpdateArgs {
     pub bb_actor: ActorRef<BBMsg>,
     pub database: Surreal<Db>,
     pub ml_queue: Option<ActorRef<MLQueueMsg>>, // optional ML queue actor
+    pub embedding_queue: Option<ActorRef<EmbeddingMsg>>,
 }
 pub struct ItemUpdateState {
     language_actor: ActorRef<LanguageMsg>,
     bb_actor: ActorRef<BBMsg>,
     database: Surreal<Db>,
     ml_queue: Option<ActorRef<MLQueueMsg>>,
+    embedding_queue: Option<ActorRef<EmbeddingMsg>>,
+}
+
+/// Items an item relies on
+pub struct Relations {
+    /// Required items declared on Steam
+    pub children: Vec<Child>,
+    /// IDs of the workshop items linked from the description
+    pub mentioned: Vec<String>,
 }
 
 pub enum ItemUpdateMsg {
     DeserializeRawFiles(SteamRoot<IPublishedResponse>),
     MainlineProcessing(IPublishedStruct),
-    Upsert((WorkshopItem<RecordId>, Vec<Child>)),
-    MaybeQueueMl((WorkshopItem<RecordId>, Vec<Child>)),
+    Upsert((WorkshopItem<RecordId>, Relations)),
+    MaybeQueueMl((WorkshopItem<RecordId>, Relations)),
 }
 #[async_trait]
 impl Actor for ItemUpdateActor {
@@ -59,6 +73,7 @@ impl Actor for ItemUpdateActor {
             language_actor: args.language_actor,
             bb_actor: args.bb_actor,
             ml_queue: args.ml_queue,
+            embedding_queue: args.embedding_queue,
         })
     }
 
@@ -95,24 +110,36 @@ impl Actor for ItemUpdateActor {
 
                 join_process_actor.send_message(JoinProcessMsg::Process(data))?;
             }
-            ItemUpdateMsg::MaybeQueueMl((item, children)) => {
+            ItemUpdateMsg::MaybeQueueMl((item, relations)) => {
                 if let Err(error) =
                     maybe_queue_ml(&state.database, state.ml_queue.as_ref(), &item).await
                 {
                     error!(?error, id = %item.id, "queuing ML work (message)");
                 }
+                if let Err(error) =
+                    maybe_queue_embedding(&state.database, state.embedding_queue.as_ref(), &item)
+                        .await
+                {
+                    error!(?error, id = %item.id, "queuing embedding");
+                }
                 if myself
-                    .send_message(ItemUpdateMsg::Upsert((item, children)))
+                    .send_message(ItemUpdateMsg::Upsert((item, relations)))
                     .is_err()
                 {
                     error!("forwarding work to upsert");
                 }
             }
-            ItemUpdateMsg::Upsert((item, children)) => {
-                let title = item.title.clone();
-                let item_id = item.id.clone();
-                if let Err(error) = insert_data(&state.database, item, children).await {
-                    error!(?error, title, %item_id, "upserting item");
+            ItemUpdateMsg::Upsert((item, relations)) => {
+                if let Err(error) = insert_data(&state.database, item.clone(), relations).await {
+                    error!(?error, item.title, %item.id, "upserting item");
+                } else {
+                    let title = ItemTitle::from(&item);
+                    if let Err(error) = link_translation(&state.database, &title).await {
+                        error!(?error, item.title, %item.id, "linking translation");
+                    }
+                    if let Err(error) = link_dependants(&state.database, &title).await {
+                        error!(?error, item.title, %item.id, "linking translated dependants");
+                    }
                 }
             }
         }
@@ -163,10 +190,44 @@ async fn maybe_queue_ml(
     Ok(())
 }
 
+/// Embed the item when it's new or its title or description changed
+async fn maybe_queue_embedding(
+    db: &Surreal<Db>,
+    embedding_queue: Option<&ActorRef<EmbeddingMsg>>,
+    item: &WorkshopItem<RecordId>,
+) -> crate::Result<(), Whatever> {
+    if let Some(queue) = embedding_queue {
+        let mut resp = db
+            .query("SELECT title, description FROM $id")
+            .bind(("id", item.id.clone()))
+            .await
+            .whatever_context("querying item for embedding check")?;
+        let old_title: Option<String> = resp
+            .take((0, "title"))
+            .whatever_context("taking title for embedding check")?;
+        let old_description: Option<String> = resp
+            .take((0, "description"))
+            .whatever_context("taking description for embedding check")?;
+        if old_title.as_ref() != Some(&item.title)
+            || old_description.as_ref() != Some(&item.description)
+        {
+            let _ = queue.send_message(EmbeddingMsg::Store(
+                item.id.clone(),
+                item.title.clone(),
+                item.plain_description.clone(),
+            ));
+        }
+    }
+    Ok(())
+}
+
 async fn insert_data(
     db: &Surreal<Db>,
     mut item: WorkshopItem<RecordId>,
-    children: Vec<Child>,
+    Relations {
+        children,
+        mentioned,
+    }: Relations,
 ) -> crate::Result<(), Whatever> {
     let tags = std::mem::take(&mut item.tags);
     let id = item.id.clone();
@@ -184,9 +245,9 @@ async fn insert_data(
 
         stmt.into = Some(Value::Table("item_dependencies".into()));
         let data = children
-            .into_iter()
+            .iter()
             .map(|child| {
-                let dep_id = RecordId::from_table_key("workshop_items", child.publishedfileid);
+                let dep_id = RecordId::from_table_key("workshop_items", &child.publishedfileid);
                 to_value(Dependencies {
                     id: RecordId::from_table_key(
                         "item_dependencies",
@@ -203,6 +264,39 @@ async fn insert_data(
         stmt
     };
 
+    // Links that Steam already declares, or back to the item, aren't worth
+    // reviewing. Existing companions keep their status.
+    let insert_companions = {
+        let mut stmt = InsertStatement::default();
+        stmt.relation = true;
+
+        stmt.into = Some(Value::Table("companions".into()));
+        let data = mentioned
+            .into_iter()
+            .filter(|id| {
+                id != &into_string(item.id.key())
+                    && !children.iter().any(|child| &child.publishedfileid == id)
+            })
+            .map(|id| {
+                let companion = RecordId::from_table_key("workshop_items", id);
+                to_value(MentionedCompanion {
+                    id: RecordId::from_table_key(
+                        "companions",
+                        vec![item.id.clone().into(), companion.clone().into()],
+                    ),
+                    this: item.id.clone(),
+                    companion,
+                    source: Source::System,
+                    kind: CompanionKind::Mentioned,
+                })
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        stmt.data = Data::SingleExpression(Value::Array(data.into()));
+        stmt.ignore = true;
+        stmt
+    };
+
     let upsert_item = {
         let mut stmt = UpsertStatement::default();
         stmt.data = Some(Data::ReplaceExpression(to_value(item.clone()).unwrap()));
@@ -215,6 +309,7 @@ async fn insert_data(
         .query(insert_tags)
         .query(upsert_item.to_string()) // Missing impl for into query
         .query(insert_item_deps)
+        .query(insert_companions)
         .query("UPDATE $id SET tags=$tags")
         .bind(("id", id))
         .bind((
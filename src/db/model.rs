@@ -11,7 +11,7 @@ use serde_hack::ValueRefDeserializer;
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use surrealdb::{RecordId, RecordIdKey};
 
-use crate::processing::language_actor::DetectedLanguage;
+use crate::processing::language_actor::{DetectedLanguage, LanguageConfidence};
 #[derive(Serialize, Deserialize, Clone, Debug, ToSchema, Default)]
 pub enum OrderBy {
     Alphabetical,
@@ -52,7 +52,23 @@ pub struct WorkshopItem<ID> {
     pub author: String,
     pub description: String,
     pub id: ID,
+    /// The description without markup, used for detection, search and
+    /// extraction rather than sent to clients
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub plain_description: String,
     pub languages: Vec<DetectedLanguage>,
+    /// Every language detected in the description, most prevalent first
+    #[serde(default)]
+    pub language_confidence: Vec<LanguageConfidence>,
+    /// Who the item is written for
+    #[serde(default)]
+    pub primary_language: Option<DetectedLanguage>,
+    /// Other languages the description has sections in
+    #[serde(default)]
+    pub also_contains: Vec<DetectedLanguage>,
+    /// The title's language, when it could be told
+    #[serde(default)]
+    pub title_language: Option<DetectedLanguage>,
     pub last_updated: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub preview_url: Option<String>,
@@ -70,6 +86,14 @@ pub struct FullWorkshopItem {
     pub description: String,                 // HTML encoded description from steam
     pub id: String,                          // The item's ID
     pub languages: Vec<DetectedLanguage>,    // All languages found in the items description
+    #[serde(default)]
+    pub language_confidence: Vec<LanguageConfidence>, // How much of the description each is in
+    #[serde(default)]
+    pub primary_language: Option<DetectedLanguage>, // Who the item is written for
+    #[serde(default)]
+    pub also_contains: Vec<DetectedLanguage>, // Other languages the description has sections in
+    #[serde(default)]
+    pub title_language: Option<DetectedLanguage>, // The language of the title, if it could be told
     pub last_updated: u64,                   // Timestamp in milliseconds
     #[serde(skip_serializing_if = "Option::is_none")]
     pub preview_url: Option<String>, // The URL to the banner image
@@ -77,6 +101,78 @@ pub struct FullWorkshopItem {
     pub tags: Vec<Tag>,                      // The list of tags found
     pub score: f32,                          // The "quality" score assigned by steam
     pub properties: Vec<WorkshopItemProperties<String, Property>>, // Approved or owned properties
+    #[serde(default)]
+    pub translations: Vec<Translation>, // Items translating this one
+    #[serde(default)]
+    pub companions: Vec<CompanionItem>, // Soft dependencies, besides those declared on Steam
+}
+impl From<WorkshopItem<RecordId>> for WorkshopItem<String> {
+    /// Drops the plain description, which is never sent to clients
+    fn from(item: WorkshopItem<RecordId>) -> Self {
+        WorkshopItem {
+            appid: item.appid,
+            author: item.author,
+            description: item.description,
+            id: into_string(item.id.key()),
+            plain_description: String::new(),
+            languages: item.languages,
+            language_confidence: item.language_confidence,
+            primary_language: item.primary_language,
+            also_contains: item.also_contains,
+            title_language: item.title_language,
+            last_updated: item.last_updated,
+            preview_url: item.preview_url,
+            title: item.title,
+            tags: item.tags,
+            score: item.score,
+            properties: item.properties,
+        }
+    }
+}
+impl From<WorkshopItem<RecordId>> for FullWorkshopItem {
+    /// An item without any of its relations filled in
+    fn from(item: WorkshopItem<RecordId>) -> Self {
+        FullWorkshopItem {
+            appid: item.appid,
+            author: item.author,
+            dependants: vec![],
+            dependencies: vec![],
+            description: item.description,
+            id: into_string(item.id.key()),
+            languages: item.languages,
+            language_confidence: item.language_confidence,
+            primary_language: item.primary_language,
+            also_contains: item.also_contains,
+            title_language: item.title_language,
+            last_updated: item.last_updated,
+            preview_url: item.preview_url,
+            title: item.title,
+            tags: item.tags,
+            score: item.score,
+            properties: item.properties,
+            translations: vec![],
+            companions: vec![],
+        }
+    }
+}
+/// An item related to another outside of Steam's required items
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct CompanionItem {
+    /// The companion's ID
+    pub id: String,
+    /// Missing until the companion has been fetched
+    pub title: Option<String>,
+    pub kind: CompanionKind,
+    pub status: Status,
+}
+/// An item detected as a translation of another
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct Translation {
+    /// The translation's ID
+    pub id: String,
+    pub title: String,
+    /// The language it translates into, when it could be told
+    pub language: Option<DetectedLanguage>,
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Dependencies {
@@ -117,14 +213,66 @@ pub struct App {
 pub struct User<T> {
     /// The steam account ID
     pub id: T,
-    /// Privileged access
+    /// Privileged access, the same as holding the admin role everywhere
     pub admin: bool,
+    #[serde(default)]
+    pub roles: Vec<RoleGrant>,
     pub banned: bool,
+    /// Why the user was banned, shown to them when logging in
+    #[serde(default)]
+    pub ban_reason: Option<String>,
+    /// When a temporary ban lifts, bans without one are permanent
+    #[serde(default)]
+    pub banned_until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub privacy: Privacy,
     /// UTC timestamp of when the user last logged in
     // Surrealdb bug: https://github.com/surrealdb/surrealdb/issues/3550
     #[serde(serialize_with = "serialize_chrono_as_sql_datetime")]
     pub last_logged_in: DateTime<Utc>,
 }
+
+/// What a user's public profile shows, the user and admins always see
+/// everything
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, ToSchema, PartialEq, Eq)]
+#[serde(default)]
+pub struct Privacy {
+    /// Submitted properties and how many were accepted
+    pub submissions: bool,
+    pub votes: bool,
+    pub last_logged_in: bool,
+}
+/// Roles below admin let trusted users moderate without managing users
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Reviews companions
+    Curator,
+    /// Reviews companions and properties
+    Moderator,
+    /// Everything, including managing users
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Curator => "curator",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// A role held by a user, either everywhere or for a single app's items
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema, PartialEq, Eq)]
+pub struct RoleGrant {
+    pub role: Role,
+    /// The app the role is limited to
+    #[serde(default)]
+    pub app: Option<i64>,
+}
+
 pub fn serialize_chrono_as_sql_datetime<S>(
     x: &chrono::DateTime<Utc>,
     s: S,
@@ -158,7 +306,22 @@ pub struct PropertyExt<SOURCE> {
     pub upvote_count: i64,
     /// The total upvotes
     pub vote_count: u64,
+    /// The score with each vote weighted by its voter's reputation
+    #[serde(default)]
+    pub weighted_score: f64,
+    /// Who decided the status, unset while pending
+    #[serde(default)]
+    pub decided_by: Option<Decision>,
     pub source: Source<SOURCE>,
+    /// The model that suggested this, for `Source::System`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// The prompt version the model was given, for `Source::System`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_version: Option<String>,
+    /// How sure the model was of this property, from 0 to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
 }
 #[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct WorkshopItemProperties<CHILD, PROP> {
@@ -186,6 +349,29 @@ pub struct Companion<R, S> {
     pub upvote_count: u64,
     pub vote_count: u64,
     pub source: Source<S>,
+    pub kind: CompanionKind,
+}
+
+/// How a companion came to be suggested
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, ToSchema, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompanionKind {
+    /// Linked from the item's description, as opposed to declared on Steam
+    Mentioned,
+    /// Put forward by a user
+    Suggested,
+}
+
+/// A companion found in an item's description, pending review
+#[derive(Serialize, Clone, Debug)]
+pub struct MentionedCompanion {
+    pub id: RecordId,
+    #[serde(rename = "in")]
+    pub this: RecordId,
+    #[serde(rename = "out")]
+    pub companion: RecordId,
+    pub source: Source<RecordId>,
+    pub kind: CompanionKind,
 }
 
 /// A voting record
@@ -289,6 +475,16 @@ pub enum Status {
     Accepted = 1,
 }
 
+/// Who decided a property's status
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    /// A moderator, votes never override them
+    Moderator,
+    /// Votes crossing the auto-moderation thresholds
+    Votes,
+}
+
 #[derive(Serialize, Deserialize, PartialOrd, PartialEq, Eq, Debug)]
 #[serde(transparent)]
 struct Id(RecordId);
@@ -9,7 +9,7 @@ use crate::{
         model::{Source, Status},
         properties_repository::PropertiesSilo,
     },
-    domain::properties::{NewProperty, PropertiesError, VoteData},
+    domain::properties::{NewProperty, PropertiesError, Provenance, Thresholds, VoteData},
 };
 
 pub static PROPERTIES_ACTOR: OnceLock<ActorRef<PropertiesMsg>> = OnceLock::new();
@@ -21,6 +21,8 @@ pub struct PropertiesActor;
 /// Actor initialization arguments.
 pub struct PropertiesArgs {
     pub database: Surreal<Db>,
+    /// When votes decide a property's status
+    pub thresholds: Thresholds,
 }
 
 /// Internal state for the actor. Holds the service instance.
@@ -34,6 +36,7 @@ pub enum PropertiesMsg {
         NewProperty,
         Source<String>,
         Status,
+        Option<Provenance>,
         RpcReplyPort<Result<(), PropertiesError>>,
     ),
     Vote(VoteData, String, RpcReplyPort<Result<(), PropertiesError>>),
@@ -53,7 +56,7 @@ impl Actor for PropertiesActor {
     ) -> Result<Self::State, ActorProcessingErr> {
         PROPERTIES_ACTOR.get_or_init(|| myself);
         Ok(PropertiesState {
-            service: PropertiesService::new(PropertiesSilo::new(args.database)),
+            service: PropertiesService::new(PropertiesSilo::new(args.database), args.thresholds),
         })
     }
 
@@ -64,8 +67,11 @@ impl Actor for PropertiesActor {
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
-            PropertiesMsg::NewProperty(prop, source, status, reply) => {
-                let res = state.service.new_property(prop, source, status).await;
+            PropertiesMsg::NewProperty(prop, source, status, provenance, reply) => {
+                let res = state
+                    .service
+                    .new_property(prop, source, status, provenance)
+                    .await;
                 let _ = reply.send(res);
             }
             PropertiesMsg::Vote(vote, userid, reply) => {
@@ -8,7 +8,9 @@ use crate::{
         ItemID, UserID,
         model::{Property, Source, Status, WorkshopItemProperties},
     },
-    domain::properties::{NewProperty, PropertiesError, PropertiesPort, VoteData},
+    domain::properties::{
+        NewProperty, PropertiesError, PropertiesPort, Provenance, Reputation, Tally, VoteData,
+    },
 };
 
 pub struct PropertiesSilo {
@@ -27,6 +29,7 @@ impl PropertiesPort for PropertiesSilo {
         new_property: NewProperty,
         source: Source<String>,
         status: Status,
+        provenance: Option<Provenance>,
     ) -> Result<(), PropertiesError> {
         let workshop_id = ItemID::from(new_property.workshop_item).into_recordid();
 
@@ -89,7 +92,8 @@ impl PropertiesPort for PropertiesSilo {
             .bind(("value", test_prop.value))
             .query(
                 "RELATE $workshop_id->workshop_item_properties->properties:{class: $class, \
-                 value:$value} SET note=$note, source=$source, status=$status;",
+                 value:$value} SET note=$note, source=$source, status=$status, model=$model, \
+                 prompt_version=$prompt_version, confidence=$confidence;",
             )
             .bind(("workshop_id", workshop_id))
             .bind(("note", new_property.note))
@@ -101,6 +105,12 @@ impl PropertiesPort for PropertiesSilo {
                 },
             ))
             .bind(("status", status))
+            .bind(("model", provenance.as_ref().map(|p| p.model.clone())))
+            .bind((
+                "prompt_version",
+                provenance.as_ref().map(|p| p.prompt_version.clone()),
+            ))
+            .bind(("confidence", provenance.and_then(|p| p.confidence)))
             .await
             .map(surrealdb::Response::check)
         {
@@ -119,7 +129,12 @@ impl PropertiesPort for PropertiesSilo {
         }
     }
 
-    async fn vote(&self, vote_data: VoteData, userid: String) -> Result<(), PropertiesError> {
+    async fn vote(
+        &self,
+        vote_data: VoteData,
+        userid: String,
+        weight: f64,
+    ) -> Result<Option<Tally>, PropertiesError> {
         let user = UserID::from(userid);
         let query = self
             .db
@@ -127,22 +142,17 @@ impl PropertiesPort for PropertiesSilo {
             .query(r#"IF !record::exists($link){THROW "FAIL LINK";}"#)
             .query(r#"IF !record::exists($item){THROW "FAIL ITEM";}"#)
             .query(
-                "LET $changed = INSERT INTO votes (id, score, when) VALUES ({link: $link, user: \
-                 $user, item: $item}, $score, time::now()) ON DUPLICATE KEY UPDATE when=time::now(), \
-                 score=$score RETURN BEFORE;",
+                "LET $before = INSERT INTO votes (id, score, weight, when) VALUES ({link: $link, \
+                 user: $user, item: $item}, $score, $weight, time::now()) ON DUPLICATE KEY UPDATE \
+                 when=time::now(), score=$score, weight=$weight RETURN BEFORE;",
             )
+            .query("LET $old = $before[0];")
+            // Take back what the previous vote counted for, if there was one
             .query(
-                r"
-            LET $changed_score = $changed.score[0];
-            IF $changed_score && $changed_score != $score{
-                LET $vote_diff = ($changed_score * -1);
-                UPDATE ONLY workshop_item_properties SET vote_count += $vote_count, upvote_count += $vote_diff WHERE in = $item AND out = $link;
-                RETURN $vote_diff
-            } else if !$changed_score{
-                UPDATE ONLY workshop_item_properties SET vote_count += 1, upvote_count += $score WHERE in = $item AND out = $link;
-            } else {
-                return {changed_score: $changed_score, score: $score};
-            };",
+                "UPDATE workshop_item_properties SET vote_count += IF $old { 0 } ELSE { 1 }, \
+                 upvote_count += $score - ($old.score ?? 0), weighted_score += $score * $weight - \
+                 ($old.score ?? 0) * ($old.weight ?? 0) WHERE in = $item AND out = $link RETURN \
+                 status, decided_by, weighted_score, vote_count;",
             )
             .bind(("class", vote_data.class))
             .bind(("value", vote_data.value))
@@ -151,10 +161,17 @@ impl PropertiesPort for PropertiesSilo {
                 "item",
                 RecordId::from_table_key("workshop_items", vote_data.item),
             ))
-            .bind(("score", vote_data.score));
+            .bind(("score", vote_data.score))
+            .bind(("weight", weight));
 
         match query.await.map(surrealdb::Response::check) {
-            Ok(Ok(_)) => Ok(()),
+            Ok(Ok(mut response)) => {
+                let last = response.num_statements() - 1;
+                response.take(last).map_err(|e| {
+                    error!(?e, "taking vote tally");
+                    PropertiesError::Internal
+                })
+            }
             Ok(Err(e)) => {
                 debug!(?e, "bad vote from user");
                 Err(PropertiesError::BadRequest {
@@ -172,7 +189,7 @@ impl PropertiesPort for PropertiesSilo {
         &self,
         vote_data: VoteData,
         userid: String,
-    ) -> Result<(), PropertiesError> {
+    ) -> Result<Option<Tally>, PropertiesError> {
         let user = UserID::from(userid);
         let result = self
             .db
@@ -183,9 +200,10 @@ impl PropertiesPort for PropertiesSilo {
                  BEFORE;",
             )
             .query(
-                "if $before.score{RETURN UPDATE ONLY workshop_item_properties SET \
-                 vote_count=math::max([vote_count-1, 0]), upvote_count-=$before.score WHERE \
-                 in=$item AND out=$link RETURN diff};",
+                "UPDATE workshop_item_properties SET vote_count=math::max([vote_count-1, 0]), \
+                 upvote_count-=$before.score, weighted_score-=$before.score * $before.weight \
+                 WHERE in=$item AND out=$link AND $before != NONE RETURN status, decided_by, \
+                 weighted_score, vote_count;",
             )
             .query("COMMIT TRANSACTION;")
             .bind(("class", vote_data.class))
@@ -198,7 +216,13 @@ impl PropertiesPort for PropertiesSilo {
             .await;
 
         match result.map(surrealdb::Response::check) {
-            Ok(Ok(_)) => Ok(()),
+            Ok(Ok(mut response)) => {
+                let last = response.num_statements() - 1;
+                response.take(last).map_err(|e| {
+                    error!(?e, "taking vote tally");
+                    PropertiesError::Internal
+                })
+            }
             Ok(Err(e)) => {
                 debug!(?e, "bad vote removal from user");
                 Err(PropertiesError::BadRequest {
@@ -211,4 +235,43 @@ impl PropertiesPort for PropertiesSilo {
             }
         }
     }
+
+    async fn reputation(&self, userid: String) -> Result<Reputation, PropertiesError> {
+        let reputation: Option<Reputation> = self
+            .db
+            .query("SELECT VALUE reputation FROM ONLY $user")
+            .bind(("user", UserID::from(userid).into_recordid()))
+            .await
+            .and_then(|mut response| response.take(0))
+            .map_err(|e| {
+                error!(?e, "querying reputation");
+                PropertiesError::Internal
+            })?;
+        Ok(reputation.unwrap_or_default())
+    }
+
+    async fn decide_by_votes(
+        &self,
+        item: String,
+        property: Property,
+        status: Status,
+    ) -> Result<(), PropertiesError> {
+        self.db
+            .query(
+                "UPDATE workshop_item_properties SET status = $status, decided_by = IF $status = \
+                 0 { NONE } ELSE { 'votes' } WHERE in = $item AND out = properties:{class: \
+                 $class, value: $value} AND decided_by != 'moderator'",
+            )
+            .bind(("status", status))
+            .bind(("item", ItemID::from(item).into_recordid()))
+            .bind(("class", property.class))
+            .bind(("value", property.value))
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(|e| {
+                error!(?e, "deciding property by votes");
+                PropertiesError::Internal
+            })?;
+        Ok(())
+    }
 }
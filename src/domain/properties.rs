@@ -2,7 +2,7 @@ use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 
-use crate::db::model::{Class, Source, Status};
+use crate::db::model::{Class, Decision, Property, Source, Status};
 
 #[derive(Debug, Snafu, Clone)]
 #[non_exhaustive]
@@ -27,6 +27,17 @@ pub struct NewProperty {
     pub note: Option<String>,
 }
 
+/// What produced a machine generated property
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The hub ID of the model
+    pub model: String,
+    /// The version of the prompt(s) the model was given
+    pub prompt_version: String,
+    /// How sure the model was of the value, from 0 to 1
+    pub confidence: Option<f32>,
+}
+
 /// Data required to cast or update a vote on a property
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct VoteData {
@@ -36,6 +47,87 @@ pub struct VoteData {
     pub score: i32,
 }
 
+/// How often a user's submissions and votes agreed with moderators' decisions
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+pub struct Reputation {
+    pub agreed: u32,
+    pub disagreed: u32,
+}
+
+impl Reputation {
+    /// How much the user's votes count for, from 0 to 2. Users without a track
+    /// record count once, each decision they agreed with moves them towards
+    /// counting twice and each they didn't towards not counting.
+    pub fn weight(self) -> f64 {
+        2.0 * f64::from(self.agreed + 1) / f64::from(self.agreed + self.disagreed + 2)
+    }
+}
+
+/// A property's votes after they changed
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tally {
+    pub status: Status,
+    pub decided_by: Option<Decision>,
+    pub weighted_score: f64,
+    pub vote_count: u64,
+}
+
+/// When votes decide a property's status, i.e. `moderation = { accept_score =
+/// 5.0, reject_score = -5.0, min_votes = 5 }`. Leaving a score out stops votes
+/// deciding that way, so by default only moderators decide.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Thresholds {
+    /// The weighted score at which a property is accepted
+    pub accept_score: Option<f64>,
+    /// The weighted score at which a property is rejected
+    pub reject_score: Option<f64>,
+    /// How many votes a property needs before votes decide anything
+    pub min_votes: u64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            accept_score: None,
+            reject_score: None,
+            min_votes: 5,
+        }
+    }
+}
+
+impl Thresholds {
+    /// The status votes move a property to, if it changes. Votes only decide
+    /// properties that are pending or that votes decided before, a property
+    /// that falls back between the thresholds goes back to pending.
+    pub fn decide(&self, tally: &Tally) -> Option<Status> {
+        let decided_by_votes = match tally.decided_by {
+            Some(Decision::Moderator) => false,
+            Some(Decision::Votes) => true,
+            None => tally.status == Status::Pending,
+        };
+        if !decided_by_votes {
+            return None;
+        }
+        let status = if tally.vote_count < self.min_votes {
+            Status::Pending
+        } else if self
+            .accept_score
+            .is_some_and(|score| tally.weighted_score >= score)
+        {
+            Status::Accepted
+        } else if self
+            .reject_score
+            .is_some_and(|score| tally.weighted_score <= score)
+        {
+            Status::Rejected
+        } else {
+            Status::Pending
+        };
+        (status != tally.status).then_some(status)
+    }
+}
+
 /// Port for property-related persistence operations.
 pub trait PropertiesPort: Send + Sync + 'static {
     async fn create_or_link_property(
@@ -43,7 +135,92 @@ pub trait PropertiesPort: Send + Sync + 'static {
         new_prop: NewProperty,
         source: Source<String>,
         status: Status,
+        provenance: Option<Provenance>,
     ) -> Result<(), PropertiesError>;
-    async fn vote(&self, vote: VoteData, userid: String) -> Result<(), PropertiesError>;
-    async fn remove_vote(&self, vote: VoteData, userid: String) -> Result<(), PropertiesError>;
+    /// Casts or changes a vote counting for `weight`, returning the property's
+    /// votes afterwards.
+    async fn vote(
+        &self,
+        vote: VoteData,
+        userid: String,
+        weight: f64,
+    ) -> Result<Option<Tally>, PropertiesError>;
+    async fn remove_vote(
+        &self,
+        vote: VoteData,
+        userid: String,
+    ) -> Result<Option<Tally>, PropertiesError>;
+    async fn reputation(&self, userid: String) -> Result<Reputation, PropertiesError>;
+    /// Sets the status of an item's property as decided by votes, leaving
+    /// moderators' decisions alone.
+    async fn decide_by_votes(
+        &self,
+        item: String,
+        property: Property,
+        status: Status,
+    ) -> Result<(), PropertiesError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Reputation, Tally, Thresholds};
+    use crate::db::model::{Decision, Status};
+
+    fn tally(weighted_score: f64, vote_count: u64) -> Tally {
+        Tally {
+            status: Status::Pending,
+            decided_by: None,
+            weighted_score,
+            vote_count,
+        }
+    }
+
+    #[test]
+    fn test_weight() {
+        assert!((Reputation::default().weight() - 1.0).abs() < f64::EPSILON);
+        let trusted = Reputation {
+            agreed: 8,
+            disagreed: 0,
+        };
+        let distrusted = Reputation {
+            agreed: 0,
+            disagreed: 8,
+        };
+        assert!(trusted.weight() > 1.5 && trusted.weight() < 2.0);
+        assert!(distrusted.weight() < 0.5 && distrusted.weight() > 0.0);
+    }
+
+    #[test]
+    fn test_decide() {
+        // Votes decide nothing unless configured to
+        assert_eq!(Thresholds::default().decide(&tally(50.0, 50)), None);
+
+        let thresholds = Thresholds {
+            accept_score: Some(5.0),
+            reject_score: Some(-5.0),
+            min_votes: 5,
+        };
+        assert_eq!(thresholds.decide(&tally(5.0, 5)), Some(Status::Accepted));
+        assert_eq!(thresholds.decide(&tally(-5.5, 6)), Some(Status::Rejected));
+        // Not enough votes, or not decisive enough
+        assert_eq!(thresholds.decide(&tally(10.0, 4)), None);
+        assert_eq!(thresholds.decide(&tally(2.0, 8)), None);
+
+        // Votes can undo what votes decided
+        let mut accepted = tally(3.0, 8);
+        accepted.status = Status::Accepted;
+        accepted.decided_by = Some(Decision::Votes);
+        assert_eq!(thresholds.decide(&accepted), Some(Status::Pending));
+        // But not what a moderator or the extraction config did
+        accepted.decided_by = Some(Decision::Moderator);
+        assert_eq!(thresholds.decide(&accepted), None);
+        accepted.decided_by = None;
+        assert_eq!(thresholds.decide(&accepted), None);
+
+        let accept_only = Thresholds {
+            reject_score: None,
+            ..thresholds
+        };
+        assert_eq!(accept_only.decide(&tally(-50.0, 50)), None);
+    }
 }
@@ -1,15 +1,104 @@
-use bbscope::{BBCode, BBCodeTagConfig};
+use std::{
+    borrow::Cow,
+    sync::{Arc, LazyLock},
+};
+
+use ammonia::{Builder, UrlRelative};
+use bbscope::{BBCode, BBCodeTagConfig, MatchInfo, MatchType, ScopeInfo};
 use ractor::{Actor, ActorProcessingErr, ActorRef, RpcReplyPort, async_trait};
+use regex::{Captures, Regex};
+use reqwest::Url;
+use serde::Deserialize;
+use snafu::{ResultExt, Whatever};
+use surrealdb::{RecordId, Surreal, engine::local::Db};
+use tracing::{error, info};
+
+/// Tags the converted HTML may keep, everything else is stripped
+const ALLOWED_TAGS: &[&str] = &[
+    "a",
+    "b",
+    "blockquote",
+    "br",
+    "code",
+    "del",
+    "details",
+    "div",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "s",
+    "span",
+    "strong",
+    "sub",
+    "summary",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "th",
+    "thead",
+    "tr",
+    "u",
+    "ul",
+];
+
+/// Stored descriptions sanitised per `BBMsg::Backfill`
+const BACKFILL_BATCH: usize = 64;
+
+/// An HTML tag, capturing whether it closes and its name
+static HTML_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<(/?)([a-zA-Z0-9]+)[^>]*>").expect("compiling HTML tag regex"));
+
+/// Links to workshop items, capturing the item's ID
+static WORKSHOP_LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"steamcommunity\.com/(?:sharedfiles|workshop)/filedetails/?\?(?:[^\s\[\]"'<>]*?&)?id=(\d+)"#,
+    )
+    .expect("compiling workshop link regex")
+});
 
 pub struct BBActor {}
 
-pub struct BBArgs {}
+pub struct BBArgs {
+    pub database: Surreal<Db>,
+}
 pub struct BBState {
+    database: Surreal<Db>,
     bb: BBCode,
+    plain: BBCode,
+    sanitiser: Builder<'static>,
+}
+
+/// A description converted from BBCode
+#[derive(Debug, Clone)]
+pub struct Rendered {
+    /// Sanitised HTML for the UI
+    pub html: String,
+    /// Just the words, for language detection, embeddings and prompts
+    pub text: String,
+    /// IDs of the workshop items linked to, in the order first linked
+    pub workshop_links: Vec<String>,
 }
 
 pub enum BBMsg {
-    Process(String, RpcReplyPort<String>),
+    /// Converts a BBCode description into HTML that's safe to render and a
+    /// plain text copy
+    Process(String, RpcReplyPort<Rendered>),
+    /// Sanitises descriptions stored before they were sanitised on the way
+    /// in and gives them a plain text copy, a batch at a time starting after
+    /// the given item
+    Backfill(Option<RecordId>),
 }
 #[async_trait]
 impl Actor for BBActor {
@@ -20,25 +109,445 @@ impl Actor for BBActor {
     async fn pre_start(
         &self,
         _: ActorRef<Self::Msg>,
-        _: Self::Arguments,
+        args: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
         Ok(Self::State {
-            bb: BBCode::from_config(BBCodeTagConfig::extended(), None)?,
+            database: args.database,
+            bb: BBCode::from_config(BBCodeTagConfig::extended(), Some(steam_tags()?))?,
+            plain: BBCode::from_matchers(plain_tags()?),
+            sanitiser: sanitiser(),
         })
     }
 
     async fn handle(
         &self,
-        _: ActorRef<Self::Msg>,
+        myself: ActorRef<Self::Msg>,
         message: Self::Msg,
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
             BBMsg::Process(data, reply) => {
-                reply.send(state.bb.parse(&data))?;
+                let html = state.bb.parse(&data);
+                reply.send(Rendered {
+                    html: state.sanitiser.clean(&html).to_string(),
+                    text: tidy(&state.plain.parse(&data)),
+                    workshop_links: workshop_links(&data),
+                })?;
             }
+            BBMsg::Backfill(after) => match backfill(state, after).await {
+                Ok(Some(last)) => myself.send_message(BBMsg::Backfill(Some(last)))?,
+                Ok(None) => info!("description backfill finished"),
+                Err(error) => error!(?error, "description backfill"),
+            },
         }
 
         Ok(())
     }
 }
+
+#[derive(Deserialize)]
+struct Unsanitised {
+    id: RecordId,
+    description: String,
+    plain_description: String,
+}
+
+/// Sanitises up to `BACKFILL_BATCH` of the descriptions flagged by the
+/// `unsanitised_descriptions` migration after `after`, returning the last item
+/// to continue from if there may be more. Items downloaded again are stored
+/// without the flag, so only those gone from Steam are left to this. Their
+/// BBCode wasn't kept, so the plain text comes from the HTML instead.
+async fn backfill(state: &BBState, after: Option<RecordId>) -> Result<Option<RecordId>, Whatever> {
+    let items: Vec<Unsanitised> = state
+        .database
+        .query(
+            "SELECT id, description, plain_description FROM workshop_items WHERE unsanitised = \
+             true AND ($after = NONE OR id > $after) ORDER BY id LIMIT $limit",
+        )
+        .bind(("after", after))
+        .bind(("limit", BACKFILL_BATCH))
+        .await
+        .whatever_context("Querying unsanitised descriptions")?
+        .take(0)
+        .whatever_context("Taking unsanitised descriptions")?;
+    for item in &items {
+        let description = state.sanitiser.clean(&item.description).to_string();
+        let plain = if item.plain_description.is_empty() {
+            html_text(&description)
+        } else {
+            item.plain_description.clone()
+        };
+        state
+            .database
+            .query(
+                "UPDATE $id SET description = $description, plain_description = $plain, \
+                 unsanitised = NONE",
+            )
+            .bind(("id", item.id.clone()))
+            .bind(("description", description))
+            .bind(("plain", plain))
+            .await
+            .and_then(surrealdb::Response::check)
+            .whatever_context("Storing sanitised description")?;
+    }
+    Ok(items
+        .last()
+        .filter(|_| items.len() == BACKFILL_BATCH)
+        .map(|item| item.id.clone()))
+}
+
+/// Tags Steam supports beyond bbscope's extended set, see
+/// <https://steamcommunity.com/comment/Guide/formattinghelp>
+fn steam_tags() -> Result<Vec<MatchInfo>, regex::Error> {
+    let mut matchers = vec![];
+    let block = Some((0, 1));
+    BBCode::add_tagmatcher(&mut matchers, "olist", wrap("ol"), block, block)?;
+    BBCode::add_tagmatcher(&mut matchers, "strike", wrap("s"), None, None)?;
+    BBCode::add_tagmatcher(
+        &mut matchers,
+        "hr",
+        ScopeInfo::basic(Arc::new(|_, _, _| String::from("<hr>"))),
+        block,
+        block,
+    )?;
+    BBCode::add_tagmatcher(
+        &mut matchers,
+        "noparse",
+        ScopeInfo {
+            only: Some(BBCode::plaintext_ids()),
+            double_closes: false,
+            emit: Arc::new(|_, body, _| String::from(body)),
+        },
+        None,
+        None,
+    )?;
+    BBCode::add_tagmatcher(
+        &mut matchers,
+        "previewyoutube",
+        ScopeInfo {
+            only: Some(BBCode::plaintext_ids()),
+            double_closes: false,
+            emit: Arc::new(|open, _, _| {
+                youtube_id(open.as_ref())
+                    .map(|id| {
+                        let url = format!("https://www.youtube.com/watch?v={id}");
+                        format!(r#"<a href="{url}">{url}</a>"#)
+                    })
+                    .unwrap_or_default()
+            }),
+        },
+        block,
+        block,
+    )?;
+    // Tables take options like `[table noborder=1 equalcells=1]`, which
+    // `add_tagmatcher` doesn't allow for.
+    let (_, close) = BBCode::get_tagregex("table", block, block);
+    matchers.push(MatchInfo {
+        id: "table",
+        regex: Regex::new(r"^\[(?i:table)(?:[ \t][^\]\n]*)?\](?:\r?\n)?")?,
+        match_type: MatchType::Open(Arc::new(wrap("table"))),
+    });
+    matchers.push(MatchInfo {
+        id: "table",
+        regex: Regex::new(&close)?,
+        match_type: MatchType::Close,
+    });
+    let row = Some((1, 1));
+    BBCode::add_tagmatcher(&mut matchers, "tr", wrap("tr"), row, row)?;
+    let cell = (Some((1, 0)), Some((0, 1)));
+    BBCode::add_tagmatcher(&mut matchers, "th", wrap("th"), cell.0, cell.1)?;
+    BBCode::add_tagmatcher(&mut matchers, "td", wrap("td"), cell.0, cell.1)?;
+    Ok(matchers)
+}
+
+fn wrap(element: &'static str) -> ScopeInfo {
+    ScopeInfo::basic(Arc::new(move |_, body, _| {
+        format!("<{element}>{body}</{element}>")
+    }))
+}
+
+/// The video from `[previewyoutube=dQw4w9WgXcQ;full]`
+fn youtube_id<'a>(open: Option<&'a Captures>) -> Option<&'a str> {
+    open.and_then(|open| open.name("attr"))
+        .and_then(|attr| attr.as_str().split(';').next())
+        .filter(|id| {
+            !id.is_empty()
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+const PLAIN_TEXT_ID: &str = "plaintext";
+
+/// Strips the markup from a description, keeping the text and the breaks
+/// between blocks. Images and embedded videos are dropped.
+fn plain_tags() -> Result<Vec<MatchInfo>, regex::Error> {
+    let mut matchers = vec![];
+    // These keep their content as is, or drop it. They take the line break
+    // before them like the block tags below, so have to put it back.
+    let verbatim = |emit: fn(&str) -> String| ScopeInfo {
+        only: Some(vec![PLAIN_TEXT_ID]),
+        double_closes: false,
+        emit: Arc::new(move |open, body, _| {
+            let newline = open.is_some_and(|open| open[0].starts_with(['\r', '\n']));
+            format!("{}{}", if newline { "\n" } else { "" }, emit(body))
+        }),
+    };
+    let newline = Some((1, 0));
+    BBCode::add_tagmatcher(
+        &mut matchers,
+        "noparse",
+        verbatim(ToOwned::to_owned),
+        newline,
+        None,
+    )?;
+    BBCode::add_tagmatcher(
+        &mut matchers,
+        "img",
+        verbatim(|_| String::new()),
+        newline,
+        None,
+    )?;
+    BBCode::add_tagmatcher(
+        &mut matchers,
+        "previewyoutube",
+        verbatim(|_| String::new()),
+        newline,
+        None,
+    )?;
+    // Block tags take the line break before them, otherwise every list item
+    // and table row would be followed by a blank line.
+    matchers.push(MatchInfo {
+        id: "tag",
+        regex: Regex::new(
+            r"^(?P<newline>\r?\n)?\[(?P<close>/)?(?P<tag>\*|[a-zA-Z][a-zA-Z0-9]*)(?:[ \t=][^\]\n]*)?\]",
+        )?,
+        match_type: MatchType::Simple(Arc::new(|tag| {
+            let close = tag.name("close").is_some();
+            match tag["tag"].to_ascii_lowercase().as_str() {
+                "*" => "\n- ",
+                "h1" | "h2" | "h3" | "hr" | "quote" | "code" => "\n",
+                "list" | "olist" | "table" | "tr" if !close => "\n",
+                "td" | "th" => " ",
+                _ if tag.name("newline").is_some() => "\n",
+                _ => "",
+            }
+            .to_owned()
+        })),
+    });
+    matchers.push(MatchInfo {
+        id: PLAIN_TEXT_ID,
+        regex: Regex::new(r"^(?:[^\[\n]+|\n)")?,
+        match_type: MatchType::Simple(Arc::new(|text| text[0].to_owned())),
+    });
+    Ok(matchers)
+}
+
+fn workshop_links(description: &str) -> Vec<String> {
+    let mut links: Vec<String> = vec![];
+    for link in WORKSHOP_LINK.captures_iter(description) {
+        if !links.iter().any(|id| id == &link[1]) {
+            links.push(link[1].to_owned());
+        }
+    }
+    links
+}
+
+/// The text of sanitised HTML, broken up like `plain_tags` does
+fn html_text(html: &str) -> String {
+    let text = HTML_TAG.replace_all(html, |tag: &Captures| {
+        let close = !tag[1].is_empty();
+        match tag[2].to_ascii_lowercase().as_str() {
+            "li" if !close => "\n- ",
+            "td" | "th" => " ",
+            "br" | "hr" | "p" | "div" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "blockquote"
+            | "pre" | "details" | "summary" | "ol" | "ul" | "table" | "tr" => "\n",
+            _ => "",
+        }
+    });
+    // Ammonia only escapes these in text
+    tidy(
+        &text
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&nbsp;", " ")
+            .replace("&amp;", "&"),
+    )
+}
+
+/// Collapses the whitespace in each line and the runs of blank lines left
+/// behind by removed tags, keeping paragraphs apart.
+fn tidy(text: &str) -> String {
+    let mut tidied = String::with_capacity(text.len());
+    let mut blank = 0;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank += 1;
+            continue;
+        }
+        if !tidied.is_empty() {
+            tidied.push_str(if blank > 0 { "\n\n" } else { "\n" });
+        }
+        tidied.push_str(&line);
+        blank = 0;
+    }
+    tidied
+}
+
+/// An allow-list of what the UI renders from descriptions. Links and images
+/// have to be absolute http(s) URLs and links are marked so they can't reach
+/// back into the page or lend it search ranking.
+fn sanitiser() -> Builder<'static> {
+    let mut builder = Builder::empty();
+    builder
+        .add_tags(ALLOWED_TAGS.iter().copied())
+        .add_tag_attributes("a", ["href"])
+        .add_tag_attributes("img", ["src", "alt"])
+        .add_url_schemes(["http", "https"])
+        .url_relative(UrlRelative::Deny)
+        .clean_content_tags(["script", "style"].into())
+        .link_rel(Some("noopener nofollow"))
+        .attribute_filter(filter_attribute);
+    builder
+}
+
+fn filter_attribute<'u>(element: &str, attribute: &str, value: &'u str) -> Option<Cow<'u, str>> {
+    match (element, attribute) {
+        ("a", "href") => Some(unwrap_link_filter(value).map_or(Cow::Borrowed(value), Cow::Owned)),
+        _ => Some(Cow::Borrowed(value)),
+    }
+}
+
+/// Steam sends outbound links through its link filter, i.e.
+/// `https://steamcommunity.com/linkfilter/?url=https://example.com`, which
+/// tracks the click and shows an interstitial. This returns the destination.
+fn unwrap_link_filter(href: &str) -> Option<String> {
+    let url = Url::parse(href).ok()?;
+    let steam = matches!(
+        url.host_str(),
+        Some("steamcommunity.com" | "www.steamcommunity.com")
+    );
+    if !steam || !url.path().starts_with("/linkfilter") {
+        return None;
+    }
+    url.query_pairs()
+        .find(|(key, _)| key == "url" || key == "u")
+        .and_then(|(_, target)| Url::parse(&target).ok())
+        .filter(|target| matches!(target.scheme(), "http" | "https"))
+        .map(String::from)
+}
+
+#[cfg(test)]
+mod test {
+    use bbscope::{BBCode, BBCodeTagConfig};
+
+    use super::{
+        html_text, plain_tags, sanitiser, steam_tags, tidy, unwrap_link_filter, workshop_links,
+    };
+
+    const DESCRIPTION: &str = r"[h1]Better Farming[/h1]
+Adds [b]crops[/b] and [strike]bugs[/strike].
+
+[previewyoutube=dQw4w9WgXcQ;full][/previewyoutube]
+[olist]
+[*]One
+[*]Two
+[/olist]
+[table noborder=1]
+[tr]
+[td]A[/td]
+[td]1[/td]
+[/tr]
+[/table]
+[noparse][b]not bold[/b][/noparse]
+[img]https://example.com/a.png[/img]";
+
+    #[test]
+    fn test_steam_tags() {
+        let bb =
+            BBCode::from_config(BBCodeTagConfig::extended(), Some(steam_tags().unwrap())).unwrap();
+        assert_eq!(
+            sanitiser().clean(&bb.parse(DESCRIPTION)).to_string(),
+            "<h1>Better Farming</h1>Adds <b>crops</b> and <s>bugs</s>.<br><br><a \
+             href=\"https://www.youtube.com/watch?v=dQw4w9WgXcQ\" rel=\"noopener \
+             nofollow\">https://www.youtube.com/watch?v=dQw4w9WgXcQ</a><ol><li>One</li><li>Two<br></li></ol><table><tbody><tr><td>A</td><td>1</td></tr></tbody></table>[b]not \
+             bold[/b]<br><img src=\"https://example.com/a.png\">"
+        );
+    }
+
+    #[test]
+    fn test_plain_text() {
+        let plain = BBCode::from_matchers(plain_tags().unwrap());
+        assert_eq!(
+            tidy(&plain.parse(DESCRIPTION)),
+            "Better Farming\n\nAdds crops and bugs.\n\n- One\n- Two\n\nA 1\n\n[b]not bold[/b]"
+        );
+    }
+
+    #[test]
+    fn test_workshop_links() {
+        assert_eq!(
+            workshop_links(
+                "Requires [url=https://steamcommunity.com/sharedfiles/filedetails/?id=2009463077]\
+                 Harmony[/url] and steamcommunity.com/workshop/filedetails/?searchtext=&id=818773962, \
+                 see https://steamcommunity.com/sharedfiles/filedetails/?id=2009463077 again"
+            ),
+            ["2009463077", "818773962"]
+        );
+    }
+
+    #[test]
+    fn test_unwrap_link_filter() {
+        assert_eq!(
+            unwrap_link_filter(
+                "https://steamcommunity.com/linkfilter/?url=https%3A%2F%2Fgithub.com%2Fexample"
+            )
+            .as_deref(),
+            Some("https://github.com/example")
+        );
+        assert_eq!(
+            unwrap_link_filter("https://steamcommunity.com/linkfilter/?u=https://example.com/a")
+                .as_deref(),
+            Some("https://example.com/a")
+        );
+        assert!(
+            unwrap_link_filter("https://steamcommunity.com/linkfilter/?url=javascript:alert(1)")
+                .is_none()
+        );
+        assert!(
+            unwrap_link_filter("https://example.com/linkfilter/?url=https://example.org").is_none()
+        );
+    }
+
+    #[test]
+    fn test_html_text() {
+        assert_eq!(
+            html_text(
+                "<h1>Better Farming</h1>Adds <b>crops</b> &amp; bugs.<br><br><ul><li>One</li><li>Two</li></ul><img src=\"https://example.com/a.png\">"
+            ),
+            "Better Farming\nAdds crops & bugs.\n\n- One\n- Two"
+        );
+    }
+
+    #[test]
+    fn test_sanitise() {
+        let clean = sanitiser()
+            .clean(
+                r#"<b onclick="steal()">Bold</b><script>alert(1)</script><a href="https://steamcommunity.com/linkfilter/?url=https://example.com" target="_blank">link</a><img src="javascript:alert(1)"><iframe src="https://example.com"></iframe>"#,
+            )
+            .to_string();
+        assert_eq!(
+            clean,
+            r#"<b>Bold</b><a href="https://example.com/" rel="noopener nofollow">link</a><img>"#
+        );
+        // Classes could pick up the UI's styles
+        assert_eq!(
+            sanitiser()
+                .clean(r#"<span class="fixed inset-0">Cover</span>"#)
+                .to_string(),
+            "<span>Cover</span>"
+        );
+    }
+}
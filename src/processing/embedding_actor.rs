@@ -0,0 +1,194 @@
+use std::sync::OnceLock;
+
+use classification::{
+    WhateverAsync,
+    embedding::{EMBEDDING_MODEL_ID, EmbeddingRunner},
+};
+use ractor::{Actor, ActorProcessingErr, ActorRef, RpcReplyPort, async_trait};
+use serde::Deserialize;
+use snafu::{ResultExt, Whatever};
+use surrealdb::{RecordId, Surreal, engine::local::Db};
+use tracing::{debug, error, info};
+
+pub static EMBEDDING_ACTOR: OnceLock<ActorRef<EmbeddingMsg>> = OnceLock::new();
+
+/// Items embedded per `EmbeddingMsg::Backfill`, kept small so searches aren't
+/// stuck behind a long backfill.
+const BACKFILL_BATCH: usize = 16;
+/// Size of the candidate list searched by the HNSW index, higher is more
+/// accurate but slower
+const SEARCH_EF: usize = 64;
+
+pub struct EmbeddingActor;
+
+pub struct EmbeddingArgs {
+    pub database: Surreal<Db>,
+}
+
+pub struct EmbeddingState {
+    database: Surreal<Db>,
+    runner: EmbeddingRunner,
+}
+
+pub enum EmbeddingMsg {
+    /// Embed and store an item's title and description
+    Store(RecordId, String, String),
+    /// Embed items that don't have an embedding yet, a batch at a time
+    /// starting after the given item
+    Backfill(Option<RecordId>),
+    /// Embed free text, i.e. a search query
+    Embed(String, RpcReplyPort<Result<Vec<f32>, WhateverAsync>>),
+}
+
+#[async_trait]
+impl Actor for EmbeddingActor {
+    type Arguments = EmbeddingArgs;
+    type Msg = EmbeddingMsg;
+    type State = EmbeddingState;
+
+    async fn pre_start(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        args: Self::Arguments,
+    ) -> Result<Self::State, ActorProcessingErr> {
+        let runner = EmbeddingRunner::setup().await?;
+        EMBEDDING_ACTOR.get_or_init(|| myself);
+        Ok(EmbeddingState {
+            database: args.database,
+            runner,
+        })
+    }
+
+    async fn handle(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        match message {
+            EmbeddingMsg::Store(id, title, description) => {
+                if let Err(error) = store(state, &id, &title, &description).await {
+                    error!(?error, record=%id, "storing embedding");
+                }
+            }
+            EmbeddingMsg::Backfill(after) => match backfill(state, after).await {
+                Ok(Some(last)) => myself.send_message(EmbeddingMsg::Backfill(Some(last)))?,
+                Ok(None) => info!("embedding backfill finished"),
+                Err(error) => error!(?error, "embedding backfill"),
+            },
+            EmbeddingMsg::Embed(text, reply) => {
+                let _ = reply.send(state.runner.embed(text).await);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The text an item is embedded from
+fn item_text(title: &str, description: &str) -> String {
+    format!("{title}\n{description}")
+}
+
+async fn store(
+    state: &EmbeddingState,
+    id: &RecordId,
+    title: &str,
+    description: &str,
+) -> Result<(), Whatever> {
+    let embedding = state
+        .runner
+        .embed(item_text(title, description))
+        .await
+        .whatever_context("Embedding item")?;
+    state
+        .database
+        .query(
+            "UPSERT type::thing('item_embeddings', record::id($item)) SET item = $item, model = \
+             $model, embedding = $embedding",
+        )
+        .bind(("item", id.clone()))
+        .bind(("model", EMBEDDING_MODEL_ID))
+        .bind(("embedding", embedding))
+        .await
+        .and_then(surrealdb::Response::check)
+        .whatever_context("Storing embedding")?;
+    debug!(record=%id, "stored embedding");
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Unembedded {
+    id: RecordId,
+    title: String,
+    description: String,
+}
+
+/// Embeds up to `BACKFILL_BATCH` items after `after`, returning the last one
+/// to continue from if there may be more.
+async fn backfill(
+    state: &EmbeddingState,
+    after: Option<RecordId>,
+) -> Result<Option<RecordId>, Whatever> {
+    let items: Vec<Unembedded> = state
+        .database
+        .query(
+            "SELECT id, title, plain_description || description AS description FROM \
+             workshop_items WHERE ($after = NONE OR id > $after) AND \
+             !record::exists(type::thing('item_embeddings', record::id(id))) ORDER BY id LIMIT \
+             $limit",
+        )
+        .bind(("after", after))
+        .bind(("limit", BACKFILL_BATCH))
+        .await
+        .whatever_context("Querying items without embeddings")?
+        .take(0)
+        .whatever_context("Taking items without embeddings")?;
+    for item in &items {
+        // Continue with the rest, the failed item is retried on the next backfill
+        if let Err(error) = store(state, &item.id, &item.title, &item.description).await {
+            error!(?error, record=%item.id, "storing embedding");
+        }
+    }
+    Ok(items
+        .last()
+        .filter(|_| items.len() == BACKFILL_BATCH)
+        .map(|item| item.id.clone()))
+}
+
+#[derive(Deserialize)]
+struct Neighbour {
+    item: RecordId,
+}
+
+/// Finds the `count` items closest to `embedding`, nearest first.
+pub async fn nearest_items(
+    db: &Surreal<Db>,
+    embedding: Vec<f32>,
+    count: usize,
+) -> Result<Vec<RecordId>, surrealdb::Error> {
+    let neighbours: Vec<Neighbour> = db
+        .query(format!(
+            "SELECT item, vector::distance::knn() AS distance FROM item_embeddings WHERE \
+             embedding <|{count},{SEARCH_EF}|> $embedding ORDER BY distance"
+        ))
+        .bind(("embedding", embedding))
+        .await?
+        .check()?
+        .take(0)?;
+    Ok(neighbours
+        .into_iter()
+        .map(|neighbour| neighbour.item)
+        .collect())
+}
+
+/// Looks up the stored embedding for an item.
+pub async fn item_embedding(
+    db: &Surreal<Db>,
+    id: RecordId,
+) -> Result<Option<Vec<f32>>, surrealdb::Error> {
+    db.query("RETURN type::thing('item_embeddings', record::id($item)).embedding")
+        .bind(("item", id))
+        .await?
+        .check()?
+        .take(0)
+}
@@ -6,10 +6,14 @@ use surrealdb::RecordId;
 use tracing::error;
 
 use crate::{
-    db::{item_update_actor::ItemUpdateMsg, model, model::WorkshopItem},
+    db::{
+        item_update_actor::{ItemUpdateMsg, Relations},
+        model,
+        model::WorkshopItem,
+    },
     processing::{
-        bb_actor::BBMsg,
-        language_actor::{DetectedLanguage, LanguageMsg},
+        bb_actor::{BBMsg, Rendered},
+        language_actor::{ItemLanguages, LanguageMsg},
     },
     steam::model::IPublishedStruct,
 };
@@ -59,14 +63,22 @@ impl Actor for JoinProcessActor {
         match message {
             JoinProcessMsg::Process(mut data) => {
                 let description = take(&mut data.file_description).unwrap_or_default();
-                let languages = call!(state.language, LanguageMsg::Detect, description.clone())?;
-                let description = call!(state.bb, BBMsg::Process, description)?;
-                let children = take(&mut data.children);
+                let mut description = call!(state.bb, BBMsg::Process, description)?;
+                let languages = call!(
+                    state.language,
+                    LanguageMsg::Detect,
+                    data.title.clone().unwrap_or_default(),
+                    description.text.clone()
+                )?;
+                let relations = Relations {
+                    children: take(&mut data.children),
+                    mentioned: take(&mut description.workshop_links),
+                };
                 match Self::new_item(data, languages, description) {
                     Ok(item) => {
                         state
                             .item_update
-                            .send_message(ItemUpdateMsg::MaybeQueueMl((item, children)))?;
+                            .send_message(ItemUpdateMsg::MaybeQueueMl((item, relations)))?;
                     }
                     Err(error) => {
                         error!(%error, "Creating new item");
@@ -82,15 +94,20 @@ impl Actor for JoinProcessActor {
 impl JoinProcessActor {
     fn new_item(
         data: IPublishedStruct,
-        languages: Vec<DetectedLanguage>,
-        description: String,
+        languages: ItemLanguages,
+        description: Rendered,
     ) -> Result<WorkshopItem<RecordId>, Whatever> {
         let app_id = data.creator_appid.whatever_context("Missing app id")?;
         let item: WorkshopItem<RecordId> = WorkshopItem {
             appid: app_id,
             author: data.creator.whatever_context("Missing author")?,
-            languages,
-            description,
+            languages: languages.significant(),
+            primary_language: languages.primary,
+            also_contains: languages.also_contains,
+            title_language: languages.title,
+            language_confidence: languages.confidence,
+            description: description.html,
+            plain_description: description.text,
             id: RecordId::from_table_key("workshop_items", data.publishedfileid),
             title: data.title.whatever_context("Missing title")?,
             preview_url: data.preview_url,
@@ -1,36 +1,72 @@
-use std::{collections::HashMap, convert::Into, fmt};
-
-use lingua::{
-    Language,
-    Language::{Chinese, English, Japanese, Korean, Portuguese, Russian, Spanish},
-    LanguageDetector, LanguageDetectorBuilder,
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt, mem,
 };
+
+use lingua::{Language, LanguageDetector, LanguageDetectorBuilder};
 use ractor::{Actor, ActorProcessingErr, ActorRef, RpcReplyPort, async_trait};
 use salvo::prelude::ToSchema;
+use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-use crate::processing::language_actor::DetectedLanguage::Unknown;
-
 // The threshold of total words a language must be, to be considered valid for
 // detection.
 const WORD_PERCENTAGE: f32 = 0.2;
+/// Words a paragraph needs before its language is counted on its own, shorter
+/// ones tend to be links, credits or headings.
+const MIN_PARAGRAPH_WORDS: usize = 5;
+
+/// Declares `DetectedLanguage` along with its mapping to lingua's `Language`.
+/// The values are stored, so existing ones must never change.
+macro_rules! detected_languages {
+    ($($language:ident = $value:literal,)*) => {
+        #[derive(
+            Debug,
+            Default,
+            ToSchema,
+            Copy,
+            Clone,
+            Serialize_repr,
+            Deserialize_repr,
+            Eq,
+            PartialEq,
+            Ord,
+            PartialOrd,
+            Hash,
+        )]
+        #[repr(u8)]
+        pub enum DetectedLanguage {
+            #[default]
+            Unknown = 0,
+            $($language = $value,)*
+        }
+
+        impl DetectedLanguage {
+            /// Every language that can be detected
+            pub const ALL: &[Self] = &[$(Self::$language,)*];
+
+            /// The lingua equivalent, `None` for `Unknown`
+            pub fn language(self) -> Option<Language> {
+                match self {
+                    Self::Unknown => None,
+                    $(Self::$language => Some(Language::$language),)*
+                }
+            }
+        }
+
+        impl From<Language> for DetectedLanguage {
+            #[allow(unreachable_patterns, reason = "lingua may add languages")]
+            fn from(value: Language) -> Self {
+                match value {
+                    $(Language::$language => Self::$language,)*
+                    _ => Self::Unknown,
+                }
+            }
+        }
+    };
+}
 
-#[derive(
-    Debug,
-    Default,
-    ToSchema,
-    Copy,
-    Clone,
-    Serialize_repr,
-    Deserialize_repr,
-    Eq,
-    PartialEq,
-    Ord,
-    PartialOrd,
-)]
-#[repr(u8)]
-pub enum DetectedLanguage {
-    #[default]
+detected_languages! {
     English = 1,
     Russian = 2,
     Chinese = 3,
@@ -38,7 +74,93 @@ pub enum DetectedLanguage {
     Korean = 5,
     Spanish = 6,
     Portuguese = 7,
-    Unknown = 0,
+    Afrikaans = 8,
+    Albanian = 9,
+    Arabic = 10,
+    Armenian = 11,
+    Azerbaijani = 12,
+    Basque = 13,
+    Belarusian = 14,
+    Bengali = 15,
+    Bokmal = 16,
+    Bosnian = 17,
+    Bulgarian = 18,
+    Catalan = 19,
+    Croatian = 20,
+    Czech = 21,
+    Danish = 22,
+    Dutch = 23,
+    Esperanto = 24,
+    Estonian = 25,
+    Finnish = 26,
+    French = 27,
+    Ganda = 28,
+    Georgian = 29,
+    German = 30,
+    Greek = 31,
+    Gujarati = 32,
+    Hebrew = 33,
+    Hindi = 34,
+    Hungarian = 35,
+    Icelandic = 36,
+    Indonesian = 37,
+    Irish = 38,
+    Italian = 39,
+    Kazakh = 40,
+    Latin = 41,
+    Latvian = 42,
+    Lithuanian = 43,
+    Macedonian = 44,
+    Malay = 45,
+    Maori = 46,
+    Marathi = 47,
+    Mongolian = 48,
+    Nynorsk = 49,
+    Persian = 50,
+    Polish = 51,
+    Punjabi = 52,
+    Romanian = 53,
+    Serbian = 54,
+    Shona = 55,
+    Slovak = 56,
+    Slovene = 57,
+    Somali = 58,
+    Sotho = 59,
+    Swahili = 60,
+    Swedish = 61,
+    Tagalog = 62,
+    Tamil = 63,
+    Telugu = 64,
+    Thai = 65,
+    Tsonga = 66,
+    Tswana = 67,
+    Turkish = 68,
+    Ukrainian = 69,
+    Urdu = 70,
+    Vietnamese = 71,
+    Welsh = 72,
+    Xhosa = 73,
+    Yoruba = 74,
+    Zulu = 75,
+}
+
+impl DetectedLanguage {
+    /// Languages detected unless configured otherwise
+    pub const DEFAULT: &[Self] = &[
+        Self::English,
+        Self::Russian,
+        Self::Chinese,
+        Self::Japanese,
+        Self::Korean,
+    ];
+
+    /// Looks a language up by its English name, ignoring case, i.e. `german`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|language| language.to_string().eq_ignore_ascii_case(name))
+    }
 }
 
 impl fmt::Display for DetectedLanguage {
@@ -46,30 +168,61 @@ impl fmt::Display for DetectedLanguage {
         write!(f, "{self:?}")
     }
 }
-impl From<Language> for DetectedLanguage {
-    fn from(value: Language) -> Self {
-        match value {
-            Chinese => Self::Chinese,
-            Russian => Self::Russian,
-            Japanese => Self::Japanese,
-            Korean => Self::Korean,
-            English => Self::English,
-            Spanish => Self::Spanish,
-            Portuguese => Self::Portuguese,
-            _ => Unknown,
-        }
+
+/// A language found in a text and the fraction of the text's words detected as
+/// it, from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct LanguageConfidence {
+    pub language: DetectedLanguage,
+    pub confidence: f32,
+}
+
+/// The languages found in an item's title and description
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ItemLanguages {
+    /// The title's language, when it could be told
+    pub title: Option<DetectedLanguage>,
+    /// Who the item is written for, see `detect_item`
+    pub primary: Option<DetectedLanguage>,
+    /// Other languages with a paragraph of their own or a significant share of
+    /// the description
+    pub also_contains: Vec<DetectedLanguage>,
+    /// Every language detected in the description, most prevalent first
+    pub confidence: Vec<LanguageConfidence>,
+}
+
+impl ItemLanguages {
+    /// The languages the item is written in, the primary one followed by any
+    /// making up a significant part of the description, see `WORD_PERCENTAGE`.
+    /// Languages only found in a paragraph are left to `also_contains`.
+    pub fn significant(&self) -> Vec<DetectedLanguage> {
+        self.primary
+            .into_iter()
+            .chain(
+                self.confidence
+                    .iter()
+                    .filter(|found| {
+                        Some(found.language) != self.primary && found.confidence > WORD_PERCENTAGE
+                    })
+                    .map(|found| found.language),
+            )
+            .collect()
     }
 }
 
 pub struct LanguageActor {}
 
-pub struct LanguageArgs {}
+pub struct LanguageArgs {
+    /// Languages to tell apart, at least two
+    pub languages: Vec<Language>,
+}
 pub struct LanguageState {
     detector: LanguageDetector,
 }
 
 pub enum LanguageMsg {
-    Detect(String, RpcReplyPort<Vec<DetectedLanguage>>),
+    /// Detects the languages of an item's title and description
+    Detect(String, String, RpcReplyPort<ItemLanguages>),
 }
 #[async_trait]
 impl Actor for LanguageActor {
@@ -80,14 +233,12 @@ impl Actor for LanguageActor {
     async fn pre_start(
         &self,
         _: ActorRef<Self::Msg>,
-        _: Self::Arguments,
+        args: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
         Ok(Self::State {
-            detector: LanguageDetectorBuilder::from_languages(&[
-                English, Russian, Chinese, Japanese, Korean,
-            ])
-            .with_minimum_relative_distance(0.9)
-            .build(),
+            detector: LanguageDetectorBuilder::from_languages(&args.languages)
+                .with_minimum_relative_distance(0.9)
+                .build(),
         })
     }
 
@@ -98,8 +249,8 @@ impl Actor for LanguageActor {
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
-            LanguageMsg::Detect(text, reply) => {
-                let _ = reply.send(detect(&text, &state.detector));
+            LanguageMsg::Detect(title, description, reply) => {
+                let _ = reply.send(detect_item(&title, &description, &state.detector));
             }
         }
 
@@ -110,21 +261,88 @@ impl Actor for LanguageActor {
 /// Using heuristics, determine what languages are likely present in the text.
 /// I'd noticed that mods sometimes have translated descriptions, hence, the
 /// need to return N langs.
-pub fn detect(text: &str, language_detector: &LanguageDetector) -> Vec<DetectedLanguage> {
+///
+/// Each paragraph is detected on its own, so a short section in another
+/// language isn't drowned out by the rest. The title decides the primary
+/// language when the description contains it, a translation pack often keeps
+/// the original mod's (usually English) blurb around its own.
+#[allow(clippy::cast_precision_loss)]
+pub fn detect_item(
+    title: &str,
+    description: &str,
+    language_detector: &LanguageDetector,
+) -> ItemLanguages {
+    let title_language = language_detector
+        .detect_language_of(title)
+        .map(DetectedLanguage::from);
     let mut detected_languages = HashMap::new();
     let mut total_words = 0;
-    for language in language_detector.detect_multiple_languages_of(text) {
-        detected_languages
-            .entry(language.language())
-            .and_modify(|e| *e += language.word_count())
-            .or_insert(language.word_count());
-        total_words += language.word_count();
+    let mut paragraph_languages = BTreeSet::new();
+    for paragraph in paragraphs(description) {
+        let mut paragraph_words = HashMap::new();
+        for language in language_detector.detect_multiple_languages_of(&paragraph) {
+            *paragraph_words
+                .entry(DetectedLanguage::from(language.language()))
+                .or_insert(0) += language.word_count();
+        }
+        let words: usize = paragraph_words.values().sum();
+        let dominant = paragraph_words.iter().max_by_key(|&(_, words)| words);
+        if let Some((&dominant, _)) = dominant.filter(|_| words >= MIN_PARAGRAPH_WORDS) {
+            paragraph_languages.insert(dominant);
+        }
+        for (language, words) in paragraph_words {
+            *detected_languages.entry(language).or_insert(0) += words;
+        }
+        total_words += words;
     }
-    detected_languages
+
+    let mut confidence = detected_languages
         .into_iter()
-        .filter(|&(_, words)| words as f32 > total_words as f32 * WORD_PERCENTAGE)
-        .map(|(lang, _)| lang.into())
-        .collect()
+        .map(|(language, words)| LanguageConfidence {
+            language,
+            confidence: words as f32 / total_words as f32,
+        })
+        .collect::<Vec<_>>();
+    confidence.sort_unstable_by(|a, b| b.confidence.total_cmp(&a.confidence));
+
+    let primary = title_language
+        .filter(|language| confidence.iter().any(|found| found.language == *language))
+        .or_else(|| confidence.first().map(|found| found.language));
+    let also_contains = confidence
+        .iter()
+        .filter(|found| {
+            Some(found.language) != primary
+                && (found.confidence > WORD_PERCENTAGE
+                    || paragraph_languages.contains(&found.language))
+        })
+        .map(|found| found.language)
+        .collect();
+    ItemLanguages {
+        title: title_language,
+        primary,
+        also_contains,
+        confidence,
+    }
+}
+
+/// Splits text on blank lines and separator lines, i.e. `=====`
+fn paragraphs(text: &str) -> Vec<String> {
+    let mut paragraphs = vec![];
+    let mut current = String::new();
+    for line in text.lines().map(str::trim) {
+        if line.chars().any(char::is_alphanumeric) {
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(line);
+        } else if !current.is_empty() {
+            paragraphs.push(mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current);
+    }
+    paragraphs
 }
 
 #[cfg(test)]
@@ -134,7 +352,9 @@ mod test {
         LanguageDetectorBuilder,
     };
 
-    use crate::processing::language_actor::DetectedLanguage;
+    use crate::processing::language_actor::{
+        DetectedLanguage, ItemLanguages, LanguageConfidence, detect_item, paragraphs,
+    };
 
     #[test]
     fn test_lang_encode() {
@@ -145,6 +365,68 @@ mod test {
             "1"
         );
     }
+    #[test]
+    fn test_from_name() {
+        assert_eq!(
+            DetectedLanguage::from_name("german"),
+            Some(DetectedLanguage::German)
+        );
+        assert_eq!(DetectedLanguage::from_name("Klingon"), None);
+        // Stored values must not shift as languages are added
+        assert_eq!(DetectedLanguage::Portuguese as u8, 7);
+        assert_eq!(DetectedLanguage::from(English), DetectedLanguage::English);
+    }
+    #[test]
+    fn test_paragraphs() {
+        assert_eq!(
+            paragraphs("Intro line\ncontinues\n\n=====\n\r\nSecond part\n"),
+            vec!["Intro line\ncontinues", "Second part"]
+        );
+    }
+
+    #[test]
+    fn test_translation_primary() {
+        let detector = LanguageDetectorBuilder::from_languages(&[English, Russian])
+            .with_minimum_relative_distance(0.9)
+            .build();
+        let detected = detect_item(
+            "Русский перевод",
+            "This is a translation of the original mod, all credit goes to its author.\n\nПеревод \
+             всех текстов мода на русский язык, включая описания предметов и исследований.",
+            &detector,
+        );
+        assert_eq!(detected.primary, Some(DetectedLanguage::Russian));
+        assert_eq!(detected.also_contains, vec![DetectedLanguage::English]);
+    }
+
+    #[test]
+    fn test_significant() {
+        let languages = ItemLanguages {
+            title: None,
+            primary: Some(DetectedLanguage::Russian),
+            also_contains: vec![DetectedLanguage::English, DetectedLanguage::Japanese],
+            confidence: vec![
+                LanguageConfidence {
+                    language: DetectedLanguage::Russian,
+                    confidence: 0.6,
+                },
+                LanguageConfidence {
+                    language: DetectedLanguage::English,
+                    confidence: 0.3,
+                },
+                LanguageConfidence {
+                    language: DetectedLanguage::Japanese,
+                    confidence: 0.1,
+                },
+            ],
+        };
+        // A paragraph alone doesn't make the item Japanese
+        assert_eq!(
+            languages.significant(),
+            vec![DetectedLanguage::Russian, DetectedLanguage::English]
+        );
+    }
+
     #[test]
     fn test_mixed() {
         let foo = r"
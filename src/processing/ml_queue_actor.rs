@@ -1,29 +1,71 @@
-use classification::actor::ExtractionMsg;
+use std::{
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+
+use classification::{Confidence, Extraction, MLProperties, Vocabulary, actor::ExtractionMsg};
 use ractor::{Actor, ActorProcessingErr, ActorRef, async_trait, call};
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
 use snafu::{ResultExt, Whatever};
 use surrealdb::{RecordId, Surreal, engine::local::Db};
+use tokio::sync::Semaphore;
 use tracing::{debug, error, info};
 
 use crate::{
     db::{
+        ItemID,
         model::{Class, Source, Status},
         properties_actor::PropertiesMsg,
     },
-    domain::properties::NewProperty,
+    domain::properties::{NewProperty, Provenance},
+    processing::language_actor::DetectedLanguage,
 };
 
+pub static ML_QUEUE_ACTOR: OnceLock<ActorRef<MLQueueMsg>> = OnceLock::new();
+/// The model and prompt version the queue's extractor is running with
+pub static CURRENT_EXTRACTION: OnceLock<Provenance> = OnceLock::new();
+
+/// How long a loaded vocabulary is reused for, keeping it stable between items
+/// lets the extractor reuse its cached prompt prefix.
+const VOCABULARY_REFRESH: Duration = Duration::from_secs(60 * 60);
+
 pub struct MLQueueActor;
 
 pub struct MLQueueArgs {
     pub database: Surreal<Db>,
     pub extractor: ActorRef<ExtractionMsg>,
     pub property_actor: ActorRef<PropertiesMsg>,
+    /// Set when classifying against a closed vocabulary, the number of values
+    /// per class to offer
+    pub vocabulary_limit: Option<usize>,
+    /// Status given to extracted properties
+    pub status: Status,
+    /// Properties the model is at least this confident in are accepted
+    /// regardless of `status`
+    pub accept_confidence: Option<f32>,
+    /// How many items are sent to the extractor at once, matching its batch
+    /// size lets it fill each batch
+    pub concurrency: usize,
+    /// The model and prompt version the extractor is running with
+    pub current: Provenance,
 }
 
 pub struct MLQueueState {
+    processor: Processor,
+    vocabulary_limit: Option<usize>,
+    vocabulary: Option<(Instant, Vocabulary)>,
+    in_flight: Arc<Semaphore>,
+}
+
+/// What's needed to process an item, cloned into each in flight extraction
+#[derive(Clone)]
+struct Processor {
     database: Surreal<Db>,
     extractor: ActorRef<ExtractionMsg>,
     property_actor: ActorRef<PropertiesMsg>,
+    status: Status,
+    accept_confidence: Option<f32>,
 }
 
 pub enum MLQueueMsg {
@@ -31,6 +73,26 @@ pub enum MLQueueMsg {
     Process(RecordId),
 }
 
+/// Selects items to extract again, by default those that were extracted by a
+/// different model or prompt version than the one currently running.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+#[serde(default)]
+pub struct RequeueFilter {
+    /// Only items belonging to this app
+    pub app_id: Option<u64>,
+    /// Only these items
+    pub items: Option<Vec<String>>,
+    /// Only items extracted with this prompt version
+    pub prompt_version: Option<String>,
+    /// Also queue items without a recorded extraction, which includes those
+    /// extracted before versions were recorded
+    pub unextracted: bool,
+    /// Queue items even if they were extracted with the current version
+    pub force: bool,
+    /// The most items to queue
+    pub limit: Option<usize>,
+}
+
 #[async_trait]
 impl Actor for MLQueueActor {
     type Arguments = MLQueueArgs;
@@ -39,13 +101,22 @@ impl Actor for MLQueueActor {
 
     async fn pre_start(
         &self,
-        _: ActorRef<Self::Msg>,
+        myself: ActorRef<Self::Msg>,
         args: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
+        ML_QUEUE_ACTOR.get_or_init(|| myself);
+        CURRENT_EXTRACTION.get_or_init(|| args.current);
         Ok(MLQueueState {
-            database: args.database,
-            extractor: args.extractor,
-            property_actor: args.property_actor,
+            processor: Processor {
+                database: args.database,
+                extractor: args.extractor,
+                property_actor: args.property_actor,
+                status: args.status,
+                accept_confidence: args.accept_confidence,
+            },
+            vocabulary_limit: args.vocabulary_limit,
+            vocabulary: None,
+            in_flight: Arc::new(Semaphore::new(args.concurrency.max(1))),
         })
     }
 
@@ -57,20 +128,93 @@ impl Actor for MLQueueActor {
     ) -> Result<(), ActorProcessingErr> {
         match message {
             MLQueueMsg::Process(id) => {
-                if let Err(e) = process_one(state, &id).await {
-                    error!(?e, record=%id, "processing ML extraction");
-                }
+                let vocabulary = match state.vocabulary().await {
+                    Ok(vocabulary) => vocabulary,
+                    Err(e) => {
+                        error!(?e, record=%id, "loading vocabulary for ML extraction");
+                        return Ok(());
+                    }
+                };
+                // Waiting here holds the rest of the queue back once enough
+                // items are in flight
+                let permit = state.in_flight.clone().acquire_owned().await?;
+                let processor = state.processor.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = process_one(&processor, &id, vocabulary).await {
+                        error!(?e, record=%id, "processing ML extraction");
+                    }
+                    drop(permit);
+                });
             }
         }
         Ok(())
     }
 }
 
-async fn process_one(state: &mut MLQueueState, id: &RecordId) -> Result<(), Whatever> {
+impl MLQueueState {
+    /// Returns the closed vocabulary, if enabled, reloading it once stale.
+    async fn vocabulary(&mut self) -> Result<Option<Vocabulary>, Whatever> {
+        let Some(limit) = self.vocabulary_limit else {
+            return Ok(None);
+        };
+        match &self.vocabulary {
+            Some((loaded, vocabulary)) if loaded.elapsed() < VOCABULARY_REFRESH => {
+                Ok(Some(vocabulary.clone()))
+            }
+            _ => {
+                let vocabulary = load_vocabulary(&self.processor.database, limit).await?;
+                self.vocabulary = Some((Instant::now(), vocabulary.clone()));
+                Ok(Some(vocabulary))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct VocabularyRow {
+    class: Class,
+    value: String,
+    uses: u64,
+}
+
+/// Loads the most used accepted values of each class.
+async fn load_vocabulary(db: &Surreal<Db>, limit: usize) -> Result<Vocabulary, Whatever> {
+    let mut rows: Vec<VocabularyRow> = db
+        .query(
+            "SELECT out.id.class AS class, out.id.value AS value, count() AS uses FROM \
+             workshop_item_properties WHERE status = 1 GROUP BY class, value",
+        )
+        .await
+        .whatever_context("Querying vocabulary")?
+        .take(0)
+        .whatever_context("Taking vocabulary from response")?;
+    rows.sort_unstable_by(|a, b| b.uses.cmp(&a.uses).then_with(|| a.value.cmp(&b.value)));
+
+    let mut vocabulary = Vocabulary::default();
+    for row in rows {
+        let values = match row.class {
+            Class::Genre => &mut vocabulary.0.genres,
+            Class::Theme => &mut vocabulary.0.themes,
+            Class::Type => &mut vocabulary.0.types,
+            Class::Feature => &mut vocabulary.0.features,
+        };
+        if values.len() < limit {
+            values.push(row.value);
+        }
+    }
+    debug!(?vocabulary, "loaded vocabulary");
+    Ok(vocabulary)
+}
+
+async fn process_one(
+    state: &Processor,
+    id: &RecordId,
+    vocabulary: Option<Vocabulary>,
+) -> Result<(), Whatever> {
     // Load minimal fields needed
     let mut resp = state
         .database
-        .query("SELECT title, description FROM $id")
+        .query("SELECT title, plain_description || description AS description FROM $id")
         .bind(("id", id.clone()))
         .await
         .whatever_context("Querying item for ML extraction")?;
@@ -89,39 +233,60 @@ async fn process_one(state: &mut MLQueueState, id: &RecordId) -> Result<(), What
     match call!(state.extractor, |reply| ExtractionMsg::Process {
         title,
         description,
+        vocabulary: vocabulary.clone(),
         rpc_reply_port: reply
     }) {
-        Ok(Ok(props)) => {
-            info!(record=%id, props=?props, "ML extraction completed");
-
-            for (class, value) in props
-                .genres
-                .into_iter()
-                .map(|v| (Class::Genre, v))
-                .chain(props.themes.into_iter().map(|v| (Class::Theme, v)))
-                .chain(props.types.into_iter().map(|v| (Class::Type, v)))
-                .chain(props.features.into_iter().map(|v| (Class::Feature, v)))
-            {
-                match call!(state.property_actor, |reply| PropertiesMsg::NewProperty(
-                    NewProperty {
-                        workshop_item: id.key().to_string().replace("⟩", "").replace("⟨", ""),
-                        class: class.clone(),
-                        value: value.clone(),
-                        note: None,
-                    },
-                    Source::System,
-                    Status::Accepted,
-                    reply
-                )) {
-                    Ok(Ok(..)) => {
-                        debug!(%class, %value, "Inserted new property");
-                    }
-                    Ok(Err(error)) => {
-                        error!(?error,%class, %value,  "Inserting new property");
-                    }
-                    Err(_) => (),
+        Ok(Ok(Extraction {
+            properties,
+            model_id,
+            prompt_version,
+            confidence,
+        })) => {
+            info!(record=%id, props=?properties, "ML extraction completed");
+            let provenance = Provenance {
+                model: model_id,
+                prompt_version,
+                confidence: None,
+            };
+            // Reproduced suggestions are linked again below with the new version
+            retire_stale_suggestions(&state.database, id, &provenance).await?;
+            match vocabulary {
+                // Values outside the vocabulary are new suggestions, so they always
+                // wait for review.
+                Some(vocabulary) => {
+                    let canonicalised = vocabulary.canonicalise(properties);
+                    insert_properties(
+                        state,
+                        id,
+                        canonicalised.known,
+                        (state.status, state.accept_confidence),
+                        &provenance,
+                        &confidence,
+                    )
+                    .await;
+                    insert_properties(
+                        state,
+                        id,
+                        canonicalised.other,
+                        (Status::Pending, None),
+                        &provenance,
+                        &confidence,
+                    )
+                    .await;
+                }
+                None => {
+                    insert_properties(
+                        state,
+                        id,
+                        properties,
+                        (state.status, state.accept_confidence),
+                        &provenance,
+                        &confidence,
+                    )
+                    .await;
                 }
             }
+            record_extraction(&state.database, id, provenance).await?;
         }
         Ok(Err(err)) => {
             error!(record=%id, ?err, "ML extraction failed");
@@ -132,3 +297,147 @@ async fn process_one(state: &mut MLQueueState, id: &RecordId) -> Result<(), What
     }
     Ok(())
 }
+
+/// Finds items to extract again with the `current` model and prompt version,
+/// see `RequeueFilter`.
+pub async fn find_requeue(
+    db: &Surreal<Db>,
+    current: &Provenance,
+    filter: RequeueFilter,
+) -> Result<Vec<RecordId>, surrealdb::Error> {
+    let items = filter.items.map(|items| {
+        items
+            .into_iter()
+            .map(|item| ItemID::from(item).into_recordid())
+            .collect::<Vec<_>>()
+    });
+    let mut response = db
+        .query(
+            "SELECT VALUE item FROM ml_extractions WHERE ($force OR model != $model OR \
+             prompt_version != $prompt_version) AND ($prompt_version_filter = NONE OR \
+             prompt_version = $prompt_version_filter) AND ($app_id = NONE OR item.appid = \
+             $app_id) AND ($items = NONE OR item IN $items) AND item.languages CONTAINS $english;",
+        )
+        .query(
+            "SELECT VALUE id FROM workshop_items WHERE $unextracted AND ($app_id = NONE OR appid \
+             = $app_id) AND ($items = NONE OR id IN $items) AND languages CONTAINS $english AND \
+             !record::exists(type::thing('ml_extractions', record::id(id)));",
+        )
+        .bind(("force", filter.force))
+        .bind(("model", current.model.clone()))
+        .bind(("prompt_version", current.prompt_version.clone()))
+        .bind(("prompt_version_filter", filter.prompt_version))
+        .bind(("app_id", filter.app_id))
+        .bind(("items", items))
+        .bind(("english", DetectedLanguage::English))
+        .bind(("unextracted", filter.unextracted))
+        .await?
+        .check()?;
+    let mut outdated: Vec<RecordId> = response.take(0)?;
+    let unextracted: Vec<RecordId> = response.take(1)?;
+    outdated.extend(unextracted);
+    if let Some(limit) = filter.limit {
+        outdated.truncate(limit);
+    }
+    Ok(outdated)
+}
+
+/// Removes pending suggestions left by a different model or prompt version,
+/// reviewed properties are kept.
+async fn retire_stale_suggestions(
+    db: &Surreal<Db>,
+    id: &RecordId,
+    provenance: &Provenance,
+) -> Result<(), Whatever> {
+    db.query(
+        "DELETE workshop_item_properties WHERE in = $item AND source = 'system' AND status = 0 \
+         AND (model != $model OR prompt_version != $prompt_version)",
+    )
+    .bind(("item", id.clone()))
+    .bind(("model", provenance.model.clone()))
+    .bind(("prompt_version", provenance.prompt_version.clone()))
+    .await
+    .and_then(surrealdb::Response::check)
+    .whatever_context("Removing stale suggestions")?;
+    Ok(())
+}
+
+/// Records which model and prompt version last processed the item, so it can
+/// be found again when either changes.
+async fn record_extraction(
+    db: &Surreal<Db>,
+    id: &RecordId,
+    provenance: Provenance,
+) -> Result<(), Whatever> {
+    db.query(
+        "UPSERT type::thing('ml_extractions', record::id($item)) SET item = $item, model = \
+         $model, prompt_version = $prompt_version, extracted_at = time::now()",
+    )
+    .bind(("item", id.clone()))
+    .bind(("model", provenance.model))
+    .bind(("prompt_version", provenance.prompt_version))
+    .await
+    .and_then(surrealdb::Response::check)
+    .whatever_context("Recording extraction")?;
+    Ok(())
+}
+
+/// Links each property to the item, `status` is the status to give them and
+/// the confidence at or above which they're accepted instead.
+async fn insert_properties(
+    state: &Processor,
+    id: &RecordId,
+    props: MLProperties,
+    (status, accept_confidence): (Status, Option<f32>),
+    provenance: &Provenance,
+    confidence: &Confidence,
+) {
+    // The class names used by `MLProperties` and so `Confidence`
+    for (class, key, value) in props
+        .genres
+        .into_iter()
+        .map(|v| (Class::Genre, "genres", v))
+        .chain(
+            props
+                .themes
+                .into_iter()
+                .map(|v| (Class::Theme, "themes", v)),
+        )
+        .chain(props.types.into_iter().map(|v| (Class::Type, "types", v)))
+        .chain(
+            props
+                .features
+                .into_iter()
+                .map(|v| (Class::Feature, "features", v)),
+        )
+    {
+        let confidence = confidence.get(key, &value);
+        let status = match (confidence, accept_confidence) {
+            (Some(confidence), Some(threshold)) if confidence >= threshold => Status::Accepted,
+            _ => status,
+        };
+        match call!(state.property_actor, |reply| PropertiesMsg::NewProperty(
+            NewProperty {
+                workshop_item: id.key().to_string().replace("⟩", "").replace("⟨", ""),
+                class: class.clone(),
+                value: value.clone(),
+                note: None,
+            },
+            Source::System,
+            status,
+            Some(Provenance {
+                confidence,
+                ..provenance.clone()
+            }),
+            reply
+        )) {
+            Ok(Ok(..)) => {
+                debug!(%class, %value, ?confidence, ?status, "Inserted new property");
+            }
+            Ok(Err(error)) => {
+                error!(?error,%class, %value,  "Inserting new property");
+            }
+            Err(_) => (),
+        }
+    }
+}
@@ -1,4 +1,6 @@
 pub mod bb_actor;
+pub mod embedding_actor;
 pub mod join_process_actor;
 pub mod language_actor;
 pub mod ml_queue_actor;
+pub mod translations;
@@ -0,0 +1,220 @@
+use serde::Deserialize;
+use snafu::{ResultExt, Whatever};
+use surrealdb::{RecordId, Surreal, engine::local::Db};
+use tracing::debug;
+
+use crate::{db::model::WorkshopItem, processing::language_actor::DetectedLanguage};
+
+/// Words (lowercase) that mark a title as a translation or localisation
+const TITLE_PATTERNS: &[&str] = &[
+    "translation",
+    "localization",
+    "localisation",
+    "language pack",
+    "перевод",
+    "русификат",
+    "русификация",
+    "переклад",
+    "українізатор",
+    "übersetzung",
+    "traduction",
+    "tradução",
+    "traducción",
+    "tłumaczenie",
+    "spolszczenie",
+    "çeviri",
+    "překlad",
+    "汉化",
+    "翻译",
+    "简体中文",
+    "繁體中文",
+    "日本語",
+    "翻訳",
+    "한국어",
+    "번역",
+];
+
+/// Language tags often put in brackets, i.e. `[RU]` or `(PT-BR)`. `UK` is left
+/// out for meaning the United Kingdom as often as Ukrainian.
+const TITLE_TAGS: &[&str] = &[
+    "ru", "ua", "de", "fr", "es", "pt", "pt-br", "br", "pl", "tr", "cs", "cn", "zh", "ch", "jp",
+    "ja", "kr", "ko", "it",
+];
+
+/// Language names only counted as tags, mods written in a language are often
+/// titled with its name too
+const TITLE_NAMES: &[&str] = &["deutsch", "français", "español", "türkçe"];
+
+/// What's matched of an item when looking for translations
+#[derive(Deserialize, Debug)]
+pub struct ItemTitle {
+    pub id: RecordId,
+    pub title: String,
+    #[serde(default)]
+    pub primary_language: Option<DetectedLanguage>,
+}
+
+impl From<&WorkshopItem<RecordId>> for ItemTitle {
+    fn from(item: &WorkshopItem<RecordId>) -> Self {
+        ItemTitle {
+            id: item.id.clone(),
+            title: item.title.clone(),
+            primary_language: item.primary_language,
+        }
+    }
+}
+
+fn looks_like_translation(title: &str) -> bool {
+    let title = title.to_lowercase();
+    TITLE_PATTERNS.iter().any(|pattern| title.contains(pattern))
+        || title
+            .split(['[', ']', '(', ')', '{', '}'])
+            .skip(1)
+            .step_by(2)
+            .map(str::trim)
+            .any(|tag| TITLE_TAGS.contains(&tag) || TITLE_NAMES.contains(&tag))
+}
+
+/// Picks the item `item` is a translation of, if it is one. A dependency named
+/// in the title is taken when the title looks like a translation or the
+/// languages differ, otherwise a translation titled pack with a single
+/// dependency in another language is assumed to translate that.
+pub fn find_original<'a>(item: &ItemTitle, candidates: &'a [ItemTitle]) -> Option<&'a ItemTitle> {
+    let patterned = looks_like_translation(&item.title);
+    let other_language = |candidate: &&ItemTitle| {
+        matches!(
+            (item.primary_language, candidate.primary_language),
+            (Some(this), Some(that)) if this != that
+        )
+    };
+    let title = item.title.to_lowercase();
+    let named = candidates
+        .iter()
+        .filter(|candidate| {
+            let candidate = candidate.title.trim().to_lowercase();
+            // Short names match too much by accident
+            candidate.chars().count() > 3 && title.contains(&candidate) && title != candidate
+        })
+        .max_by_key(|candidate| candidate.title.len());
+    if let Some(named) = named.filter(|named| patterned || other_language(named)) {
+        return Some(named);
+    }
+    if !patterned {
+        return None;
+    }
+    let mut others = candidates.iter().filter(other_language);
+    match (others.next(), others.next()) {
+        (Some(only), None) => Some(only),
+        _ => None,
+    }
+}
+
+/// Links `item` to the item it translates, replacing any previous link.
+pub async fn link_translation(db: &Surreal<Db>, item: &ItemTitle) -> Result<(), Whatever> {
+    let candidates: Vec<ItemTitle> = db
+        .query(
+            "SELECT out AS id, out.title AS title, out.primary_language AS primary_language FROM \
+             item_dependencies WHERE in = $item AND out.title != NONE",
+        )
+        .bind(("item", item.id.clone()))
+        .await
+        .whatever_context("Querying translation candidates")?
+        .take(0)
+        .whatever_context("Taking translation candidates")?;
+    let original = find_original(item, &candidates);
+    debug!(item = %item.id, ?original, "checked for translation");
+    db.query("BEGIN TRANSACTION")
+        .query("DELETE translation_of WHERE in = $item")
+        .query(if original.is_some() {
+            "RELATE $item->translation_of->$original SET language = $language"
+        } else {
+            "RETURN NONE"
+        })
+        .query("COMMIT")
+        .bind(("item", item.id.clone()))
+        .bind(("original", original.map(|original| original.id.clone())))
+        .bind(("language", item.primary_language))
+        .await
+        .and_then(surrealdb::Response::check)
+        .whatever_context("Linking translation")?;
+    Ok(())
+}
+
+/// Links the items depending on `item` that translate it. Dependencies
+/// without a title aren't candidates, so a translation crawled before its
+/// original is only linked once the original is.
+pub async fn link_dependants(db: &Surreal<Db>, item: &ItemTitle) -> Result<(), Whatever> {
+    let dependants: Vec<ItemTitle> = db
+        .query(
+            "SELECT id, title, primary_language FROM $item<-item_dependencies<-workshop_items \
+             WHERE title != NONE AND count(->translation_of) = 0",
+        )
+        .bind(("item", item.id.clone()))
+        .await
+        .whatever_context("Querying dependants")?
+        .take(0)
+        .whatever_context("Taking dependants")?;
+    // Popular libraries have thousands of dependants, only those that could
+    // pick `item` are worth checking against all of their dependencies
+    let original = std::slice::from_ref(item);
+    for dependant in dependants
+        .iter()
+        .filter(|dependant| find_original(dependant, original).is_some())
+    {
+        link_translation(db, dependant).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use surrealdb::RecordId;
+
+    use super::{ItemTitle, find_original, looks_like_translation};
+    use crate::processing::language_actor::DetectedLanguage;
+
+    fn item(title: &str, language: DetectedLanguage) -> ItemTitle {
+        candidate("1", title, language)
+    }
+
+    fn candidate(id: &str, title: &str, language: DetectedLanguage) -> ItemTitle {
+        ItemTitle {
+            id: RecordId::from_table_key("workshop_items", id),
+            title: title.to_owned(),
+            primary_language: Some(language),
+        }
+    }
+
+    #[test]
+    fn test_patterns() {
+        assert!(looks_like_translation("Vanilla Expanded - Русский перевод"));
+        assert!(looks_like_translation("Better Farming [DE]"));
+        assert!(!looks_like_translation("Better Farming"));
+        assert!(!looks_like_translation("Dead Man's Switch (Continued)"));
+        assert!(looks_like_translation("Better Farming (Deutsch)"));
+        // Mods written in a language are titled in it too
+        assert!(!looks_like_translation("Deutsch Waffen Pack"));
+        assert!(!looks_like_translation("Türkçe Yemekler"));
+        assert!(!looks_like_translation("Royal Guard [UK]"));
+    }
+
+    #[test]
+    fn test_find_original() {
+        let candidates = [
+            candidate("2", "Harmony", DetectedLanguage::English),
+            candidate("3", "Better Farming", DetectedLanguage::English),
+        ];
+        let original = find_original(
+            &item(
+                "Better Farming - Русский перевод",
+                DetectedLanguage::Russian,
+            ),
+            &candidates,
+        );
+        assert_eq!(original.map(|c| c.title.as_str()), Some("Better Farming"));
+
+        // Depending on a mod isn't enough on its own
+        let addon = item("Better Farming Extras", DetectedLanguage::English);
+        assert!(find_original(&addon, &candidates).is_none());
+    }
+}
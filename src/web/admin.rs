@@ -1,16 +1,26 @@
+use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
 use salvo::{
     Depot, Response, Writer, handler,
-    oapi::{ToSchema, extract::JsonBody},
+    oapi::{
+        ToSchema,
+        extract::{JsonBody, QueryParam},
+    },
     prelude::{Json, endpoint},
 };
 use serde::{Deserialize, Serialize};
-use surrealdb::{Surreal, engine::local::Db};
-use tracing::error;
+use surrealdb::{RecordId, Surreal, engine::local::Db};
+use tracing::{error, info};
 
-use crate::db::{
-    ItemID, UserID,
-    model::{Property, Status, User, WorkshopItemProperties},
+use crate::{
+    db::{
+        ItemID, UserID,
+        model::{Class, CompanionKind, Property, RoleGrant, Status, User, WorkshopItemProperties},
+    },
+    processing::ml_queue_actor::{
+        CURRENT_EXTRACTION, ML_QUEUE_ACTOR, MLQueueMsg, RequeueFilter, find_requeue,
+    },
+    web::auth::{self, Grant, Permission, Revocation},
 };
 
 #[endpoint]
@@ -34,55 +44,123 @@ pub async fn get_users(depot: &mut Depot, response: &mut Response) {
 
 #[endpoint]
 pub async fn patch_user(data: JsonBody<PatchUser>, depot: &mut Depot, response: &mut Response) {
-    let id = UserID::from(data.0.id);
+    let user = UserID::from(data.0.id.clone()).into_recordid();
+    let db = depot.obtain::<Surreal<Db>>().expect("getting shared state");
     if let Some(banned) = data.0.banned {
-        let res = depot
-            .obtain::<Surreal<Db>>()
-            .expect("getting shared state")
-            .query("UPDATE $user SET banned=$banned")
-            .bind(("user", id.clone()))
+        // Lifting a ban clears the reason and expiry along with it
+        let res = db
+            .query("UPDATE $user SET banned=$banned, ban_reason=$reason, banned_until=$until")
+            .bind(("user", user.clone()))
             .bind(("banned", banned))
-            .await;
+            .bind(("reason", data.0.ban_reason.clone().filter(|_| banned)))
+            .bind((
+                "until",
+                data.0
+                    .banned_until
+                    .filter(|_| banned)
+                    .map(surrealdb::sql::Datetime::from),
+            ))
+            .await
+            .and_then(surrealdb::Response::check);
         if let Err(e) = res {
             error!("{e:?}");
             response.status_code(StatusCode::INTERNAL_SERVER_ERROR);
             return;
         }
+        // Otherwise a stolen or existing session would keep working
+        if banned {
+            if let Err(e) = auth::revoke(data.0.id.clone(), Revocation::Everything).await {
+                error!("{e:?}");
+                response.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                return;
+            }
+        }
+        if banned && data.0.rollback {
+            if let Err(e) = rollback_contributions(db, user.clone()).await {
+                error!("{e:?}");
+                response.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                return;
+            }
+        }
     }
 
-    if let Some(admin) = data.0.admin {
-        let res = depot
-            .obtain::<Surreal<Db>>()
-            .expect("getting shared state")
-            .query("UPDATE $user SET $admin=admin")
-            .bind(("user", id))
-            .bind(("admin", admin))
-            .await;
+    if data.0.admin.is_some() || data.0.roles.is_some() {
+        let res = db
+            .query("UPDATE $user SET admin=$admin ?? admin, roles=$roles ?? roles")
+            .bind(("user", user))
+            .bind(("admin", data.0.admin))
+            .bind(("roles", data.0.roles.clone()))
+            .await
+            .and_then(surrealdb::Response::check);
         if let Err(e) = res {
             error!("{e:?}");
             response.status_code(StatusCode::INTERNAL_SERVER_ERROR);
             return;
         }
+        // Roles are minted into sessions, logging in again picks up the change
+        if let Err(e) = auth::revoke(data.0.id.clone(), Revocation::Sessions).await {
+            error!("{e:?}");
+            response.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            return;
+        }
     }
     response.status_code(StatusCode::NO_CONTENT);
 }
 
+/// Rejects `user`'s pending properties and removes their votes, undoing
+/// what the votes added to each property's counts.
+async fn rollback_contributions(db: &Surreal<Db>, user: RecordId) -> surrealdb::Result<()> {
+    db.query("BEGIN TRANSACTION")
+        .query("LET $votes = DELETE votes WHERE id.user = $user RETURN BEFORE")
+        .query(
+            "FOR $vote IN $votes { UPDATE workshop_item_properties SET vote_count = \
+             math::max([vote_count - 1, 0]), upvote_count -= $vote.score, weighted_score -= \
+             $vote.score * $vote.weight WHERE in = $vote.id.item AND out = $vote.id.link; }",
+        )
+        .query(
+            "UPDATE workshop_item_properties SET status = -1 WHERE source = $user AND status = 0",
+        )
+        .query("COMMIT TRANSACTION")
+        .bind(("user", user))
+        .await
+        .and_then(surrealdb::Response::check)?;
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct PatchUser {
     pub id: String,
     pub banned: Option<bool>,
+    /// Shown to the user, only kept while banned
+    pub ban_reason: Option<String>,
+    /// Makes the ban temporary, lifting at this time
+    pub banned_until: Option<DateTime<Utc>>,
+    /// When banning, also reject the user's pending properties and remove their
+    /// votes
+    #[serde(default)]
+    pub rollback: bool,
     pub admin: Option<bool>,
+    /// Replaces the user's roles
+    pub roles: Option<Vec<RoleGrant>>,
+}
+
+/// The apps the current user may act on with `permission`, `None` for all.
+fn scoped_apps(depot: &mut Depot, permission: Permission) -> Option<Vec<i64>> {
+    auth::grant_from_depot(depot, permission).map_or_else(|| Some(vec![]), Grant::apps)
 }
 
 #[endpoint]
 pub async fn get_workshop_item_properties(depot: &mut Depot, response: &mut Response) {
+    let apps = scoped_apps(depot, Permission::ModerateProperties);
     match depot
         .obtain::<Surreal<Db>>()
         .expect("getting shared state")
         .query(
             "SELECT record::id(in) as in, out.*.id.{class,value} as out, source.to_string(), \
-             id.to_string(), * FROM workshop_item_properties",
+             id.to_string(), * FROM workshop_item_properties WHERE $apps = NONE OR in.appid IN \
+             $apps",
         )
+        .bind(("apps", apps))
         .await
         .map(|mut q| q.take(0))
     {
@@ -104,14 +182,18 @@ pub async fn patch_workshop_item_properties(
     depot: &mut Depot,
     response: &mut Response,
 ) {
+    let apps = scoped_apps(depot, Permission::ModerateProperties);
     let res = depot
         .obtain::<Surreal<Db>>()
         .expect("getting shared state")
         .query("LET $link = properties:{class: $class, value: $value}")
         .query(
-            "UPDATE ONLY workshop_item_properties SET status=$status WHERE in = $item AND out = \
-             $link;",
+            "UPDATE ONLY workshop_item_properties SET status=$status, decided_by=IF $status = 0 { \
+             NONE } ELSE { 'moderator' } WHERE in = $item AND out = $link AND ($apps = NONE OR \
+             in.appid IN $apps);",
         )
+        .query("fn::recalculate_reputation($item, $link)")
+        .bind(("apps", apps))
         .bind(("class", data.0.property.class))
         .bind(("value", data.0.property.value))
         .bind(("item", ItemID::from(data.0.item).into_recordid()))
@@ -132,3 +214,224 @@ pub struct PatchRelationship {
     pub property: Property,
     pub status: Status,
 }
+
+/// Lists machine generated properties that are waiting for review, along with
+/// the model and prompt that produced them. The most confident come first and
+/// can be limited to a range of confidence.
+#[endpoint]
+pub async fn get_pending_ml_properties(
+    min_confidence: QueryParam<f32, false>,
+    max_confidence: QueryParam<f32, false>,
+    depot: &mut Depot,
+    response: &mut Response,
+) {
+    let apps = scoped_apps(depot, Permission::ModerateProperties);
+    match depot
+        .obtain::<Surreal<Db>>()
+        .expect("getting shared state")
+        .query(
+            "SELECT record::id(in) as in, out.*.id.{class,value} as out, source.to_string(), \
+             id.to_string(), * FROM workshop_item_properties WHERE source = 'system' AND status = \
+             0 AND ($min = NONE OR confidence >= $min) AND ($max = NONE OR confidence <= $max) \
+             AND ($apps = NONE OR in.appid IN $apps) ORDER BY confidence DESC",
+        )
+        .bind(("apps", apps))
+        .bind(("min", min_confidence.into_inner()))
+        .bind(("max", max_confidence.into_inner()))
+        .await
+        .map(|mut q| q.take(0))
+    {
+        Ok(Ok(results)) => {
+            response.render(Json::<Vec<WorkshopItemProperties<String, Property>>>(
+                results,
+            ));
+        }
+        Ok(Err(e)) | Err(e) => {
+            error!("{e:?}");
+            response.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+}
+
+/// Accepts or rejects machine generated properties in bulk.
+#[endpoint]
+pub async fn review_ml_properties(
+    data: JsonBody<ReviewProperties>,
+    depot: &mut Depot,
+    response: &mut Response,
+) {
+    let targets = data
+        .0
+        .properties
+        .into_iter()
+        .map(|target| ReviewBinding {
+            item: ItemID::from(target.item).into_recordid(),
+            class: target.property.class,
+            value: target.property.value,
+        })
+        .collect::<Vec<_>>();
+    let apps = scoped_apps(depot, Permission::ModerateProperties);
+    let res = depot
+        .obtain::<Surreal<Db>>()
+        .expect("getting shared state")
+        .query(
+            "FOR $target IN $targets { LET $link = type::thing('properties', {class: \
+             $target.class, value: $target.value}); UPDATE workshop_item_properties SET \
+             status=$status, decided_by=IF $status = 0 { NONE } ELSE { 'moderator' } WHERE in = \
+             $target.item AND out = $link AND source = 'system' AND ($apps = NONE OR in.appid IN \
+             $apps); fn::recalculate_reputation($target.item, $link); };",
+        )
+        .bind(("targets", targets))
+        .bind(("apps", apps))
+        .bind(("status", data.0.status))
+        .await
+        .map(surrealdb::Response::check);
+    if let Err(e) | Ok(Err(e)) = res {
+        error!("{e:?}");
+        response.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        return;
+    }
+    response.status_code(StatusCode::NO_CONTENT);
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ReviewProperties {
+    pub properties: Vec<ReviewTarget>,
+    pub status: Status,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ReviewTarget {
+    pub item: String,
+    #[serde(flatten)]
+    pub property: Property,
+}
+
+#[derive(Serialize)]
+struct ReviewBinding {
+    item: RecordId,
+    class: Class,
+    value: String,
+}
+
+/// Queues items for ML extraction again, by default those extracted by a model
+/// or prompt version other than the current one. Responds with the number of
+/// items queued.
+#[endpoint]
+pub async fn requeue_ml_extraction(
+    data: JsonBody<RequeueFilter>,
+    depot: &mut Depot,
+    response: &mut Response,
+) {
+    let (Some(actor), Some(current)) = (ML_QUEUE_ACTOR.get(), CURRENT_EXTRACTION.get()) else {
+        response.status_code(StatusCode::SERVICE_UNAVAILABLE);
+        return;
+    };
+    // Found here rather than by the queue, which can be busy for a long while
+    let db = depot.obtain::<Surreal<Db>>().expect("getting shared state");
+    match find_requeue(db, current, data.0).await {
+        Ok(items) => {
+            let queued = items.len();
+            for id in items {
+                let _ = actor.cast(MLQueueMsg::Process(id));
+            }
+            info!(queued, "requeued items for ML extraction");
+            response.render(Json(Requeued { queued }));
+        }
+        Err(e) => {
+            error!("{e:?}");
+            response.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct Requeued {
+    pub queued: usize,
+}
+
+/// Lists companions found in descriptions that are waiting for review.
+#[endpoint]
+pub async fn get_pending_companions(depot: &mut Depot, response: &mut Response) {
+    let apps = scoped_apps(depot, Permission::ReviewCompanions);
+    match depot
+        .obtain::<Surreal<Db>>()
+        .expect("getting shared state")
+        .query(
+            "SELECT record::id(in) AS item, in.title AS item_title, record::id(out) AS companion, \
+             out.title AS companion_title, kind FROM companions WHERE status = 0 AND kind = \
+             'mentioned' AND ($apps = NONE OR in.appid IN $apps)",
+        )
+        .bind(("apps", apps))
+        .await
+        .map(|mut q| q.take(0))
+    {
+        Ok(Ok(results)) => {
+            response.render(Json::<Vec<PendingCompanion>>(results));
+        }
+        Ok(Err(e)) | Err(e) => {
+            error!("{e:?}");
+            response.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct PendingCompanion {
+    pub item: String,
+    pub item_title: Option<String>,
+    pub companion: String,
+    pub companion_title: Option<String>,
+    pub kind: CompanionKind,
+}
+
+/// Accepts or rejects companions in bulk.
+#[endpoint]
+pub async fn review_companions(
+    data: JsonBody<ReviewCompanions>,
+    depot: &mut Depot,
+    response: &mut Response,
+) {
+    let targets = data
+        .0
+        .companions
+        .into_iter()
+        .map(|target| {
+            (
+                ItemID::from(target.item).into_recordid(),
+                ItemID::from(target.companion).into_recordid(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let apps = scoped_apps(depot, Permission::ReviewCompanions);
+    let res = depot
+        .obtain::<Surreal<Db>>()
+        .expect("getting shared state")
+        .query(
+            "FOR $target IN $targets { UPDATE companions SET status=$status WHERE in = $target[0] \
+             AND out = $target[1] AND ($apps = NONE OR in.appid IN $apps); };",
+        )
+        .bind(("targets", targets))
+        .bind(("apps", apps))
+        .bind(("status", data.0.status))
+        .await
+        .map(surrealdb::Response::check);
+    if let Err(e) | Ok(Err(e)) = res {
+        error!("{e:?}");
+        response.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        return;
+    }
+    response.status_code(StatusCode::NO_CONTENT);
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ReviewCompanions {
+    pub companions: Vec<CompanionTarget>,
+    pub status: Status,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct CompanionTarget {
+    pub item: String,
+    pub companion: String,
+}
@@ -1,56 +1,59 @@
 use std::{
+    collections::HashSet,
+    fmt::Write,
     mem,
-    str::FromStr,
     sync::{Arc, OnceLock},
     time::SystemTime,
 };
 
 use biscuit_auth::{
-    Authorizer, Biscuit, KeyPair,
+    Authorizer, Biscuit,
+    builder::Fact,
     builder_ext::AuthorizerExt,
-    macros::{authorizer, biscuit},
+    macros::{authorizer, biscuit, block, fact},
 };
-use chrono::{NaiveDateTime, TimeDelta, Utc};
+use chrono::{DateTime, Utc};
 use multimap::MultiMap;
 use ractor::{Actor, ActorProcessingErr, ActorRef, RpcReplyPort, async_trait, call};
 use reqwest::{Client, Url};
 use salvo::{
     Depot, Request, Response,
     http::{
-        HeaderValue,
+        HeaderValue, Method,
         cookie::{Cookie, SameSite, time::Duration},
+        header::AUTHORIZATION,
     },
-    prelude::{Redirect, StatusCode, StatusError, endpoint},
+    prelude::{Redirect, StatusCode, StatusError, endpoint, handler},
 };
-use serde::{Deserialize, Serialize};
-use serde_xml_rs::from_str;
+use serde::Deserialize;
 use snafu::{ErrorCompat, prelude::*};
 use surrealdb::{
-    Surreal,
+    RecordId, Surreal,
     engine::local::Db,
-    sql::{Data, Operator, Value, statements::InsertStatement, to_value},
+    sql::{Data, Datetime, Operator, Value, statements::InsertStatement, to_value},
     syn::idiom,
 };
 use tracing::{debug, error};
 
+use self::identity::IdentityProvider;
 use crate::{
-    app_config::BiscuitConfig,
-    db::{UserID, model::User},
+    app_config::{BiscuitConfig, Config, IdentityConfig},
+    db::{
+        UserID,
+        model::{Privacy, Role, RoleGrant, User},
+    },
+    web::tokens::TokenScope,
 };
 
+mod identity;
+
 static AUTH_ACTOR: OnceLock<ActorRef<AuthMessage>> = OnceLock::new();
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub type Error = StatusError;
-const STEAM_DISCOVERY: &str = "https://steamcommunity.com/openid/";
 
 const TOKEN_LIFETIME: Duration = Duration::days(30);
 
-struct Info {
-    r#type: String,
-    uri: String,
-}
-
 #[derive(Debug, Snafu)]
 #[non_exhaustive]
 #[snafu(visibility(pub(crate)))]
@@ -63,16 +66,26 @@ enum InnerError {
     BuildingURI,
     SelfValidationFailed,
     PeerValidationFailed,
+    /// The response's nonce has already been used
+    ReplayedResponse,
+    /// Only locations under the base URL can be redirected to
+    InvalidRedirect,
     InternalError,
     Unauthorized,
+    Forbidden,
+    #[snafu(display("Banned: {reason}"))]
+    Banned {
+        reason: String,
+    },
 }
 
 impl InnerError {
     pub fn status_code(&self) -> StatusCode {
         match self {
-            InnerError::SelfValidationFailed | InnerError::PeerValidationFailed => {
-                StatusCode::FORBIDDEN
-            }
+            InnerError::SelfValidationFailed
+            | InnerError::PeerValidationFailed
+            | InnerError::ReplayedResponse => StatusCode::FORBIDDEN,
+            InnerError::InvalidRedirect => StatusCode::BAD_REQUEST,
             InnerError::QueryingDiscovery
             | InnerError::DiscoveryBadResponse
             | InnerError::DeserializingDiscovery
@@ -81,6 +94,7 @@ impl InnerError {
             | InnerError::BuildingURI
             | InnerError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
             InnerError::Unauthorized => StatusCode::UNAUTHORIZED,
+            InnerError::Forbidden | InnerError::Banned { .. } => StatusCode::FORBIDDEN,
         }
     }
 }
@@ -100,37 +114,50 @@ impl From<InnerError> for StatusError {
     }
 }
 
+/// Sends the user to the identity provider to log in
 #[endpoint]
-pub async fn redirect_to_steam_auth(req: &mut Request, resp: &mut Response) -> Result<()> {
-    let location = req
-        .query::<String>("location")
-        .ok_or(InnerError::SelfValidationFailed)?;
+pub async fn redirect_to_login(
+    req: &mut Request,
+    depot: &mut Depot,
+    resp: &mut Response,
+) -> Result<()> {
+    let location = local_redirect(
+        depot_base_url(depot),
+        req.query::<&str>("location")
+            .ok_or(InnerError::SelfValidationFailed)?,
+    )?;
     let actor = AUTH_ACTOR
         .get()
         .cloned()
         .ok_or(InnerError::InternalError)
         .inspect_err(|e| error!(?e, "{}:{}", file!(), line!()))?;
-    let steam_auth_url = call!(actor, AuthMessage::GetAuthUrl, location)
+    let login_url = call!(actor, AuthMessage::GetAuthUrl, location)
         .map_err(|_| InnerError::InternalError)
-        .inspect_err(|e| error!(?e, "{}:{}", file!(), line!()))?;
+        .inspect_err(|e| error!(?e, "{}:{}", file!(), line!()))??;
 
-    resp.render(Redirect::found(steam_auth_url));
+    resp.render(Redirect::found(login_url));
 
     Ok(())
 }
 
+/// Where the identity provider sends the user back to, setting the session
+/// cookie once verified.
 #[endpoint]
-pub async fn verify_token_from_steam(req: &mut Request, response: &mut Response) -> Result<()> {
+pub async fn verify_login(
+    req: &mut Request,
+    depot: &mut Depot,
+    response: &mut Response,
+) -> Result<()> {
     // Pull this out first because it'll likely be gone after the take.
-    let redirect_to = req
-        .query::<String>("location")
-        .ok_or(InnerError::SelfValidationFailed)?;
+    let redirect_to = local_redirect(
+        depot_base_url(depot),
+        req.query::<&str>("location")
+            .ok_or(InnerError::SelfValidationFailed)?,
+    )?;
     let actor = AUTH_ACTOR.get().cloned().ok_or(InnerError::InternalError)?;
     let map = mem::take(req.queries_mut());
-    let token = call!(actor, |reply| {
-        AuthMessage::VerifySteamResponse(map, reply)
-    })
-    .map_err(|_| InnerError::InternalError)?;
+    let token = call!(actor, |reply| { AuthMessage::VerifyResponse(map, reply) })
+        .map_err(|_| InnerError::InternalError)??;
 
     response.add_cookie(
         Cookie::build(("token", token))
@@ -158,103 +185,346 @@ pub async fn verify_token_from_steam(req: &mut Request, response: &mut Response)
 /// Instructs the client to clear the cookies for the site, functioning as
 /// logout. Done here because JS can't access the tokens we use.
 #[endpoint]
-pub async fn invalidate(req: &mut Request, response: &mut Response) -> Result<()> {
+pub async fn invalidate(
+    req: &mut Request,
+    depot: &mut Depot,
+    response: &mut Response,
+) -> Result<()> {
+    let redirect_to = local_redirect(
+        depot_base_url(depot),
+        req.query::<&str>("location")
+            .ok_or(InnerError::SelfValidationFailed)?,
+    )?;
     response
         .headers
         .insert("Clear-Site-Data", HeaderValue::from_static("\"cookies\""));
-    let redirect_to = req
-        .query::<&str>("location")
-        .ok_or(InnerError::SelfValidationFailed)?;
     response.render(Redirect::found(redirect_to));
     Ok(())
 }
 
+fn depot_base_url(depot: &Depot) -> &str {
+    depot
+        .obtain::<Arc<Config>>()
+        .expect("getting shared state")
+        .base_url
+        .as_str()
+}
+
+/// Resolves `location` against `base_url`, only allowing it to go somewhere
+/// under `base_url` so logging in can't be used to send users off-site.
+fn local_redirect(base_url: &str, location: &str) -> Result<String, InnerError> {
+    let mut base = Url::parse(base_url).map_err(|_| InnerError::BuildingURI)?;
+    if !base.path().ends_with('/') {
+        // Otherwise joining would replace the last segment
+        let path = format!("{}/", base.path());
+        base.set_path(&path);
+    }
+    let target = base
+        .join(location)
+        .map_err(|_| InnerError::InvalidRedirect)?;
+    if target.origin() != base.origin()
+        || !target.path().starts_with(base.path())
+        || !target.username().is_empty()
+        || target.password().is_some()
+    {
+        return Err(InnerError::InvalidRedirect);
+    }
+    Ok(target.into())
+}
+
+/// Logs the current user out of every session by revoking their session
+/// biscuits, API tokens are left alone. Clears this client's cookies too.
 #[endpoint]
-pub async fn validate_biscuit_token(req: &mut Request, depot: &mut Depot) -> Result<()> {
-    match req.cookie("token") {
-        None => Err(InnerError::Unauthorized)?,
-        Some(token) => {
-            let actor = AUTH_ACTOR.get().cloned().ok_or(InnerError::InternalError)?;
-            let authorizer = call!(actor, |reply| {
-                AuthMessage::ValidateToken(token.clone(), reply)
-            })
-            .map_err(|_| InnerError::InternalError)??;
+pub async fn logout_everywhere(depot: &mut Depot, response: &mut Response) -> Result<()> {
+    let user_id = get_user_from_depot(depot).ok_or(InnerError::Unauthorized)?;
+    revoke(user_id, Revocation::Sessions).await?;
+    response
+        .headers
+        .insert("Clear-Site-Data", HeaderValue::from_static("\"cookies\""));
+    response.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+/// What a request does, API tokens can be limited to some of these
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Vote,
+    Write,
+    /// Minting, listing or revoking API tokens, exporting the user's data or
+    /// deleting their account, only sessions may
+    ManageTokens,
+}
 
-            depot.inject::<Authorizer>(authorizer);
-            Ok(())
+impl Operation {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Operation::Read => "read",
+            Operation::Vote => "vote",
+            Operation::Write => "write",
+            Operation::ManageTokens => "manage_tokens",
         }
     }
 }
 
-#[endpoint]
-pub async fn enforce_admin(depot: &mut Depot) -> Result<()> {
-    match get_user_from_depot(depot) {
-        None => Err(InnerError::Unauthorized)?,
-        Some(userid) => {
-            let actor = AUTH_ACTOR.get().cloned().ok_or(InnerError::InternalError)?;
-            let admin = call!(actor, |reply| { AuthMessage::IsAdmin(userid, reply) })
-                .map_err(|_| InnerError::InternalError)??;
-
-            if admin {
-                Ok(())
-            } else {
-                Err(InnerError::Unauthorized)?
-            }
+/// The biscuit sent with a request, API tokens are given as `Authorization:
+/// Bearer <token>` and sessions as the `token` cookie.
+fn request_token(req: &Request) -> Option<String> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned())
+        .or_else(|| req.cookie("token").map(|cookie| cookie.value().to_owned()))
+}
+
+/// Validates the request's biscuit for the operation its route performs,
+/// routes set the operation so API token scopes don't depend on paths.
+#[derive(Debug, Clone, Copy)]
+pub struct Authenticate {
+    reads: Operation,
+    writes: Operation,
+}
+
+/// GET and HEAD requests are reads, everything else is `operation`.
+pub fn authenticate(operation: Operation) -> Authenticate {
+    Authenticate {
+        reads: Operation::Read,
+        writes: operation,
+    }
+}
+
+/// Every request is `operation`, e.g. listing API tokens still manages them.
+pub fn authenticate_as(operation: Operation) -> Authenticate {
+    Authenticate {
+        reads: operation,
+        writes: operation,
+    }
+}
+
+#[handler]
+impl Authenticate {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot) -> Result<()> {
+        let operation = if matches!(*req.method(), Method::GET | Method::HEAD) {
+            self.reads
+        } else {
+            self.writes
+        };
+        validate(req, depot, operation).await
+    }
+}
+
+async fn validate(req: &Request, depot: &mut Depot, operation: Operation) -> Result<()> {
+    let token = request_token(req).ok_or(InnerError::Unauthorized)?;
+    let actor = AUTH_ACTOR.get().cloned().ok_or(InnerError::InternalError)?;
+    let authorizer = call!(actor, |reply| {
+        AuthMessage::ValidateToken(token, operation, reply)
+    })
+    .map_err(|_| InnerError::InternalError)??;
+
+    depot.inject::<Authorizer>(authorizer);
+    Ok(())
+}
+
+/// Revokes some or all of `user_id`'s biscuits, they stop validating
+/// immediately. Returns how many were revoked.
+pub async fn revoke(user_id: String, revocation: Revocation) -> Result<usize> {
+    let actor = AUTH_ACTOR.get().cloned().ok_or(InnerError::InternalError)?;
+    call!(actor, |reply| {
+        AuthMessage::Revoke {
+            user_id,
+            revocation,
+            reply,
+        }
+    })
+    .map_err(|_| InnerError::InternalError)?
+}
+
+/// Mints a biscuit for the API token `token_id`, limited to `scope` and
+/// expiring at `expires`.
+pub async fn mint_api_token(
+    user_id: String,
+    token_id: String,
+    scope: TokenScope,
+    expires: SystemTime,
+) -> Result<String> {
+    let actor = AUTH_ACTOR.get().cloned().ok_or(InnerError::InternalError)?;
+    call!(actor, |reply| {
+        AuthMessage::MintApiToken {
+            user_id,
+            token_id,
+            scope,
+            expires,
+            reply,
+        }
+    })
+    .map_err(|_| InnerError::InternalError)?
+}
+
+/// What a route can require, each is allowed by some roles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    ReviewCompanions,
+    ModerateProperties,
+    ManageUsers,
+}
+
+impl Permission {
+    fn roles(self) -> &'static [Role] {
+        match self {
+            Permission::ReviewCompanions => &[Role::Curator, Role::Moderator, Role::Admin],
+            Permission::ModerateProperties => &[Role::Moderator, Role::Admin],
+            Permission::ManageUsers => &[Role::Admin],
+        }
+    }
+
+    /// Whether a role held for a single app is enough
+    fn app_scoped(self) -> bool {
+        self != Permission::ManageUsers
+    }
+}
+
+/// Where the current user holds a permission
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Grant {
+    Everywhere,
+    Apps(Vec<i64>),
+}
+
+impl Grant {
+    /// The apps to limit queries to, `None` when unlimited
+    pub fn apps(self) -> Option<Vec<i64>> {
+        match self {
+            Grant::Everywhere => None,
+            Grant::Apps(apps) => Some(apps),
         }
     }
 }
+
+/// Checks the role facts minted into the current user's biscuit for
+/// `permission`.
+pub fn grant_from_depot(depot: &mut Depot, permission: Permission) -> Option<Grant> {
+    let authorizer = depot.obtain_mut::<Authorizer>().ok()?;
+    let roles = format!(
+        "[{}]",
+        permission
+            .roles()
+            .iter()
+            .map(|role| format!(r#""{}""#, role.as_str()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let global: Vec<(String,)> = authorizer
+        .query(format!("global($role) <- role($role), {roles}.contains($role)").as_str())
+        .ok()?;
+    if !global.is_empty() {
+        return Some(Grant::Everywhere);
+    }
+    if !permission.app_scoped() {
+        return None;
+    }
+    let apps: Vec<(i64,)> = authorizer
+        .query(format!("apps($app) <- app_role($role, $app), {roles}.contains($role)").as_str())
+        .ok()?;
+    if apps.is_empty() {
+        None
+    } else {
+        Some(Grant::Apps(apps.into_iter().map(|(app,)| app).collect()))
+    }
+}
+
+fn enforce(depot: &mut Depot, permission: Permission) -> Result<()> {
+    match grant_from_depot(depot, permission) {
+        Some(_) => Ok(()),
+        None if get_user_from_depot(depot).is_some() => Err(InnerError::Forbidden)?,
+        None => Err(InnerError::Unauthorized)?,
+    }
+}
+
+#[endpoint]
+pub async fn enforce_admin(depot: &mut Depot) -> Result<()> {
+    enforce(depot, Permission::ManageUsers)
+}
+
+#[endpoint]
+pub async fn enforce_moderator(depot: &mut Depot) -> Result<()> {
+    enforce(depot, Permission::ModerateProperties)
+}
+
+#[endpoint]
+pub async fn enforce_curator(depot: &mut Depot) -> Result<()> {
+    enforce(depot, Permission::ReviewCompanions)
+}
 #[endpoint]
 pub async fn validate_opt(req: &mut Request, depot: &mut Depot) -> Result<()> {
-    if req.cookie("token").is_some() {
-        validate_biscuit_token::validate_biscuit_token(req, depot).await?;
+    if request_token(req).is_some() {
+        validate(req, depot, Operation::Read).await?;
     }
     Ok(())
 }
 /// Returns the user id of the current user, if any.
 pub fn get_user_from_depot(depot: &mut Depot) -> Option<String> {
-    let authorizer = depot.obtain_mut::<Authorizer>().ok()?;
+    user_of(depot.obtain_mut::<Authorizer>().ok()?)
+}
+
+fn user_of(authorizer: &mut Authorizer) -> Option<String> {
     let (userid, _): (String, i64) = authorizer
         .query_exactly_one("data($user, 0) <- user($user)")
         .ok()?;
     Some(userid)
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-struct Xrds {
-    #[serde(rename = "XRD")]
-    xrd: Xrd,
+/// A ban that is in effect
+#[derive(Debug, Deserialize)]
+struct Ban {
+    reason: Option<String>,
+    until: Option<DateTime<Utc>>,
 }
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-struct Xrd {
-    #[serde(rename = "Service")]
-    service: Service,
-}
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-struct Service {
-    #[serde(rename = "Type")]
-    r#type: String,
-    #[serde(rename = "URI")]
-    uri: String,
+
+impl From<Ban> for InnerError {
+    fn from(ban: Ban) -> Self {
+        let reason = ban.reason.unwrap_or_else(|| "no reason given".to_owned());
+        InnerError::Banned {
+            reason: match ban.until {
+                Some(until) => format!("{reason}, until {}", until.to_rfc3339()),
+                None => reason,
+            },
+        }
+    }
 }
 
 pub struct AuthActor {}
 pub enum AuthMessage {
-    GetAuthUrl(String, RpcReplyPort<String>),
-    VerifySteamResponse(MultiMap<String, String>, RpcReplyPort<String>),
-    ValidateToken(Cookie<'static>, RpcReplyPort<Result<Authorizer>>),
-    IsAdmin(String, RpcReplyPort<Result<bool>>),
+    GetAuthUrl(String, RpcReplyPort<Result<String>>),
+    VerifyResponse(MultiMap<String, String>, RpcReplyPort<Result<String>>),
+    ValidateToken(String, Operation, RpcReplyPort<Result<Authorizer>>),
+    MintApiToken {
+        user_id: String,
+        token_id: String,
+        scope: TokenScope,
+        expires: SystemTime,
+        reply: RpcReplyPort<Result<String>>,
+    },
+    Revoke {
+        user_id: String,
+        revocation: Revocation,
+        reply: RpcReplyPort<Result<usize>>,
+    },
 }
 pub struct AuthState {
-    open_id_info: Info,
+    provider: Box<dyn IdentityProvider>,
     database: Surreal<Db>,
     base_url: Arc<String>,
     biscuit: Arc<BiscuitConfig>,
+    /// Hex encoded revocation IDs of biscuits that haven't expired yet
+    revoked: HashSet<String>,
 }
 pub struct AuthArgs {
     pub database: Surreal<Db>,
     pub client: Client,
     pub base_url: Arc<String>,
     pub biscuit: Arc<BiscuitConfig>,
+    pub identity: IdentityConfig,
 }
 #[async_trait]
 impl Actor for AuthActor {
@@ -267,9 +537,14 @@ impl Actor for AuthActor {
         myself: ActorRef<Self::Msg>,
         args: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
-        AUTH_ACTOR.get_or_init(|| myself);
+        // Endpoints use the named actor, unnamed ones belong to whoever spawned them
+        if myself.get_name().is_some() {
+            AUTH_ACTOR.get_or_init(|| myself);
+        }
         Ok(Self::State {
-            open_id_info: AuthActor::discover_openid_info(&args.client).await?,
+            provider: identity::from_config(&args.identity, &args.client, args.base_url.clone())
+                .await?,
+            revoked: AuthActor::load_revoked(&args.database).await?,
             database: args.database,
             base_url: args.base_url.clone(),
             biscuit: args.biscuit.clone(),
@@ -286,37 +561,54 @@ impl Actor for AuthActor {
         match message {
             AuthMessage::GetAuthUrl(location, reply_port) => {
                 if reply_port
-                    .send(AuthActor::get_auth_url(state, &location)?)
+                    .send(AuthActor::get_auth_url(state, &location))
                     .is_err()
                 {
                     error!(message = "GetAuthUrl", "Failed to reply to message");
                 }
             }
-            AuthMessage::VerifySteamResponse(map, reply_port) => {
+            AuthMessage::VerifyResponse(map, reply_port) => {
                 if reply_port
-                    .send(AuthActor::verify_steam_response(map, state).await?)
+                    .send(AuthActor::verify_response(map, state).await)
                     .is_err()
                 {
-                    error!(
-                        message = "VerifySteamResponse",
-                        "Failed to reply to message"
-                    );
+                    error!(message = "VerifyResponse", "Failed to reply to message");
                 }
             }
-            AuthMessage::ValidateToken(cookie, reply_port) => {
+            AuthMessage::ValidateToken(token, operation, reply_port) => {
                 if reply_port
-                    .send(AuthActor::validate_cookie(&state.biscuit, cookie.value()))
+                    .send(AuthActor::validate_token(state, &token, operation).await)
                     .is_err()
                 {
                     error!(message = "ValidateToken", "Failed to reply to message");
                 }
             }
-            AuthMessage::IsAdmin(userid, reply_port) => {
-                if reply_port
-                    .send(AuthActor::is_admin(&state.database, userid).await)
+            AuthMessage::MintApiToken {
+                user_id,
+                token_id,
+                scope,
+                expires,
+                reply,
+            } => {
+                if reply
+                    .send(
+                        AuthActor::mint_api_token(state, &user_id, &token_id, scope, expires).await,
+                    )
+                    .is_err()
+                {
+                    error!(message = "MintApiToken", "Failed to reply to message");
+                }
+            }
+            AuthMessage::Revoke {
+                user_id,
+                revocation,
+                reply,
+            } => {
+                if reply
+                    .send(AuthActor::revoke(state, user_id, revocation).await)
                     .is_err()
                 {
-                    error!(message = "IsAdmin", "Failed to reply to message");
+                    error!(message = "Revoke", "Failed to reply to message");
                 }
             }
         }
@@ -325,24 +617,185 @@ impl Actor for AuthActor {
 }
 
 impl AuthActor {
-    async fn is_admin(db: &Surreal<Db>, userid: String) -> Result<bool> {
-        match db
-            .query("SELECT admin FROM $user")
-            .bind(("user", UserID::from(userid).into_recordid()))
+    /// The roles minted into a session, the admin flag counts as the admin
+    /// role everywhere.
+    async fn role_grants(db: &Surreal<Db>, user_id: &str) -> Result<Vec<RoleGrant>> {
+        #[derive(Deserialize)]
+        struct Roles {
+            admin: bool,
+            #[serde(default)]
+            roles: Vec<RoleGrant>,
+        }
+        let roles: Option<Roles> = db
+            .query("SELECT admin, roles FROM $user")
+            .bind(("user", UserID::from(user_id.to_owned()).into_recordid()))
             .await
-            .map(surrealdb::Response::check)
-        {
-            Err(_) | Ok(Err(_)) => Err(InnerError::InternalError)?,
-            Ok(Ok(mut db_response)) => Ok(db_response.take("admin").ok().flatten() == Some(true)),
+            .and_then(|mut response| response.take(0))
+            .map_err(|e| {
+                error!(?e, "loading roles");
+                InnerError::InternalError
+            })?;
+        Ok(roles
+            .map(|Roles { admin, mut roles }| {
+                if admin {
+                    roles.push(RoleGrant {
+                        role: Role::Admin,
+                        app: None,
+                    });
+                }
+                roles
+            })
+            .unwrap_or_default())
+    }
+
+    /// Loads the revocation list, forgetting biscuits that have expired since
+    /// they'd fail validation anyway.
+    async fn load_revoked(db: &Surreal<Db>) -> Result<HashSet<String>> {
+        db.query("DELETE revoked_tokens WHERE expires < time::now()")
+            .query("DELETE sessions WHERE expires < time::now()")
+            .query("SELECT VALUE record::id(id) FROM revoked_tokens")
+            .await
+            .and_then(|mut response| response.take::<Vec<String>>(2))
+            .map(HashSet::from_iter)
+            .map_err(|e| {
+                error!(?e, "loading revoked tokens");
+                InnerError::InternalError.into()
+            })
+    }
+
+    /// Records a newly minted biscuit so it can be revoked later, API tokens
+    /// are already recorded and only gain the ID.
+    async fn record_issued(
+        db: &Surreal<Db>,
+        biscuit: &Biscuit,
+        user_id: &str,
+        token_id: Option<&str>,
+        expires: SystemTime,
+    ) -> Result<()> {
+        let query = if token_id.is_some() {
+            "UPDATE type::thing('api_tokens', $token) SET revocation_id = $revocation_id"
+        } else {
+            "CREATE sessions SET user = $user, revocation_id = $revocation_id, created = \
+             time::now(), expires = $expires"
+        };
+        db.query(query)
+            .bind(("token", token_id.map(ToOwned::to_owned)))
+            .bind(("user", UserID::from(user_id.to_owned()).into_recordid()))
+            .bind(("revocation_id", revocation_id(biscuit)))
+            .bind(("expires", Datetime::from(DateTime::<Utc>::from(expires))))
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(|e| {
+                error!(?e, "recording issued biscuit");
+                InnerError::InternalError
+            })?;
+        Ok(())
+    }
+
+    /// Moves the matching sessions and API tokens onto the revocation list,
+    /// returning how many were revoked.
+    async fn revoke(
+        state: &mut AuthState,
+        user_id: String,
+        revocation: Revocation,
+    ) -> Result<usize> {
+        let removed = match revocation {
+            Revocation::ApiToken(_) => {
+                "(DELETE api_tokens WHERE user = $user AND id = $token RETURN BEFORE)"
+            }
+            Revocation::Sessions => "(DELETE sessions WHERE user = $user RETURN BEFORE)",
+            Revocation::Everything => {
+                "array::concat((DELETE sessions WHERE user = $user RETURN BEFORE), (DELETE \
+                 api_tokens WHERE user = $user RETURN BEFORE))"
+            }
+        };
+        let token = match revocation {
+            Revocation::ApiToken(id) => Some(RecordId::from_table_key("api_tokens", id)),
+            Revocation::Sessions | Revocation::Everything => None,
+        };
+        let revoked: Vec<String> = state
+            .database
+            .query("BEGIN TRANSACTION")
+            .query(format!(
+                "LET $revoked = SELECT revocation_id AS id, expires FROM {removed} WHERE \
+                 revocation_id != NONE"
+            ))
+            .query("INSERT IGNORE INTO revoked_tokens $revoked")
+            .query("RETURN $revoked.id")
+            .query("COMMIT TRANSACTION")
+            .bind(("user", UserID::from(user_id).into_recordid()))
+            .bind(("token", token))
+            .await
+            // A RETURN inside a transaction replaces the results of the
+            // statements before it, so the revoked IDs are always the last
+            .and_then(|mut response| response.take(response.num_statements() - 1))
+            .map_err(|e| {
+                error!(?e, "revoking biscuits");
+                InnerError::InternalError
+            })?;
+        debug!(count = revoked.len(), "revoked biscuits");
+        let count = revoked.len();
+        state.revoked.extend(revoked);
+        Ok(count)
+    }
+
+    /// Returns the user's ban if one is in effect, temporary bans lapse on
+    /// their own.
+    async fn ban_status(db: &Surreal<Db>, user_id: &str) -> Result<Option<Ban>> {
+        db.query(
+            "SELECT ban_reason AS reason, banned_until AS until FROM $user WHERE banned AND \
+             (banned_until = NONE OR banned_until > time::now())",
+        )
+        .bind(("user", UserID::from(user_id.to_owned()).into_recordid()))
+        .await
+        .and_then(|mut response| response.take(0))
+        .map_err(|e| {
+            error!(?e, "checking ban");
+            InnerError::InternalError.into()
+        })
+    }
+
+    /// Validates a biscuit for `operation`, banned users may only read.
+    async fn validate_token(
+        state: &AuthState,
+        token: &str,
+        operation: Operation,
+    ) -> Result<Authorizer> {
+        let mut authorizer =
+            AuthActor::validate_cookie(&state.biscuit, &state.revoked, token, operation)?;
+        if operation != Operation::Read {
+            let user_id = user_of(&mut authorizer).ok_or(InnerError::Unauthorized)?;
+            if let Some(ban) = AuthActor::ban_status(&state.database, &user_id).await? {
+                debug!(%user_id, ?ban, "Banned user attempted a write");
+                return Err(InnerError::from(ban))?;
+            }
         }
+        Ok(authorizer)
     }
 
-    fn validate_cookie(config: &BiscuitConfig, token: &str) -> Result<Authorizer> {
-        let keypair = &KeyPair::from(&config.private_key);
-        let Ok(token) = Biscuit::from_base64(token, keypair.public()) else {
+    fn validate_cookie(
+        config: &BiscuitConfig,
+        revoked: &HashSet<String>,
+        token: &str,
+        operation: Operation,
+    ) -> Result<Authorizer> {
+        let Ok(token) = Biscuit::from_base64(token, config) else {
             return Err(InnerError::Unauthorized)?;
         };
-        let Ok(mut authorizer) = authorizer!("").time().allow_all().build(&token) else {
+        if token
+            .revocation_identifiers()
+            .iter()
+            .any(|id| revoked.contains(&hex(id)))
+        {
+            debug!("Revoked token");
+            return Err(InnerError::Unauthorized)?;
+        }
+        let Ok(mut authorizer) =
+            authorizer!("operation({operation});", operation = operation.as_str())
+                .time()
+                .allow_all()
+                .build(&token)
+        else {
             return Err(InnerError::Unauthorized)?;
         };
 
@@ -353,142 +806,80 @@ impl AuthActor {
         Ok(authorizer)
     }
 
-    fn get_auth_url(state: &AuthState, location: &str) -> Result<String> {
-        let mut url =
-            Url::from_str(&state.open_id_info.uri).map_err(|_| InnerError::BuildingURI)?;
-        url.query_pairs_mut()
-            .append_pair("openid.mode", "checkid_setup")
-            .append_pair("openid.ns", "http://specs.openid.net/auth/2.0")
-            .append_pair(
-                "openid.claimed_id",
-                "http://specs.openid.net/auth/2.0/identifier_select",
-            )
-            .append_pair(
-                "openid.identity",
-                "http://specs.openid.net/auth/2.0/identifier_select",
-            )
-            .append_pair(
-                "openid.return_to",
-                &AuthActor::redirect_url(&state.base_url, location),
-            )
-            .append_pair("openid.realm", state.base_url.as_str())
-            .finish();
-
-        Ok(url.to_string())
+    /// API tokens are attenuated, the authority block names the user and token
+    /// and the block after limits the operations and lifetime.
+    async fn mint_api_token(
+        state: &AuthState,
+        user_id: &str,
+        token_id: &str,
+        scope: TokenScope,
+        expires: SystemTime,
+    ) -> Result<String> {
+        let attenuation = block!(r#"check if time($time), $time <= {expires};"#)
+            .code(scope.check())
+            .map_err(|_| InnerError::InternalError)?;
+        let biscuit = biscuit!(
+            r#"
+          user({user_id});
+          token({token_id});
+    "#
+        )
+        .root_key_id(state.biscuit.key_id)
+        .build(&state.biscuit.keypair())
+        .and_then(|biscuit| biscuit.append(attenuation))
+        .map_err(|_| InnerError::InternalError)?;
+        AuthActor::record_issued(&state.database, &biscuit, user_id, Some(token_id), expires)
+            .await?;
+        biscuit
+            .to_base64()
+            .map_err(|_| InnerError::InternalError.into())
     }
 
-    fn redirect_url(base: &Arc<String>, location: &str) -> String {
-        String::clone(base) + "/api/verify?location=" + location
+    fn get_auth_url(state: &AuthState, location: &str) -> Result<String> {
+        state
+            .provider
+            .login_url(&AuthActor::redirect_url(&state.base_url, location)?)
     }
 
-    async fn discover_openid_info(client: &Client) -> Result<Info> {
-        let response = client
-            .get(STEAM_DISCOVERY)
-            .send()
-            .await
-            .map_err(|_| InnerError::QueryingDiscovery)?;
-        let response_text = response
-            .text()
-            .await
-            .map_err(|_| InnerError::DiscoveryBadResponse)?;
-
-        let doc: Xrds = from_str(&response_text).map_err(|_| InnerError::DeserializingDiscovery)?;
-        Ok(Info {
-            r#type: doc.xrd.service.r#type,
-            uri: doc.xrd.service.uri,
-        })
+    fn redirect_url(base: &Arc<String>, location: &str) -> Result<String> {
+        let mut url =
+            Url::parse(&format!("{base}/api/verify")).map_err(|_| InnerError::BuildingURI)?;
+        url.query_pairs_mut()
+            .append_pair("location", location)
+            .finish();
+        Ok(url.into())
     }
 
-    async fn verify_steam_response(
+    /// Checks the identity provider's response, minting a session for the
+    /// user it names.
+    async fn verify_response(
         map: MultiMap<String, String>,
         state: &mut AuthState,
     ) -> Result<String> {
-        {
-            if (map.get("openid.ns").map(String::as_str))
-                != (Some(
-                    &state.open_id_info.r#type[0..state
-                        .open_id_info
-                        .r#type
-                        .len()
-                        .saturating_sub(b"/server".len())],
-                ))
-            {
-                return Err(InnerError::SelfValidationFailed)?;
-            }
-
-            if (map.get("openid.op_endpoint")) != (Some(&state.open_id_info.uri)) {
-                return Err(InnerError::SelfValidationFailed)?;
-            }
-            if let Some((timestamp, _)) = map
-                .get("openid.response_nonce")
-                .ok_or(InnerError::SelfValidationFailed)?
-                .split_once('Z')
-            {
-                let timestamp = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S")
-                    .map_err(|_| InnerError::SelfValidationFailed)?;
-                if timestamp - Utc::now().naive_utc() > TimeDelta::minutes(5) {
-                    return Err(InnerError::SelfValidationFailed)?;
-                }
-            } else {
-                return Err(InnerError::SelfValidationFailed)?;
-            }
-        }
-
-        let mut url = Url::from_str("https://steamcommunity.com/openid/login").unwrap();
-
-        for item in map
-            .get("openid.signed")
-            .ok_or(InnerError::SelfValidationFailed)?
-            .split(',')
-        {
-            let key = format!("openid.{item}");
-            let val = map.get(&key).ok_or(InnerError::SelfValidationFailed)?;
-            url.query_pairs_mut().append_pair(&key, val).finish();
-        }
-        url.query_pairs_mut()
-            .append_pair(
-                "openid.sig",
-                map.get("openid.sig")
-                    .ok_or(InnerError::SelfValidationFailed)?,
-            )
-            .append_pair(
-                "openid.ns",
-                map.get("openid.ns")
-                    .ok_or(InnerError::SelfValidationFailed)?,
-            )
-            .append_pair("openid.mode", "check_authentication")
-            .finish();
-
-        let resp = reqwest::get(url)
-            .await
-            .map_err(|_| InnerError::PeerValidationFailed)?;
-        let text = resp
-            .text()
-            .await
-            .map_err(|_| InnerError::PeerValidationFailed)?;
+        let user_id = &state.provider.verify(&map).await?;
 
-        if text != "ns:http://specs.openid.net/auth/2.0\nis_valid:true\n" {
-            return Err(InnerError::PeerValidationFailed)?;
+        if let Some(ban) = AuthActor::ban_status(&state.database, user_id).await? {
+            return Err(InnerError::from(ban))?;
         }
 
-        let user_id = map
-            .get("openid.identity")
-            .ok_or(InnerError::PeerValidationFailed)?
-            .rsplit('/')
-            .next()
-            .ok_or(InnerError::PeerValidationFailed)?;
+        let expires = SystemTime::now() + TOKEN_LIFETIME;
 
-        let keypair = &KeyPair::from(&state.biscuit.private_key);
-
-        let biscuit: Biscuit = biscuit!(
+        let mut builder = biscuit!(
             r#"
           user({user_id});
           check if time($time), $time <= {expires};
-    "#,
-            expires = SystemTime::now() + TOKEN_LIFETIME
-        )
-        .build(keypair)
-        .map_err(|_| InnerError::PeerValidationFailed)?;
+    "#
+        );
+        for grant in AuthActor::role_grants(&state.database, user_id).await? {
+            builder = builder
+                .fact(role_fact(&grant))
+                .map_err(|_| InnerError::InternalError)?;
+        }
+        let biscuit: Biscuit = builder
+            .root_key_id(state.biscuit.key_id)
+            .build(&state.biscuit.keypair())
+            .map_err(|_| InnerError::PeerValidationFailed)?;
+        AuthActor::record_issued(&state.database, &biscuit, user_id, None, expires).await?;
 
         let based = biscuit
             .to_base64()
@@ -498,7 +889,11 @@ impl AuthActor {
             let user = User {
                 id: UserID::from(user_id.to_owned()).into_recordid(),
                 admin: false,
+                roles: vec![],
                 banned: false,
+                ban_reason: None,
+                banned_until: None,
+                privacy: Privacy::default(),
                 last_logged_in: Utc::now(),
             };
             let mut stmt = InsertStatement::default();
@@ -524,3 +919,332 @@ impl AuthActor {
         Ok(based)
     }
 }
+
+/// `role($role)` for roles held everywhere, `app_role($role, $app)` otherwise
+fn role_fact(grant: &RoleGrant) -> Fact {
+    let role = grant.role.as_str();
+    match grant.app {
+        None => fact!("role({role})"),
+        Some(app) => fact!("app_role({role}, {app})"),
+    }
+}
+
+/// Lowercase hex, as revocation IDs are stored
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// The ID revoking every block of `biscuit`, a token fails validation if any
+/// of its blocks' IDs are revoked so the authority block's is enough.
+fn revocation_id(biscuit: &Biscuit) -> String {
+    biscuit
+        .revocation_identifiers()
+        .first()
+        .map(|id| hex(id))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use biscuit_auth::{
+        KeyPair,
+        builder_ext::AuthorizerExt,
+        macros::{authorizer, biscuit},
+    };
+    use multimap::MultiMap;
+    use ractor::{Actor, ActorRef, call};
+    use reqwest::Client;
+    use salvo::Depot;
+    use surrealdb::{
+        Surreal,
+        engine::local::{Db, Mem},
+    };
+    use surrealdb_migrations::MigrationRunner;
+
+    use super::{
+        AuthActor, AuthArgs, AuthMessage, Grant, InnerError, Operation, Permission, Revocation,
+        grant_from_depot, local_redirect, role_fact, user_of,
+    };
+    use crate::{
+        app_config::{BiscuitConfig, IdentityConfig},
+        db::model::{Role, RoleGrant},
+        web::tokens::TokenScope,
+    };
+
+    async fn spawn_dev(db: Surreal<Db>) -> ActorRef<AuthMessage> {
+        let (actor, _) = Actor::spawn(
+            None,
+            AuthActor {},
+            AuthArgs {
+                database: db,
+                client: Client::new(),
+                base_url: Arc::new("http://localhost:5800".to_owned()),
+                biscuit: Arc::new(BiscuitConfig {
+                    private_key: KeyPair::new().private(),
+                    key_id: 0,
+                    previous_keys: vec![],
+                }),
+                identity: IdentityConfig::Dev {
+                    user: "76561197960287930".to_owned(),
+                },
+            },
+        )
+        .await
+        .unwrap();
+        actor
+    }
+
+    async fn login(actor: &ActorRef<AuthMessage>) -> String {
+        let mut query = MultiMap::new();
+        query.insert("dev.user".to_owned(), "76561197960287930".to_owned());
+        call!(actor, AuthMessage::VerifyResponse, query)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dev_login() {
+        let db = Surreal::new::<Mem>(()).await.unwrap();
+        db.use_ns("test").use_db("test").await.unwrap();
+        MigrationRunner::new(&db).up().await.unwrap();
+        let actor = spawn_dev(db).await;
+
+        let url = call!(actor, AuthMessage::GetAuthUrl, "/".to_owned())
+            .unwrap()
+            .unwrap();
+        assert!(url.contains("dev.user=76561197960287930"));
+
+        let token = login(&actor).await;
+        let mut authorizer = call!(actor, AuthMessage::ValidateToken, token, Operation::Write)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            user_of(&mut authorizer).as_deref(),
+            Some("76561197960287930")
+        );
+
+        // Nobody gets in without saying who they are
+        let denied = call!(actor, AuthMessage::VerifyResponse, MultiMap::new()).unwrap();
+        assert!(denied.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_revoke() {
+        let db = Surreal::new::<Mem>(()).await.unwrap();
+        db.use_ns("test").use_db("test").await.unwrap();
+        MigrationRunner::new(&db).up().await.unwrap();
+        let actor = spawn_dev(db.clone()).await;
+        let session = login(&actor).await;
+
+        db.query(
+            "CREATE api_tokens:cli SET user = users:76561197960287930, name = 'cli', scope = \
+             'full', created = time::now(), expires = time::now() + 1d",
+        )
+        .await
+        .unwrap()
+        .check()
+        .unwrap();
+        let api_token = call!(actor, |reply| AuthMessage::MintApiToken {
+            user_id: "76561197960287930".to_owned(),
+            token_id: "cli".to_owned(),
+            scope: TokenScope::Full,
+            expires: SystemTime::now() + Duration::from_secs(60),
+            reply,
+        })
+        .unwrap()
+        .unwrap();
+        let validate = async |token: &str| {
+            call!(
+                actor,
+                AuthMessage::ValidateToken,
+                token.to_owned(),
+                Operation::Read
+            )
+            .unwrap()
+        };
+        assert!(validate(&api_token).await.is_ok());
+
+        let revoke = async |revocation: Revocation| {
+            call!(actor, |reply| AuthMessage::Revoke {
+                user_id: "76561197960287930".to_owned(),
+                revocation,
+                reply,
+            })
+            .unwrap()
+            .unwrap()
+        };
+        assert_eq!(revoke(Revocation::ApiToken("cli".to_owned())).await, 1);
+        assert!(validate(&api_token).await.is_err());
+        // The session is untouched until the user logs out everywhere
+        assert!(validate(&session).await.is_ok());
+        assert_eq!(revoke(Revocation::Sessions).await, 1);
+        assert!(validate(&session).await.is_err());
+        assert_eq!(revoke(Revocation::Everything).await, 0);
+    }
+
+    fn depot_with(grants: &[RoleGrant]) -> Depot {
+        let mut builder = biscuit!(r#"user("76561197960287930");"#);
+        for grant in grants {
+            builder = builder.fact(role_fact(grant)).unwrap();
+        }
+        let biscuit = builder.build(&KeyPair::new()).unwrap();
+        let mut authorizer = authorizer!(r#"operation("read");"#)
+            .allow_all()
+            .build(&biscuit)
+            .unwrap();
+        authorizer.authorize().unwrap();
+        let mut depot = Depot::new();
+        depot.inject(authorizer);
+        depot
+    }
+
+    #[test]
+    fn test_grants() {
+        let grant = |role, app| RoleGrant { role, app };
+        assert_eq!(Permission::ManageUsers.roles(), &[Role::Admin]);
+        assert!(
+            !Permission::ModerateProperties
+                .roles()
+                .contains(&Role::Curator)
+        );
+
+        let mut depot = depot_with(&[grant(Role::Admin, None)]);
+        for permission in [
+            Permission::ReviewCompanions,
+            Permission::ModerateProperties,
+            Permission::ManageUsers,
+        ] {
+            assert_eq!(
+                grant_from_depot(&mut depot, permission),
+                Some(Grant::Everywhere)
+            );
+        }
+
+        let mut depot = depot_with(&[
+            grant(Role::Moderator, Some(294_100)),
+            grant(Role::Curator, Some(108_600)),
+        ]);
+        assert_eq!(
+            grant_from_depot(&mut depot, Permission::ModerateProperties),
+            Some(Grant::Apps(vec![294_100]))
+        );
+        let Some(Grant::Apps(mut apps)) =
+            grant_from_depot(&mut depot, Permission::ReviewCompanions)
+        else {
+            panic!("curating both apps");
+        };
+        apps.sort_unstable();
+        assert_eq!(apps, vec![108_600, 294_100]);
+
+        // Curators don't moderate and per-app admins don't manage users
+        let mut depot = depot_with(&[
+            grant(Role::Curator, None),
+            grant(Role::Admin, Some(294_100)),
+        ]);
+        assert_eq!(
+            grant_from_depot(&mut depot, Permission::ReviewCompanions),
+            Some(Grant::Everywhere)
+        );
+        assert_eq!(
+            grant_from_depot(&mut depot, Permission::ModerateProperties),
+            Some(Grant::Apps(vec![294_100]))
+        );
+        assert_eq!(grant_from_depot(&mut depot, Permission::ManageUsers), None);
+        let mut depot = depot_with(&[grant(Role::Curator, None)]);
+        assert_eq!(
+            grant_from_depot(&mut depot, Permission::ModerateProperties),
+            None
+        );
+
+        assert_eq!(Grant::Everywhere.apps(), None);
+        assert_eq!(Grant::Apps(vec![1]).apps(), Some(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn test_demotion() {
+        let db = Surreal::new::<Mem>(()).await.unwrap();
+        db.use_ns("test").use_db("test").await.unwrap();
+        MigrationRunner::new(&db).up().await.unwrap();
+        let actor = spawn_dev(db.clone()).await;
+        let set_roles = async |roles: &str| {
+            db.query(format!(
+                "UPDATE users:76561197960287930 SET roles = {roles}"
+            ))
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+        };
+        let grant = async |token: &str| {
+            let authorizer = call!(
+                actor,
+                AuthMessage::ValidateToken,
+                token.to_owned(),
+                Operation::Write
+            )
+            .unwrap()
+            .ok()?;
+            let mut depot = Depot::new();
+            depot.inject(authorizer);
+            grant_from_depot(&mut depot, Permission::ModerateProperties)
+        };
+
+        // Logging in creates the user
+        login(&actor).await;
+        set_roles("[{ role: 'moderator', app: 294100 }]").await;
+        let token = login(&actor).await;
+        assert_eq!(grant(&token).await, Some(Grant::Apps(vec![294_100])));
+
+        // What patch_user does when demoting
+        set_roles("[]").await;
+        call!(actor, |reply| AuthMessage::Revoke {
+            user_id: "76561197960287930".to_owned(),
+            revocation: Revocation::Sessions,
+            reply,
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(grant(&token).await, None);
+        assert_eq!(grant(&login(&actor).await).await, None);
+    }
+
+    #[test]
+    fn test_local_redirect() {
+        let base = "http://localhost:5800";
+        assert_eq!(
+            local_redirect(base, "/mods/1?page=2").unwrap(),
+            "http://localhost:5800/mods/1?page=2"
+        );
+        assert_eq!(
+            local_redirect(base, "http://localhost:5800/admin").unwrap(),
+            "http://localhost:5800/admin"
+        );
+        for location in [
+            "https://evil.example/",
+            "//evil.example",
+            "/\\evil.example",
+            "javascript:alert(1)",
+            "http://localhost:5800.evil.example/",
+            "http://user@localhost:5800/",
+        ] {
+            assert!(
+                matches!(
+                    local_redirect(base, location),
+                    Err(InnerError::InvalidRedirect)
+                ),
+                "{location}"
+            );
+        }
+        // Nothing outside the base path either
+        assert!(local_redirect("https://example.com/app", "/other").is_err());
+        assert!(local_redirect("https://example.com/app", "../other").is_err());
+    }
+}
@@ -0,0 +1,373 @@
+//! Where users log in. Steam's OpenID 2.0 endpoint is the default, the dev
+//! provider lets tests and offline environments log in as anyone.
+
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use multimap::MultiMap;
+use ractor::async_trait;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_xml_rs::from_str;
+use tracing::warn;
+
+use super::{InnerError, Result};
+use crate::app_config::IdentityConfig;
+
+/// Sends users off to log in and checks what they come back with
+#[async_trait]
+pub trait IdentityProvider: Send + Sync {
+    /// Where to send the user to log in, the provider sends them back to
+    /// `return_to` afterward.
+    fn login_url(&self, return_to: &str) -> Result<String>;
+
+    /// Checks the query the provider sent the user back with, returning the
+    /// user's ID.
+    async fn verify(&mut self, query: &MultiMap<String, String>) -> Result<String>;
+}
+
+/// How far a response's nonce timestamp may be from now, either way to allow
+/// for clock skew. Nonces are remembered for this long after their timestamp
+/// to catch replays.
+const NONCE_WINDOW: TimeDelta = TimeDelta::minutes(5);
+
+/// Fields that have to be covered by the provider's signature, anything else
+/// could be changed without it noticing.
+const SIGNED_FIELDS: &[&str] = &[
+    "op_endpoint",
+    "claimed_id",
+    "identity",
+    "return_to",
+    "response_nonce",
+];
+
+/// Builds the provider picked in the config
+pub async fn from_config(
+    config: &IdentityConfig,
+    client: &Client,
+    base_url: Arc<String>,
+) -> Result<Box<dyn IdentityProvider>> {
+    Ok(match config {
+        IdentityConfig::Steam { discovery_url } => {
+            Box::new(OpenId::discover(client.clone(), discovery_url, base_url).await?)
+        }
+        IdentityConfig::Dev { user } => {
+            warn!("Using the dev identity provider, anyone can log in as anyone");
+            Box::new(Dev { user: user.clone() })
+        }
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Xrds {
+    #[serde(rename = "XRD")]
+    xrd: Xrd,
+}
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Xrd {
+    #[serde(rename = "Service")]
+    service: Service,
+}
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Service {
+    #[serde(rename = "Type")]
+    r#type: String,
+    #[serde(rename = "URI")]
+    uri: String,
+}
+
+/// An OpenID 2.0 provider found through discovery, i.e. Steam or a local
+/// stand-in for it
+pub struct OpenId {
+    client: Client,
+    r#type: String,
+    uri: String,
+    base_url: Arc<String>,
+    /// Response nonces already used, with when they can be forgotten. Kept in
+    /// memory, a restart forgets them but the timestamp window still applies.
+    seen_nonces: HashMap<String, DateTime<Utc>>,
+}
+
+impl OpenId {
+    pub async fn discover(
+        client: Client,
+        discovery_url: &str,
+        base_url: Arc<String>,
+    ) -> Result<Self> {
+        let response = client
+            .get(discovery_url)
+            .send()
+            .await
+            .map_err(|_| InnerError::QueryingDiscovery)?;
+        let response_text = response
+            .text()
+            .await
+            .map_err(|_| InnerError::DiscoveryBadResponse)?;
+
+        let doc: Xrds = from_str(&response_text).map_err(|_| InnerError::DeserializingDiscovery)?;
+        Ok(Self {
+            client,
+            r#type: doc.xrd.service.r#type,
+            uri: doc.xrd.service.uri,
+            base_url,
+            seen_nonces: HashMap::new(),
+        })
+    }
+
+    /// Checks the response was meant for us and hasn't been seen before,
+    /// before asking the provider. The nonce is used up even if the provider
+    /// then rejects the response.
+    fn check_response(
+        &mut self,
+        query: &MultiMap<String, String>,
+    ) -> std::result::Result<(), InnerError> {
+        let field = |key: &str| query.get(key).ok_or(InnerError::SelfValidationFailed);
+        if field("openid.ns")?
+            != &self.r#type[0..self.r#type.len().saturating_sub(b"/server".len())]
+        {
+            return Err(InnerError::SelfValidationFailed);
+        }
+        if field("openid.op_endpoint")? != &self.uri {
+            return Err(InnerError::SelfValidationFailed);
+        }
+        if !field("openid.return_to")?.starts_with(&format!("{}/api/verify?", self.base_url)) {
+            return Err(InnerError::SelfValidationFailed);
+        }
+        let signed: Vec<&str> = field("openid.signed")?.split(',').collect();
+        if !SIGNED_FIELDS.iter().all(|needed| signed.contains(needed)) {
+            return Err(InnerError::SelfValidationFailed);
+        }
+
+        let nonce = field("openid.response_nonce")?;
+        let (timestamp, _) = nonce
+            .split_once('Z')
+            .ok_or(InnerError::SelfValidationFailed)?;
+        let timestamp = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S")
+            .map_err(|_| InnerError::SelfValidationFailed)?
+            .and_utc();
+        let now = Utc::now();
+        if (timestamp - now).abs() > NONCE_WINDOW {
+            return Err(InnerError::SelfValidationFailed);
+        }
+        self.seen_nonces.retain(|_, forget_at| *forget_at > now);
+        if self.seen_nonces.contains_key(nonce) {
+            return Err(InnerError::ReplayedResponse);
+        }
+        self.seen_nonces
+            .insert(nonce.to_owned(), timestamp + NONCE_WINDOW);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for OpenId {
+    fn login_url(&self, return_to: &str) -> Result<String> {
+        let mut url = Url::from_str(&self.uri).map_err(|_| InnerError::BuildingURI)?;
+        url.query_pairs_mut()
+            .append_pair("openid.mode", "checkid_setup")
+            .append_pair("openid.ns", "http://specs.openid.net/auth/2.0")
+            .append_pair(
+                "openid.claimed_id",
+                "http://specs.openid.net/auth/2.0/identifier_select",
+            )
+            .append_pair(
+                "openid.identity",
+                "http://specs.openid.net/auth/2.0/identifier_select",
+            )
+            .append_pair("openid.return_to", return_to)
+            .append_pair("openid.realm", self.base_url.as_str())
+            .finish();
+
+        Ok(url.to_string())
+    }
+
+    async fn verify(&mut self, query: &MultiMap<String, String>) -> Result<String> {
+        self.check_response(query)?;
+
+        let mut url = Url::from_str(&self.uri).map_err(|_| InnerError::BuildingURI)?;
+
+        for item in query
+            .get("openid.signed")
+            .ok_or(InnerError::SelfValidationFailed)?
+            .split(',')
+        {
+            let key = format!("openid.{item}");
+            let val = query.get(&key).ok_or(InnerError::SelfValidationFailed)?;
+            url.query_pairs_mut().append_pair(&key, val).finish();
+        }
+        url.query_pairs_mut()
+            .append_pair(
+                "openid.sig",
+                query
+                    .get("openid.sig")
+                    .ok_or(InnerError::SelfValidationFailed)?,
+            )
+            .append_pair(
+                "openid.ns",
+                query
+                    .get("openid.ns")
+                    .ok_or(InnerError::SelfValidationFailed)?,
+            )
+            .append_pair("openid.mode", "check_authentication")
+            .finish();
+
+        let resp = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|_| InnerError::PeerValidationFailed)?;
+        let text = resp
+            .text()
+            .await
+            .map_err(|_| InnerError::PeerValidationFailed)?;
+
+        if text != "ns:http://specs.openid.net/auth/2.0\nis_valid:true\n" {
+            return Err(InnerError::PeerValidationFailed)?;
+        }
+
+        query
+            .get("openid.identity")
+            .ok_or(InnerError::PeerValidationFailed)?
+            .rsplit('/')
+            .next()
+            .map(ToOwned::to_owned)
+            .ok_or(InnerError::PeerValidationFailed.into())
+    }
+}
+
+/// Logs in as whoever is asked for, never use it where it's reachable by
+/// others. Logging in goes straight back with `dev.user` set to the
+/// configured user, which can be changed in the query.
+pub struct Dev {
+    user: String,
+}
+
+#[async_trait]
+impl IdentityProvider for Dev {
+    fn login_url(&self, return_to: &str) -> Result<String> {
+        let mut url = Url::from_str(return_to).map_err(|_| InnerError::BuildingURI)?;
+        url.query_pairs_mut()
+            .append_pair("dev.user", &self.user)
+            .finish();
+        Ok(url.to_string())
+    }
+
+    async fn verify(&mut self, query: &MultiMap<String, String>) -> Result<String> {
+        query
+            .get("dev.user")
+            .filter(|user| !user.is_empty())
+            .cloned()
+            .ok_or(InnerError::SelfValidationFailed.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use chrono::{TimeDelta, Utc};
+    use multimap::MultiMap;
+    use reqwest::Client;
+
+    use super::{Dev, IdentityProvider, OpenId};
+    use crate::web::auth::InnerError;
+
+    fn provider() -> OpenId {
+        OpenId {
+            client: Client::new(),
+            r#type: "http://specs.openid.net/auth/2.0/server".to_owned(),
+            uri: "https://steamcommunity.com/openid/login".to_owned(),
+            base_url: Arc::new("http://localhost:5800".to_owned()),
+            seen_nonces: HashMap::new(),
+        }
+    }
+
+    fn response(nonce_age: TimeDelta) -> MultiMap<String, String> {
+        let nonce = format!(
+            "{}Zb4Xc2",
+            (Utc::now() - nonce_age).format("%Y-%m-%dT%H:%M:%S")
+        );
+        [
+            ("openid.ns", "http://specs.openid.net/auth/2.0"),
+            (
+                "openid.op_endpoint",
+                "https://steamcommunity.com/openid/login",
+            ),
+            (
+                "openid.return_to",
+                "http://localhost:5800/api/verify?location=http%3A%2F%2Flocalhost%3A5800%2F",
+            ),
+            (
+                "openid.signed",
+                "signed,op_endpoint,claimed_id,identity,return_to,response_nonce,assoc_handle",
+            ),
+            ("openid.response_nonce", nonce.as_str()),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect()
+    }
+
+    fn with(key: &str, value: &str) -> MultiMap<String, String> {
+        let mut query = response(TimeDelta::zero());
+        query.remove(key);
+        query.insert(key.to_owned(), value.to_owned());
+        query
+    }
+
+    #[test]
+    fn test_check_response() {
+        let mut provider = provider();
+        let query = response(TimeDelta::seconds(30));
+        assert!(provider.check_response(&query).is_ok());
+        assert!(matches!(
+            provider.check_response(&query),
+            Err(InnerError::ReplayedResponse)
+        ));
+
+        let rejected = [
+            with("openid.ns", "http://specs.openid.net/auth/1.1"),
+            with("openid.op_endpoint", "https://evil.example/openid/login"),
+            with(
+                "openid.return_to",
+                "https://evil.example/api/verify?location=%2F",
+            ),
+            with(
+                "openid.signed",
+                "signed,op_endpoint,return_to,response_nonce",
+            ),
+            with("openid.response_nonce", "not a nonce"),
+            with("openid.response_nonce", "2024-13-01T00:00:00Zb4Xc2"),
+            response(TimeDelta::minutes(10)),
+            response(-TimeDelta::minutes(10)),
+        ];
+        for query in rejected {
+            assert!(
+                matches!(
+                    provider.check_response(&query),
+                    Err(InnerError::SelfValidationFailed)
+                ),
+                "{query:?}"
+            );
+        }
+        let mut missing = response(TimeDelta::zero());
+        missing.remove("openid.response_nonce");
+        assert!(matches!(
+            provider.check_response(&missing),
+            Err(InnerError::SelfValidationFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dev() {
+        let mut dev = Dev {
+            user: "76561197960287930".to_owned(),
+        };
+        let url = dev
+            .login_url("http://localhost:5800/api/verify?location=%2F")
+            .unwrap();
+        assert!(url.ends_with("&dev.user=76561197960287930"));
+        assert!(dev.verify(&MultiMap::new()).await.is_err());
+    }
+}
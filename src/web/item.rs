@@ -12,12 +12,30 @@ use tracing::{error, instrument};
 use crate::{
     db::{
         UserID,
-        model::{FullWorkshopItem, WorkshopItem, into_string},
+        model::{CompanionItem, FullWorkshopItem, Translation, WorkshopItem},
     },
+    processing::embedding_actor::{item_embedding, nearest_items},
     web::auth,
 };
 
 static ITEM_ACTOR: OnceLock<ActorRef<ItemMsg>> = OnceLock::new();
+/// How many items `similar` returns
+const SIMILAR_ITEMS: usize = 10;
+/// An item with its tags and approved properties, to be followed by `FROM`
+const ITEM_PROJECTION: &str = r"SELECT *, tags.{id: id.to_string(), app_id, display_name} as tags,
+    ->workshop_item_properties.filter(|$prop|$prop.status == 1)[*].{
+        id: id.to_string(),
+        in: in.to_string(),
+        out: out.id.{
+            class,
+            `value`
+        },
+        source: 'system',
+        status,
+        upvote_count,
+        vote_count,
+        confidence
+    } as properties";
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub type Error = StatusError;
@@ -66,6 +84,7 @@ pub enum ItemMsg {
         Option<String>,
         RpcReplyPort<Result<FullWorkshopItem>>,
     ),
+    Similar(String, RpcReplyPort<Result<Vec<WorkshopItem<String>>>>),
 }
 
 #[async_trait]
@@ -98,6 +117,12 @@ impl Actor for ItemActor {
                     error!(message = "Get", "Failed to reply to message");
                 }
             }
+            ItemMsg::Similar(id, reply) => {
+                let res = similar_items(&state.database, id).await;
+                if reply.send(res).is_err() {
+                    error!(message = "Similar", "Failed to reply to message");
+                }
+            }
         }
         Ok(())
     }
@@ -109,14 +134,18 @@ async fn get_item(db: &Surreal<Db>, id: String, user: Option<String>) -> Result<
     let mut response = db
         .query(
             "SELECT in.appid as appid, in.description as description, in.id as id, in.title
-             as title, in.author as author, in.languages as languages, in.last_updated as
+             as title, in.author as author, in.languages as languages, in.language_confidence as
+             language_confidence, in.primary_language as primary_language, in.also_contains as
+             also_contains, in.title_language as title_language, in.last_updated as
              last_updated, in.score as score, in.tags.{id: id.to_string(), app_id, display_name} \
              as tags, in.preview_url as preview_url, [] as properties FROM \
              $id<-item_dependencies.*;",
         )
         .query(
             "SELECT out.appid as appid, out.description as description, out.id as id,
-             out.author as author, out.languages as languages, out.last_updated as
+             out.author as author, out.languages as languages, out.language_confidence as
+             language_confidence, out.primary_language as primary_language, out.also_contains as
+             also_contains, out.title_language as title_language, out.last_updated as
              last_updated, out.title as title, out.score as score, out.tags.{id: id.to_string(), \
              app_id, display_name} as tags, out.preview_url as preview_url, [] as properties
              FROM $id->item_dependencies.*;",
@@ -133,21 +162,7 @@ async fn get_item(db: &Surreal<Db>, id: String, user: Option<String>) -> Result<
     let result = {
         let mut res = match user {
             None => db
-                .query(
-                    r"SELECT *, tags.{id: id.to_string(), app_id, display_name} as tags,
-                ->workshop_item_properties.filter(|$prop|$prop.status == 1)[*].{
-                    id: id.to_string(),
-                    in: in.to_string(),
-                    out: out.id.{
-                        class,
-                        `value`
-                    },
-                    source: 'system',
-                    status,
-                    upvote_count,
-                    vote_count
-                } as properties FROM $id",
-                )
+                .query(format!("{ITEM_PROJECTION} FROM $id"))
                 .bind(("id", id.clone()))
                 .await
                 .map_err(|_| InnerError::InternalError)?,
@@ -166,6 +181,7 @@ async fn get_item(db: &Surreal<Db>, id: String, user: Option<String>) -> Result<
                                         status,
                                         upvote_count,
                                         vote_count,
+                                        confidence,
                                         vote_state: votes:{{item: $id, link: out, user: {0}}}.score
                                     }} as properties FROM $id",
                     UserID::from(user).into_recordid()
@@ -180,58 +196,70 @@ async fn get_item(db: &Surreal<Db>, id: String, user: Option<String>) -> Result<
         result.ok_or(InnerError::NotFound)?
     };
 
+    let translations: Vec<Translation> = db
+        .query(
+            "SELECT record::id(in) AS id, in.title AS title, language FROM translation_of WHERE \
+             out = $id ORDER BY title",
+        )
+        .bind(("id", result.id.clone()))
+        .await
+        .map_err(|_| InnerError::InternalError)?
+        .take(0)
+        .map_err(|_| InnerError::InternalError)?;
+
+    // Anything Steam declares is listed in `dependencies` already. Suggestions
+    // stay hidden until a curator approves them, like properties.
+    let companions: Vec<CompanionItem> = db
+        .query(
+            "SELECT record::id(out) AS id, out.title AS title, kind, status FROM companions WHERE \
+             in = $id AND status = 1 AND out NOTINSIDE $id->item_dependencies.out ORDER BY title",
+        )
+        .bind(("id", result.id.clone()))
+        .await
+        .map_err(|_| InnerError::InternalError)?
+        .take(0)
+        .map_err(|_| InnerError::InternalError)?;
+
     Ok(FullWorkshopItem {
-        appid: result.appid,
-        description: result.description,
-        id: into_string(result.id.key()),
-        title: result.title,
-        preview_url: result.preview_url,
-        languages: result.languages,
-        author: result.author,
-        last_updated: result.last_updated,
         dependencies: dependencies
             .into_iter()
-            .map(|e| FullWorkshopItem {
-                appid: e.appid,
-                author: e.author,
-                dependants: vec![],
-                dependencies: vec![],
-                description: e.description,
-                id: into_string(e.id.key()),
-                languages: e.languages,
-                title: e.title,
-                preview_url: e.preview_url,
-                last_updated: e.last_updated,
-                tags: e.tags,
-                score: e.score,
-                properties: e.properties,
-            })
+            .map(FullWorkshopItem::from)
             .collect(),
-
-        dependants: dependants
-            .into_iter()
-            .map(|e| FullWorkshopItem {
-                appid: e.appid,
-                author: e.author,
-                dependants: vec![],
-                dependencies: vec![],
-                description: e.description,
-                id: into_string(e.id.key()),
-                languages: e.languages,
-                title: e.title,
-                preview_url: e.preview_url,
-                last_updated: e.last_updated,
-                tags: e.tags,
-                score: e.score,
-                properties: e.properties,
-            })
-            .collect(),
-        tags: result.tags,
-        score: result.score,
-        properties: result.properties,
+        dependants: dependants.into_iter().map(FullWorkshopItem::from).collect(),
+        translations,
+        companions,
+        ..FullWorkshopItem::from(result)
     })
 }
 
+/// Items nearest to `id` by embedding, most similar first.
+async fn similar_items(db: &Surreal<Db>, id: String) -> Result<Vec<WorkshopItem<String>>> {
+    let id = RecordId::from_table_key("workshop_items", &id);
+    let embedding = item_embedding(db, id.clone())
+        .await
+        .map_err(|_| InnerError::InternalError)?
+        .ok_or(InnerError::NotFound)?;
+    // The item itself is always the nearest
+    let neighbours = nearest_items(db, embedding, SIMILAR_ITEMS + 1)
+        .await
+        .map_err(|_| InnerError::InternalError)?
+        .into_iter()
+        .filter(|neighbour| neighbour != &id)
+        .take(SIMILAR_ITEMS)
+        .collect::<Vec<_>>();
+
+    let mut items: Vec<WorkshopItem<RecordId>> = db
+        .query(format!("{ITEM_PROJECTION} FROM $ids"))
+        .bind(("ids", neighbours.clone()))
+        .await
+        .map_err(|_| InnerError::InternalError)?
+        .take(0)
+        .map_err(|_| InnerError::InternalError)?;
+    items.sort_by_key(|item| neighbours.iter().position(|id| id == &item.id));
+
+    Ok(items.into_iter().map(WorkshopItem::from).collect())
+}
+
 /// GET /api/item/{id}
 /// Retrieves a full workshop item by id, including dependencies and dependants.
 #[endpoint]
@@ -245,3 +273,16 @@ pub async fn get(id: PathParam<String>, depot: &mut Depot) -> Result<Json<FullWo
         .map_err(|_| InnerError::InternalError)??;
     Ok(Json(data))
 }
+
+/// GET /api/item/{id}/similar
+/// Lists the items whose title and description are closest in meaning to this
+/// item's.
+#[endpoint]
+#[instrument(skip_all)]
+pub async fn similar(id: PathParam<String>) -> Result<Json<Vec<WorkshopItem<String>>>> {
+    let actor = ITEM_ACTOR.get().cloned().ok_or(InnerError::InternalError)?;
+
+    let data = call!(actor, |reply| { ItemMsg::Similar(id.0, reply) })
+        .map_err(|_| InnerError::InternalError)??;
+    Ok(Json(data))
+}
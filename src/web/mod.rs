@@ -4,6 +4,8 @@ mod companions;
 pub mod item;
 pub mod properties;
 mod query;
+pub mod tokens;
+mod users;
 
 use std::sync::Arc;
 
@@ -22,8 +24,32 @@ use crate::app_config::Config;
 static DB_POOL: OnceCell<Surreal<Db>> = OnceCell::const_new();
 ///  Start the webserver returning once it exists
 pub async fn start(db: Surreal<Db>, config: Arc<Config>) {
-    let _ = DB_POOL.get_or_init(|| async { db }).await.clone();
-    let router = Router::new().push(
+    let _ = DB_POOL.get_or_init(|| async { db.clone() }).await.clone();
+    let router = router(db, config);
+    let doc = OpenApi::new("workshop-walker", "0.0.1").merge_router(&router);
+    let router = router
+        .push(doc.into_router("/api-doc/openapi.json"))
+        .push(SwaggerUi::new("/api-doc/openapi.json").into_router("swagger-ui"));
+
+    let router = router.push(
+        Router::with_path("{**path}").get(
+            StaticDir::new(["ui/build/"])
+                .include_dot_files(false)
+                .auto_list(true)
+                .defaults("index.html")
+                .fallback("index.html"),
+        ),
+    );
+
+    let service = Service::new(router).hoop(Logger::new());
+
+    let acceptor = TcpListener::new("0.0.0.0:5800").bind().await;
+    Server::new(acceptor).serve(service).await;
+}
+
+/// The API's routes, with the database and config available to handlers
+fn router(db: Surreal<Db>, config: Arc<Config>) -> Router {
+    Router::new().push(
         Router::with_path("api")
             .hoop(max_size(1024 * 1024))
             .push(Router::with_path("list").get(query::list))
@@ -32,14 +58,15 @@ pub async fn start(db: Surreal<Db>, config: Arc<Config>) {
                     .hoop(auth::validate_opt)
                     .get(item::get),
             )
+            .push(Router::with_path("item/{id}/similar").get(item::similar))
             .push(
                 Router::with_path("property")
-                    .hoop(auth::validate_biscuit_token)
+                    .hoop(auth::authenticate(auth::Operation::Write))
                     .post(properties::new),
             )
             .push(
                 Router::with_path("vote")
-                    .hoop(auth::validate_biscuit_token)
+                    .hoop(auth::authenticate(auth::Operation::Vote))
                     .push(
                         Router::with_path("property")
                             .post(properties::vote)
@@ -48,43 +75,81 @@ pub async fn start(db: Surreal<Db>, config: Arc<Config>) {
             )
             .push(
                 Router::with_path("admin")
-                    .hoop(auth::validate_biscuit_token)
-                    .hoop(auth::enforce_admin)
+                    .hoop(auth::authenticate(auth::Operation::Write))
+                    .push(
+                        Router::new()
+                            .hoop(auth::enforce_moderator)
+                            .push(
+                                Router::with_path("properties")
+                                    .put(admin::patch_workshop_item_properties)
+                                    .get(admin::get_workshop_item_properties),
+                            )
+                            .push(
+                                Router::with_path("review")
+                                    .get(admin::get_pending_ml_properties)
+                                    .put(admin::review_ml_properties),
+                            ),
+                    )
                     .push(
-                        Router::with_path("properties")
-                            .put(admin::patch_workshop_item_properties)
-                            .get(admin::get_workshop_item_properties),
+                        Router::with_path("companions")
+                            .hoop(auth::enforce_curator)
+                            .get(admin::get_pending_companions)
+                            .put(admin::review_companions),
                     )
                     .push(
-                        Router::with_path("users")
-                            .get(admin::get_users)
-                            .put(admin::patch_user),
+                        Router::new()
+                            .hoop(auth::enforce_admin)
+                            .push(
+                                Router::with_path("users")
+                                    .get(admin::get_users)
+                                    .put(admin::patch_user),
+                            )
+                            .push(
+                                Router::with_path("reextract").post(admin::requeue_ml_extraction),
+                            ),
                     ),
             )
-            .hoop(affix_state::inject(config))
-            .push(Router::with_path("login").get(auth::redirect_to_steam_auth))
-            .push(Router::with_path("verify").get(auth::verify_token_from_steam))
-            .push(Router::with_path("logout").get(auth::invalidate)),
-    );
-    let doc = OpenApi::new("workshop-walker", "0.0.1").merge_router(&router);
-    let router = router
-        .push(doc.into_router("/api-doc/openapi.json"))
-        .push(SwaggerUi::new("/api-doc/openapi.json").into_router("swagger-ui"));
-
-    let router = router.push(
-        Router::with_path("{**path}").get(
-            StaticDir::new(["ui/build/"])
-                .include_dot_files(false)
-                .auto_list(true)
-                .defaults("index.html")
-                .fallback("index.html"),
-        ),
-    );
-
-    let service = Service::new(router).hoop(Logger::new());
-
-    let acceptor = TcpListener::new("0.0.0.0:5800").bind().await;
-    Server::new(acceptor).serve(service).await;
+            .push(
+                Router::with_path("me")
+                    .push(
+                        Router::new()
+                            .hoop(auth::authenticate(auth::Operation::ManageTokens))
+                            .get(users::me)
+                            .delete(users::delete),
+                    )
+                    .push(
+                        Router::with_path("privacy")
+                            .hoop(auth::authenticate(auth::Operation::Write))
+                            .put(users::update_privacy),
+                    )
+                    .push(
+                        Router::with_path("export")
+                            .hoop(auth::authenticate_as(auth::Operation::ManageTokens))
+                            .get(users::export),
+                    ),
+            )
+            .push(
+                Router::with_path("user/{id}")
+                    .hoop(auth::validate_opt)
+                    .get(users::get),
+            )
+            .push(
+                Router::with_path("tokens")
+                    .hoop(auth::authenticate_as(auth::Operation::ManageTokens))
+                    .get(tokens::list)
+                    .post(tokens::create)
+                    .push(Router::with_path("{id}").delete(tokens::revoke)),
+            )
+            .hoop(affix_state::inject(config).inject(db))
+            .push(Router::with_path("login").get(auth::redirect_to_login))
+            .push(Router::with_path("verify").get(auth::verify_login))
+            .push(Router::with_path("logout").get(auth::invalidate))
+            .push(
+                Router::with_path("logout/everywhere")
+                    .hoop(auth::authenticate_as(auth::Operation::ManageTokens))
+                    .post(auth::logout_everywhere),
+            ),
+    )
 }
 
 /// Type alias for our Error type
@@ -117,3 +182,116 @@ impl Writer for Error {
         res.render(Text::Plain(format!("Error: {:#?}", self.0)));
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use biscuit_auth::KeyPair;
+    use ractor::Actor;
+    use reqwest::Client;
+    use salvo::{
+        Service,
+        http::header::{COOKIE, LOCATION},
+        prelude::StatusCode,
+        test::{ResponseExt, TestClient},
+    };
+    use serde_json::Value;
+    use surrealdb::{Surreal, engine::local::Mem};
+    use surrealdb_migrations::MigrationRunner;
+
+    use crate::{
+        app_config::{BiscuitConfig, Config, Database, Extraction, IdentityConfig, Steam},
+        domain::properties::Thresholds,
+        web::auth::{AuthActor, AuthArgs},
+    };
+
+    const BASE_URL: &str = "http://localhost:5800";
+
+    #[tokio::test]
+    async fn test_login_and_delete() {
+        let db = Surreal::new::<Mem>(()).await.unwrap();
+        db.use_ns("test").use_db("test").await.unwrap();
+        MigrationRunner::new(&db).up().await.unwrap();
+        let config = Arc::new(Config {
+            steam: Steam {
+                api_token: Arc::new(String::new()),
+                appid: 294_100,
+            },
+            database: Database {
+                user: String::new(),
+                password: String::new(),
+            },
+            updater: false,
+            ml_extraction: false,
+            extraction: Extraction::default(),
+            embeddings: false,
+            languages: vec![],
+            force_update: false,
+            base_url: Arc::new(BASE_URL.to_owned()),
+            biscuit: Arc::new(BiscuitConfig {
+                private_key: KeyPair::new().private(),
+                key_id: 0,
+                previous_keys: vec![],
+            }),
+            identity: IdentityConfig::Dev {
+                user: "76561197960287930".to_owned(),
+            },
+            moderation: Thresholds::default(),
+        });
+        // Named so the endpoints use it
+        Actor::spawn(
+            Some("/auth".to_owned()),
+            AuthActor {},
+            AuthArgs {
+                database: db.clone(),
+                client: Client::new(),
+                base_url: config.base_url.clone(),
+                biscuit: config.biscuit.clone(),
+                identity: config.identity.clone(),
+            },
+        )
+        .await
+        .unwrap();
+        let service = Service::new(super::router(db, config));
+
+        let res = TestClient::get(format!("{BASE_URL}/api/login?location=/me"))
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::FOUND));
+        let verify = res.headers().get(LOCATION).unwrap().to_str().unwrap();
+        assert!(verify.starts_with(&format!("{BASE_URL}/api/verify?")));
+
+        let res = TestClient::get(verify).send(&service).await;
+        assert_eq!(res.status_code, Some(StatusCode::FOUND));
+        assert_eq!(
+            res.headers().get(LOCATION).unwrap(),
+            &format!("{BASE_URL}/me")
+        );
+        let token = res.cookie("token").unwrap().value().to_owned();
+
+        let me = async |token: Option<&str>| {
+            let mut req = TestClient::get(format!("{BASE_URL}/api/me"));
+            if let Some(token) = token {
+                req = req.add_header(COOKIE, format!("token={token}"), true);
+            }
+            req.send(&service).await
+        };
+        let mut res = me(Some(&token)).await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        let profile: Value = res.take_json().await.unwrap();
+        assert_eq!(profile["id"], "76561197960287930");
+        assert_eq!(me(None).await.status_code, Some(StatusCode::UNAUTHORIZED));
+
+        // Deleting the account ends the session along with it
+        let res = TestClient::delete(format!("{BASE_URL}/api/me"))
+            .add_header(COOKIE, format!("token={token}"), true)
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::NO_CONTENT));
+        assert_eq!(
+            me(Some(&token)).await.status_code,
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+}
@@ -139,6 +139,7 @@ pub async fn new(new_property: JsonBody<NewProperty>, depot: &mut Depot) -> Resu
         new_property.0,
         Source::User(userid),
         Status::Pending,
+        None,
         reply,
     ))
     .map_err(InnerError::from)?
@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
 use itertools::Itertools;
+use ractor::{ActorRef, call};
 use salvo::{
     Request, Writer,
     oapi::{endpoint, extract::QueryParam},
@@ -19,10 +20,25 @@ use tracing::{Instrument, info, info_span, instrument};
 
 use crate::{
     db::model::{OrderBy, WorkshopItem},
-    processing::language_actor::DetectedLanguage,
+    processing::{
+        embedding_actor::{EMBEDDING_ACTOR, EmbeddingMsg, nearest_items},
+        language_actor::DetectedLanguage,
+    },
     web,
     web::DB_POOL,
 };
+
+/// How many of the nearest items a semantic search considers before any other
+/// filters apply
+const SEMANTIC_CANDIDATES: usize = 500;
+
+/// Lists workshop items. When `semantic` is given only items close in meaning
+/// to it are listed, ordered by similarity unless `order_by` is set; it's
+/// ignored when embeddings are disabled.
+/// `min_confidence` hides machine generated properties the model was less sure
+/// of. `min_language_confidence` only lists items where at least that fraction
+/// of the description is in `languages`. `hide_translations` leaves out items
+/// detected as translations of another.
 #[instrument(skip_all)]
 #[endpoint]
 pub async fn list(
@@ -34,21 +50,36 @@ pub async fn list(
     mut title: QueryParam<String, false>,
     last_updated: QueryParam<u64, false>,
     mut order_by: QueryParam<OrderBy, false>,
+    mut semantic: QueryParam<String, false>,
+    min_confidence: QueryParam<f32, false>,
+    min_language_confidence: QueryParam<f32, false>,
+    hide_translations: QueryParam<bool, false>,
 ) -> web::Result<Json<Vec<WorkshopItem<String>>>> {
     let page = page.unwrap_or(0);
     let limit = limit.unwrap_or(100).min(100);
     let db: &Surreal<Db> = DB_POOL.get().expect("Getting db connection");
+    let neighbours = match (semantic.take(), EMBEDDING_ACTOR.get()) {
+        (Some(text), Some(actor)) => Some(semantic_neighbours(db, actor, text).await?),
+        _ => None,
+    };
     #[instrument(skip_all)]
+    #[allow(clippy::too_many_arguments)]
     async fn query(
         page: u64,
         limit: u64,
-        languages: Option<DetectedLanguage>,
+        languages: Option<(DetectedLanguage, Option<f32>)>,
         tags: Vec<String>,
         title: Option<String>,
         last_updated: Option<u64>,
         order_by: Option<OrderBy>,
+        neighbours: Option<Vec<RecordId>>,
+        min_confidence: Option<f32>,
+        hide_translations: bool,
         db: &Surreal<Db>,
     ) -> web::Result<Vec<WorkshopItem<String>>, Whatever> {
+        // Without an explicit order, semantic results are ranked by similarity
+        // so paging has to happen after ranking.
+        let ranking = neighbours.clone().filter(|_| order_by.is_none());
         let mut stmt = SelectStatement::default();
         {
             stmt.expr.0.append(&mut vec![Field::All]);
@@ -62,22 +93,29 @@ pub async fn list(
                 });
             }
             {
+                let confident = min_confidence
+                    .filter(|min| min.is_finite())
+                    .map(|min| {
+                        format!(" && ($prop.confidence == NONE || $prop.confidence >= {min})")
+                    })
+                    .unwrap_or_default();
                 stmt.expr.0.push(Field::Single {
                     // Select _approved_ props only
-                    expr: idiom(
-                        r"->workshop_item_properties.filter(|$prop|$prop.status == 1)[*].{
+                    expr: idiom(&format!(
+                        r"->workshop_item_properties.filter(|$prop|$prop.status == 1{confident})[*].{{
                                         id: id.to_string(),
                                         in: in.to_string(),
-                                        out: out.id.{
+                                        out: out.id.{{
                                             class,
                                             `value`
-                                        },
+                                        }},
                                         source: 'system',
                                         status,
                                         upvote_count,
-                                        vote_count
-                                    }",
-                    )
+                                        vote_count,
+                                        confidence
+                                    }}",
+                    ))
                     .expect("expanding properties idiom")
                     .into(),
                     alias: Some("properties".into()),
@@ -93,27 +131,41 @@ pub async fn list(
             }
         }
 
-        stmt.limit = Some({
-            let mut d = Limit::default();
-            d.0 = to_value(limit).whatever_context("limit")?;
-            d
-        });
-        stmt.start = Some({
-            let mut s = Start::default();
-            s.0 = to_value(limit * page).whatever_context("start limit")?;
-            s
-        });
+        if ranking.is_none() {
+            stmt.limit = Some({
+                let mut d = Limit::default();
+                d.0 = to_value(limit).whatever_context("limit")?;
+                d
+            });
+            stmt.start = Some({
+                let mut s = Start::default();
+                s.0 = to_value(limit * page).whatever_context("start limit")?;
+                s
+            });
+        }
 
         stmt.parallel = true;
         stmt.what.0.push(Value::Table("workshop_items".into()));
         stmt.cond = {
             let conditions = vec![
-                languages.map(|lang| {
-                    Expression::new(
+                languages.map(|(lang, min_confidence)| match min_confidence {
+                    Some(min) => Expression::new(
+                        Value::Idiom(
+                            idiom(&format!(
+                                "language_confidence[WHERE language = {} AND confidence >= \
+                                 {min}].len()",
+                                lang as u8
+                            ))
+                            .expect("expanding language confidence idiom"),
+                        ),
+                        Operator::MoreThan,
+                        Value::Number(0.into()),
+                    ),
+                    None => Expression::new(
                         Value::Array(vec![(lang as u8).into(), Value::Number(0.into())].into()),
                         Operator::ContainAny,
                         Value::Idiom("languages".into()),
-                    )
+                    ),
                 }),
                 last_updated.map(|updated| {
                     Expression::new(
@@ -169,6 +221,27 @@ pub async fn list(
                         Value::Strand(title_query.into()),
                     )
                 }),
+                hide_translations.then(|| {
+                    Expression::new(
+                        Value::Idiom(
+                            idiom("->translation_of.len()").expect("expanding translations idiom"),
+                        ),
+                        Operator::Equal,
+                        Value::Number(0.into()),
+                    )
+                }),
+                neighbours.map(|ids| {
+                    Expression::new(
+                        Value::Idiom("id".into()),
+                        Operator::Inside,
+                        Value::Array(
+                            ids.into_iter()
+                                .map(|id| to_value(id).unwrap())
+                                .collect::<Vec<_>>()
+                                .into(),
+                        ),
+                    )
+                }),
             ]
             .into_iter()
             .flatten()
@@ -246,8 +319,16 @@ pub async fn list(
         info!("{stmt}");
         let mut results = db.query(stmt).await.whatever_context("querying")?;
 
-        let results: Vec<WorkshopItem<RecordId>> =
+        let mut results: Vec<WorkshopItem<RecordId>> =
             results.take(0).whatever_context("taking result")?;
+        if let Some(ranking) = ranking {
+            results.sort_by_key(|item| ranking.iter().position(|id| id == &item.id));
+            results = results
+                .into_iter()
+                .skip(usize::try_from(limit * page).unwrap_or(usize::MAX))
+                .take(usize::try_from(limit).unwrap_or(usize::MAX))
+                .collect();
+        }
 
         Ok(results
             .into_iter()
@@ -256,7 +337,12 @@ pub async fn list(
                 author: res.author,
                 description: res.description,
                 id: res.id.key().to_string().replace("⟩", "").replace("⟨", ""),
+                plain_description: String::new(),
                 languages: res.languages,
+                language_confidence: res.language_confidence,
+                primary_language: res.primary_language,
+                also_contains: res.also_contains,
+                title_language: res.title_language,
                 title: res.title,
                 preview_url: res.preview_url,
                 last_updated: res.last_updated,
@@ -269,11 +355,14 @@ pub async fn list(
     let results = query(
         page,
         limit,
-        *languages,
+        languages.map(|lang| (lang, min_language_confidence.filter(|min| min.is_finite()))),
         tags.take().unwrap_or_default(),
         title.take(),
         *last_updated,
         order_by.take(),
+        neighbours,
+        *min_confidence,
+        hide_translations.unwrap_or(false),
         db,
     )
     .instrument(info_span!("query list").or_current())
@@ -281,3 +370,17 @@ pub async fn list(
 
     Ok(Json(results))
 }
+
+/// Embeds the search text and finds the items nearest to it.
+async fn semantic_neighbours(
+    db: &Surreal<Db>,
+    actor: &ActorRef<EmbeddingMsg>,
+    text: String,
+) -> web::Result<Vec<RecordId>> {
+    let embedding = call!(actor, EmbeddingMsg::Embed, text)
+        .whatever_context("Calling embedding actor")?
+        .whatever_context("Embedding search text")?;
+    Ok(nearest_items(db, embedding, SEMANTIC_CANDIDATES)
+        .await
+        .whatever_context("Finding nearest items")?)
+}
@@ -0,0 +1,263 @@
+//! Personal API tokens, for scripts that can't go through the Steam login
+
+use std::time::SystemTime;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use salvo::{
+    Depot, Response,
+    oapi::{
+        ToSchema,
+        extract::{JsonBody, PathParam},
+    },
+    prelude::{Json, StatusCode, StatusError, endpoint},
+};
+use serde::{Deserialize, Serialize};
+use snafu::{ErrorCompat, prelude::*};
+use surrealdb::{Surreal, engine::local::Db};
+use tracing::error;
+
+use crate::{db::UserID, web::auth};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+pub type Error = StatusError;
+
+const DEFAULT_EXPIRY_DAYS: u16 = 90;
+const MAX_EXPIRY_DAYS: u16 = 365;
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Snafu)]
+#[non_exhaustive]
+#[snafu(visibility(pub(crate)))]
+enum InnerError {
+    #[snafu(display("Bad request: {msg}"))]
+    BadRequest {
+        msg: String,
+    },
+    NotFound,
+    Unauthorized,
+    InternalError,
+}
+
+impl InnerError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            InnerError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            InnerError::NotFound => StatusCode::NOT_FOUND,
+            InnerError::Unauthorized => StatusCode::UNAUTHORIZED,
+            InnerError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<InnerError> for StatusError {
+    fn from(value: InnerError) -> Self {
+        let mut error = StatusError::internal_server_error();
+        error.code = value.status_code();
+        error.name = value
+            .status_code()
+            .canonical_reason()
+            .unwrap_or_default()
+            .to_string();
+        error.brief = value.to_string();
+        error.detail = value.backtrace().map(std::string::ToString::to_string);
+        error
+    }
+}
+
+/// What an API token may be used for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    /// Reading only
+    ReadOnly,
+    /// Reading and voting on properties
+    Vote,
+    /// Everything the user can do besides managing tokens, roles aren't
+    /// carried over so moderation needs a session
+    Full,
+}
+
+impl TokenScope {
+    /// The datalog check attenuating a token to this scope, matched against
+    /// the `operation` fact provided when validating.
+    pub fn check(self) -> &'static str {
+        match self {
+            TokenScope::ReadOnly => r#"check if operation("read");"#,
+            TokenScope::Vote => r#"check if operation($op), ["read", "vote"].contains($op);"#,
+            TokenScope::Full => {
+                r#"check if operation($op), ["read", "vote", "write"].contains($op);"#
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct NewToken {
+    /// Something to recognise the token by, i.e. what script uses it
+    pub name: String,
+    pub scope: TokenScope,
+    /// Defaults to 90 days, at most 365
+    pub expires_in_days: Option<u16>,
+}
+
+/// A token as listed, the secret is only ever returned once when minted
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub scope: TokenScope,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct MintedToken {
+    #[serde(flatten)]
+    pub token: ApiToken,
+    /// Sent as `Authorization: Bearer <secret>`
+    pub secret: String,
+}
+
+/// Lists the current user's API tokens that haven't expired.
+#[endpoint]
+pub async fn list(depot: &mut Depot) -> Result<Json<Vec<ApiToken>>> {
+    let user = auth::get_user_from_depot(depot).ok_or(InnerError::Unauthorized)?;
+    let tokens = depot
+        .obtain::<Surreal<Db>>()
+        .expect("getting shared state")
+        .query(
+            "SELECT record::id(id) AS id, name, scope, created, expires FROM api_tokens WHERE \
+             user = $user AND expires > time::now() ORDER BY created DESC",
+        )
+        .bind(("user", UserID::from(user).into_recordid()))
+        .await
+        .and_then(|mut response| response.take(0))
+        .map_err(|e| {
+            error!(?e, "listing api tokens");
+            InnerError::InternalError
+        })?;
+    Ok(Json(tokens))
+}
+
+/// Mints a new API token for the current user. The secret is only returned
+/// here.
+#[endpoint]
+pub async fn create(data: JsonBody<NewToken>, depot: &mut Depot) -> Result<Json<MintedToken>> {
+    let user = auth::get_user_from_depot(depot).ok_or(InnerError::Unauthorized)?;
+    let NewToken {
+        name,
+        scope,
+        expires_in_days,
+    } = data.0;
+    let name = name.trim().to_owned();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(InnerError::BadRequest {
+            msg: format!("name must be between 1 and {MAX_NAME_LEN} characters"),
+        })?;
+    }
+    let days = expires_in_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+    if !(1..=MAX_EXPIRY_DAYS).contains(&days) {
+        return Err(InnerError::BadRequest {
+            msg: format!("expires_in_days must be between 1 and {MAX_EXPIRY_DAYS}"),
+        })?;
+    }
+    let expires = Utc::now() + TimeDelta::days(i64::from(days));
+
+    let db = depot
+        .obtain::<Surreal<Db>>()
+        .expect("getting shared state")
+        .clone();
+    let token: Option<ApiToken> = db
+        .query(
+            "CREATE ONLY api_tokens SET user = $user, name = $name, scope = $scope, created = \
+             time::now(), expires = $expires RETURN record::id(id) AS id, name, scope, created, \
+             expires",
+        )
+        .bind(("user", UserID::from(user.clone()).into_recordid()))
+        .bind(("name", name))
+        .bind(("scope", scope))
+        .bind(("expires", surrealdb::sql::Datetime::from(expires)))
+        .await
+        .and_then(|mut response| response.take(0))
+        .map_err(|e| {
+            error!(?e, "creating api token");
+            InnerError::InternalError
+        })?;
+    let token = token.ok_or(InnerError::InternalError)?;
+
+    match auth::mint_api_token(user, token.id.clone(), scope, SystemTime::from(expires)).await {
+        Ok(secret) => Ok(Json(MintedToken { token, secret })),
+        Err(e) => {
+            // Don't leave a token listed that nobody holds
+            if let Err(e) = db
+                .query("DELETE type::thing('api_tokens', $id)")
+                .bind(("id", token.id))
+                .await
+            {
+                error!(?e, "removing unminted api token");
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Revokes one of the current user's API tokens, it stops working
+/// immediately.
+#[endpoint]
+pub async fn revoke(
+    id: PathParam<String>,
+    depot: &mut Depot,
+    response: &mut Response,
+) -> Result<()> {
+    let user = auth::get_user_from_depot(depot).ok_or(InnerError::Unauthorized)?;
+    if auth::revoke(user, auth::Revocation::ApiToken(id.into_inner())).await? == 0 {
+        return Err(InnerError::NotFound)?;
+    }
+    response.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use biscuit_auth::{
+        KeyPair,
+        builder_ext::AuthorizerExt,
+        macros::{authorizer, biscuit, block},
+    };
+
+    use super::TokenScope;
+    use crate::web::auth::Operation;
+
+    #[test]
+    fn test_scope_check() {
+        let root = KeyPair::new();
+        let allows = |scope: TokenScope, operation: Operation| {
+            let token = biscuit!(r#"user("76561197960287930");"#)
+                .build(&root)
+                .and_then(|token| token.append(block!("").code(scope.check())?))
+                .unwrap();
+            authorizer!("operation({operation});", operation = operation.as_str())
+                .allow_all()
+                .build(&token)
+                .unwrap()
+                .authorize()
+                .is_ok()
+        };
+        let operations = [
+            Operation::Read,
+            Operation::Vote,
+            Operation::Write,
+            Operation::ManageTokens,
+        ];
+        for (scope, allowed) in [
+            (TokenScope::ReadOnly, [true, false, false, false]),
+            (TokenScope::Vote, [true, true, false, false]),
+            // Only sessions may manage tokens
+            (TokenScope::Full, [true, true, true, false]),
+        ] {
+            for (operation, allowed) in operations.into_iter().zip(allowed) {
+                assert_eq!(allows(scope, operation), allowed, "{scope:?} {operation:?}");
+            }
+        }
+    }
+}
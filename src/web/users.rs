@@ -0,0 +1,459 @@
+//! User profiles, what a user has submitted and voted on, and the user's own
+//! data export and account deletion
+
+use chrono::{DateTime, Utc};
+use salvo::{
+    Depot, Response,
+    http::{HeaderValue, header::CONTENT_DISPOSITION},
+    oapi::{
+        ToSchema,
+        extract::{JsonBody, PathParam},
+    },
+    prelude::{Json, StatusCode, StatusError, endpoint},
+};
+use serde::{Deserialize, Serialize};
+use snafu::{ErrorCompat, prelude::*};
+use surrealdb::{RecordId, Surreal, engine::local::Db};
+use tracing::error;
+
+use crate::{
+    db::{
+        UserID,
+        model::{CompanionKind, Privacy, Property, Status, User},
+    },
+    web::{
+        auth::{self, Grant, Permission, Revocation},
+        tokens::ApiToken,
+    },
+};
+
+/// Who contributions are attributed to once their user deletes their account
+const DELETED_USER: &str = "deleted";
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+pub type Error = StatusError;
+
+#[derive(Debug, Snafu)]
+#[non_exhaustive]
+#[snafu(visibility(pub(crate)))]
+enum InnerError {
+    NotFound,
+    Unauthorized,
+    InternalError,
+}
+
+impl InnerError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            InnerError::NotFound => StatusCode::NOT_FOUND,
+            InnerError::Unauthorized => StatusCode::UNAUTHORIZED,
+            InnerError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<InnerError> for StatusError {
+    fn from(value: InnerError) -> Self {
+        let mut error = StatusError::internal_server_error();
+        error.code = value.status_code();
+        error.name = value
+            .status_code()
+            .canonical_reason()
+            .unwrap_or_default()
+            .to_string();
+        error.brief = value.to_string();
+        error.detail = value.backtrace().map(std::string::ToString::to_string);
+        error
+    }
+}
+
+/// A property the user submitted for an item
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct Submission {
+    pub item: String,
+    pub title: Option<String>,
+    pub property: Property,
+    pub status: Status,
+    pub note: Option<String>,
+    pub upvote_count: i64,
+    pub vote_count: u64,
+}
+
+/// A vote the user cast on an item's property
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct CastVote {
+    pub item: String,
+    pub title: Option<String>,
+    pub property: Property,
+    pub score: i64,
+    pub when: DateTime<Utc>,
+}
+
+/// A user's profile, parts the user hasn't made public are left out for
+/// everyone else
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Profile {
+    pub id: String,
+    /// Only shown to the user and admins
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub privacy: Option<Privacy>,
+    pub last_logged_in: Option<DateTime<Utc>>,
+    pub submissions: Option<Vec<Submission>>,
+    /// Accepted out of accepted and rejected submissions, pending ones don't
+    /// count. `None` until something has been decided.
+    pub acceptance_rate: Option<f64>,
+    pub votes: Option<Vec<CastVote>>,
+}
+
+/// A companion the user suggested
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct SuggestedCompanion {
+    pub item: String,
+    pub companion: String,
+    pub kind: CompanionKind,
+    pub status: Status,
+    pub note: Option<String>,
+}
+
+/// Any vote the user cast, `link` being the voted on property or companion
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ExportedVote {
+    pub item: String,
+    pub link: String,
+    pub score: i64,
+    pub when: DateTime<Utc>,
+}
+
+/// A session minted when the user logged in
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct Login {
+    /// When the user logged in, unknown for older sessions
+    pub created: Option<DateTime<Utc>>,
+    pub expires: DateTime<Utc>,
+}
+
+/// Everything stored about a user
+#[derive(Serialize, Debug, ToSchema)]
+pub struct DataExport {
+    pub user: User<UserID>,
+    pub submissions: Vec<Submission>,
+    pub companions: Vec<SuggestedCompanion>,
+    pub votes: Vec<ExportedVote>,
+    /// Sessions that haven't been revoked, expired ones included
+    pub logins: Vec<Login>,
+    pub api_tokens: Vec<ApiToken>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProfileUser {
+    id: String,
+    last_logged_in: DateTime<Utc>,
+    #[serde(default)]
+    privacy: Privacy,
+}
+
+/// The current user's profile, including everything they haven't made
+/// public.
+#[endpoint]
+pub async fn me(depot: &mut Depot) -> Result<Json<Profile>> {
+    let user = auth::get_user_from_depot(depot).ok_or(InnerError::Unauthorized)?;
+    let db = depot.obtain::<Surreal<Db>>().expect("getting shared state");
+    Ok(Json(profile(db, user, true).await?))
+}
+
+/// A user's profile as others see it, the user and admins see all of it.
+#[endpoint]
+pub async fn get(id: PathParam<String>, depot: &mut Depot) -> Result<Json<Profile>> {
+    let id = id.into_inner();
+    let full = auth::get_user_from_depot(depot).is_some_and(|user| user == id)
+        || auth::grant_from_depot(depot, Permission::ManageUsers) == Some(Grant::Everywhere);
+    let db = depot.obtain::<Surreal<Db>>().expect("getting shared state");
+    Ok(Json(profile(db, id, full).await?))
+}
+
+/// Changes what the current user's public profile shows.
+#[endpoint]
+pub async fn update_privacy(data: JsonBody<Privacy>, depot: &mut Depot) -> Result<Json<Privacy>> {
+    let user = auth::get_user_from_depot(depot).ok_or(InnerError::Unauthorized)?;
+    let privacy: Option<Privacy> = depot
+        .obtain::<Surreal<Db>>()
+        .expect("getting shared state")
+        .query("UPDATE $user SET privacy = $privacy RETURN VALUE privacy")
+        .bind(("user", UserID::from(user).into_recordid()))
+        .bind(("privacy", data.0))
+        .await
+        .and_then(|mut response| response.take(0))
+        .map_err(|e| {
+            error!(?e, "updating privacy");
+            InnerError::InternalError
+        })?;
+    Ok(Json(privacy.ok_or(InnerError::NotFound)?))
+}
+
+async fn profile(db: &Surreal<Db>, user_id: String, full: bool) -> Result<Profile> {
+    let user_id = UserID::from(user_id).into_recordid();
+    let user: Option<ProfileUser> = db
+        .query("SELECT record::id(id) AS id, last_logged_in, privacy FROM ONLY $user")
+        .bind(("user", user_id.clone()))
+        .await
+        .and_then(|mut response| response.take(0))
+        .map_err(|e| {
+            error!(?e, "getting profile");
+            InnerError::InternalError
+        })?;
+    let user = user.ok_or(InnerError::NotFound)?;
+    let shown = if full {
+        Privacy {
+            submissions: true,
+            votes: true,
+            last_logged_in: true,
+        }
+    } else {
+        user.privacy
+    };
+
+    let mut response = db
+        .query(if shown.submissions {
+            "SELECT record::id(in) AS item, in.title AS title, record::id(out) AS property, \
+             status, note, upvote_count, vote_count FROM workshop_item_properties WHERE source = \
+             $user ORDER BY item"
+        } else {
+            "RETURN []"
+        })
+        .query(if shown.votes {
+            "SELECT record::id(id.item) AS item, id.item.title AS title, record::id(id.link) AS \
+             property, score, when FROM votes WHERE id.user = $user AND record::tb(id.link) = \
+             'properties' ORDER BY when DESC"
+        } else {
+            "RETURN []"
+        })
+        .bind(("user", user_id))
+        .await
+        .map_err(|e| {
+            error!(?e, "getting contributions");
+            InnerError::InternalError
+        })?;
+    let submissions: Vec<Submission> = response.take(0).map_err(|e| {
+        error!(?e, "taking submissions");
+        InnerError::InternalError
+    })?;
+    let votes: Vec<CastVote> = response.take(1).map_err(|e| {
+        error!(?e, "taking votes");
+        InnerError::InternalError
+    })?;
+
+    Ok(Profile {
+        id: user.id,
+        privacy: full.then_some(user.privacy),
+        last_logged_in: shown.last_logged_in.then_some(user.last_logged_in),
+        acceptance_rate: shown
+            .submissions
+            .then(|| acceptance_rate(&submissions))
+            .flatten(),
+        submissions: shown.submissions.then_some(submissions),
+        votes: shown.votes.then_some(votes),
+    })
+}
+
+/// Removes `user`'s votes, undoing what they added to the counts, and detaches
+/// their contributions from them before deleting them.
+async fn delete_account(db: &Surreal<Db>, user: RecordId) -> surrealdb::Result<()> {
+    db.query("BEGIN TRANSACTION")
+        .query("LET $votes = DELETE votes WHERE id.user = $user RETURN BEFORE")
+        .query(
+            "FOR $vote IN $votes { IF record::tb($vote.id.link) = 'companions' { UPDATE \
+             $vote.id.link SET vote_count = math::max([vote_count - 1, 0]), upvote_count -= \
+             $vote.score; } ELSE { UPDATE workshop_item_properties SET vote_count = \
+             math::max([vote_count - 1, 0]), upvote_count -= $vote.score, weighted_score -= \
+             $vote.score * $vote.weight WHERE in = $vote.id.item AND out = $vote.id.link; }; }",
+        )
+        .query(
+            "UPDATE workshop_item_properties SET source = $deleted, note = NONE WHERE source = \
+             $user",
+        )
+        .query("UPDATE companions SET source = $deleted, note = NONE WHERE source = $user")
+        .query("DELETE $user")
+        .query("COMMIT TRANSACTION")
+        .bind(("user", user))
+        .bind(("deleted", RecordId::from_table_key("users", DELETED_USER)))
+        .await
+        .and_then(surrealdb::Response::check)?;
+    Ok(())
+}
+
+fn acceptance_rate(submissions: &[Submission]) -> Option<f64> {
+    let (accepted, decided) = submissions.iter().fold(
+        (0_u32, 0_u32),
+        |(accepted, decided), submission| match submission.status {
+            Status::Accepted => (accepted + 1, decided + 1),
+            Status::Rejected => (accepted, decided + 1),
+            Status::Pending => (accepted, decided),
+        },
+    );
+    (decided > 0).then(|| f64::from(accepted) / f64::from(decided))
+}
+
+/// Downloads everything stored about the current user as JSON.
+#[endpoint]
+pub async fn export(depot: &mut Depot, response: &mut Response) -> Result<Json<DataExport>> {
+    let user = auth::get_user_from_depot(depot).ok_or(InnerError::Unauthorized)?;
+    let mut results = depot
+        .obtain::<Surreal<Db>>()
+        .expect("getting shared state")
+        .query("SELECT record::id(id) AS id, * FROM ONLY $user")
+        .query(
+            "SELECT record::id(in) AS item, in.title AS title, record::id(out) AS property, \
+             status, note, upvote_count, vote_count FROM workshop_item_properties WHERE source = \
+             $user ORDER BY item",
+        )
+        .query(
+            "SELECT record::id(in) AS item, record::id(out) AS companion, kind, status, note FROM \
+             companions WHERE source = $user ORDER BY item",
+        )
+        .query(
+            "SELECT record::id(id.item) AS item, type::string(id.link) AS link, score, when FROM \
+             votes WHERE id.user = $user ORDER BY when DESC",
+        )
+        .query("SELECT created, expires FROM sessions WHERE user = $user ORDER BY expires DESC")
+        .query(
+            "SELECT record::id(id) AS id, name, scope, created, expires FROM api_tokens WHERE \
+             user = $user ORDER BY created DESC",
+        )
+        .bind(("user", UserID::from(user).into_recordid()))
+        .await
+        .map_err(|e| {
+            error!(?e, "exporting user data");
+            InnerError::InternalError
+        })?;
+    let taking = |e: surrealdb::Error| {
+        error!(?e, "taking user data");
+        InnerError::InternalError
+    };
+    let user: Option<User<UserID>> = results.take(0).map_err(taking)?;
+    let export = DataExport {
+        user: user.ok_or(InnerError::NotFound)?,
+        submissions: results.take(1).map_err(taking)?,
+        companions: results.take(2).map_err(taking)?,
+        votes: results.take(3).map_err(taking)?,
+        logins: results.take(4).map_err(taking)?,
+        api_tokens: results.take(5).map_err(taking)?,
+    };
+
+    response.headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_static("attachment; filename=\"workshop-walker-data.json\""),
+    );
+    Ok(Json(export))
+}
+
+/// Deletes the current user's account, logging them out everywhere. Their
+/// votes are removed and taken off each property's counts, their submissions
+/// stay but are attributed to nobody and lose their notes. Banned users can't
+/// delete their account, as it would lift the ban.
+#[endpoint]
+pub async fn delete(depot: &mut Depot, response: &mut Response) -> Result<()> {
+    let user = auth::get_user_from_depot(depot).ok_or(InnerError::Unauthorized)?;
+    auth::revoke(user.clone(), Revocation::Everything).await?;
+    let db = depot.obtain::<Surreal<Db>>().expect("getting shared state");
+    delete_account(db, UserID::from(user).into_recordid())
+        .await
+        .map_err(|e| {
+            error!(?e, "deleting account");
+            InnerError::InternalError
+        })?;
+    response
+        .headers
+        .insert("Clear-Site-Data", HeaderValue::from_static("\"cookies\""));
+    response.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Deserialize;
+    use surrealdb::{RecordId, Surreal, engine::local::Mem};
+    use surrealdb_migrations::MigrationRunner;
+
+    use super::{Submission, acceptance_rate, delete_account};
+    use crate::db::model::{Class, Property, Status};
+
+    fn submission(status: Status) -> Submission {
+        Submission {
+            item: "1".to_owned(),
+            title: None,
+            property: Property {
+                class: Class::Type,
+                value: "Mod".to_owned(),
+            },
+            status,
+            note: None,
+            upvote_count: 0,
+            vote_count: 0,
+        }
+    }
+
+    #[test]
+    fn test_acceptance_rate() {
+        assert_eq!(acceptance_rate(&[]), None);
+        assert_eq!(acceptance_rate(&[submission(Status::Pending)]), None);
+        let submissions = [
+            submission(Status::Accepted),
+            submission(Status::Accepted),
+            submission(Status::Rejected),
+            submission(Status::Pending),
+        ];
+        assert!((acceptance_rate(&submissions).unwrap() - 2.0 / 3.0).abs() < f64::EPSILON);
+    }
+
+    #[tokio::test]
+    async fn test_delete_account() {
+        #[derive(Deserialize)]
+        struct Counts {
+            upvote_count: i64,
+            vote_count: u64,
+            weighted_score: f64,
+            note: Option<String>,
+            source: String,
+        }
+
+        let db = Surreal::new::<Mem>(()).await.unwrap();
+        db.use_ns("test").use_db("test").await.unwrap();
+        MigrationRunner::new(&db).up().await.unwrap();
+        db.query(
+            r"
+            LET $property = properties:{ class: 'TYPE', value: 'Mod' };
+            RELATE workshop_items:item->workshop_item_properties->$property SET
+                source = users:leaving, note = 'mine', upvote_count = 2, vote_count = 2,
+                weighted_score = 2.5;
+            INSERT INTO votes [
+                { id: { item: workshop_items:item, link: $property, user: users:leaving }, score: 1, weight: 1.5, when: time::now() },
+                { id: { item: workshop_items:item, link: $property, user: users:staying }, score: 1, when: time::now() },
+            ];
+            ",
+        )
+        .await
+        .and_then(surrealdb::Response::check)
+        .unwrap();
+
+        delete_account(&db, RecordId::from_table_key("users", "leaving"))
+            .await
+            .unwrap();
+
+        let mut response = db
+            .query(
+                "SELECT upvote_count, vote_count, weighted_score, note, type::string(source) AS \
+                 source FROM ONLY workshop_item_properties LIMIT 1",
+            )
+            .query("RETURN count(SELECT * FROM votes)")
+            .await
+            .unwrap();
+        let counts: Option<Counts> = response.take(0).unwrap();
+        let counts = counts.unwrap();
+        assert_eq!((counts.upvote_count, counts.vote_count), (1, 1));
+        assert!((counts.weighted_score - 1.0).abs() < f64::EPSILON);
+        assert_eq!(counts.note, None);
+        assert_eq!(counts.source, "users:deleted");
+        let votes: Option<i64> = response.take(1).unwrap();
+        assert_eq!(votes, Some(1));
+    }
+}